@@ -0,0 +1,291 @@
+//! Dates in events files and natural-language date phrases in queries.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+
+/// Inclusive range of calendar days.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateRange {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+}
+
+impl DateRange {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        self.start <= date && date <= self.end
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "january",
+    "february",
+    "march",
+    "april",
+    "may",
+    "june",
+    "july",
+    "august",
+    "september",
+    "october",
+    "november",
+    "december",
+];
+
+/// Words an event's date can be found by: the ISO date, its year-month, the month name and
+/// the year ("2024-06-15 2024-06 june 2024"). A date that doesn't parse is returned as is.
+pub fn search_terms(date: &str) -> String {
+    match parse_date(date) {
+        Some(d) => format!(
+            "{} {} {} {}",
+            d.format("%Y-%m-%d"),
+            d.format("%Y-%m"),
+            MONTHS[d.month0() as usize],
+            d.year()
+        ),
+        None => date.to_string(),
+    }
+}
+
+/// Parse the date formats we see in practice: "2/16/2026" (events files), "2026-02-16", and
+/// the frontend's long form "Monday, February 16, 2026" (weekday optional). An ISO date
+/// with a time (see `parse_datetime`) gives its date.
+pub fn parse_date(s: &str) -> Option<NaiveDate> {
+    let s = s.trim();
+    for fmt in ["%m/%d/%Y", "%Y-%m-%d", "%B %d, %Y"] {
+        if let Ok(d) = NaiveDate::parse_from_str(s, fmt) {
+            return Some(d);
+        }
+    }
+    if let Some(datetime) = parse_datetime(s) {
+        return Some(datetime.date());
+    }
+    let (_, rest) = s.split_once(", ")?;
+    NaiveDate::parse_from_str(rest.trim(), "%B %d, %Y").ok()
+}
+
+/// An ISO date with a time of day: "2026-02-16T14:30", "2026-02-16 14:30:00", or RFC 3339
+/// with an offset ("2026-02-16T14:30:00+01:00"), read as the local time it names.
+fn parse_datetime(s: &str) -> Option<NaiveDateTime> {
+    let s = s.trim();
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(s) {
+        return Some(datetime.naive_local());
+    }
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+}
+
+/// A `current_date` that may carry a time of day, split into the date ("2026-02-16") and
+/// the time. Date-only input comes back as given, with no time.
+pub fn split_current_date(s: &str) -> (String, Option<NaiveTime>) {
+    match parse_datetime(s) {
+        Some(datetime) => (datetime.date().format("%Y-%m-%d").to_string(), Some(datetime.time())),
+        None => (s.to_string(), None),
+    }
+}
+
+/// An event's time of day: "14:30", "14:30:00", or 12-hour "2:30 PM", "2:30pm", "2 PM".
+pub fn parse_time(s: &str) -> Option<NaiveTime> {
+    let s = s.trim().to_uppercase();
+    for fmt in ["%H:%M", "%H:%M:%S"] {
+        if let Ok(time) = NaiveTime::parse_from_str(&s, fmt) {
+            return Some(time);
+        }
+    }
+    let (clock, meridiem) = s
+        .strip_suffix("AM")
+        .map(|clock| (clock, "AM"))
+        .or_else(|| s.strip_suffix("PM").map(|clock| (clock, "PM")))?;
+    let clock = clock.trim();
+    let clock = if clock.contains(':') {
+        clock.to_string()
+    } else {
+        format!("{}:00", clock)
+    };
+    NaiveTime::parse_from_str(&format!("{} {}", clock, meridiem), "%I:%M %p").ok()
+}
+
+fn month_range(year: i32, month: u32) -> Option<DateRange> {
+    let start = NaiveDate::from_ymd_opt(year, month, 1)?;
+    let next = if month == 12 {
+        NaiveDate::from_ymd_opt(year + 1, 1, 1)?
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1)?
+    };
+    Some(DateRange {
+        start,
+        end: next - Duration::days(1),
+    })
+}
+
+fn week_range(day: NaiveDate) -> DateRange {
+    let start = day - Duration::days(day.weekday().num_days_from_monday() as i64);
+    DateRange {
+        start,
+        end: start + Duration::days(6),
+    }
+}
+
+/// Resolve a date phrase in `query` ("today", "tomorrow", "this weekend", "this/next week",
+/// "this/next month", or a month name) against `today`. Weeks run Monday to Sunday. A bare
+/// month name means its next occurrence, so "July" asked in August is next year's July.
+/// Returns None when the query has no date phrase.
+pub fn parse_date_range(query: &str, today: NaiveDate) -> Option<DateRange> {
+    let query = query.to_lowercase();
+    let words: Vec<&str> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect();
+    let has_pair = |a: &str, b: &str| words.windows(2).any(|w| w[0] == a && w[1] == b);
+    let has = |a: &str| words.contains(&a);
+
+    if has("today") || has("tonight") {
+        return Some(DateRange {
+            start: today,
+            end: today,
+        });
+    }
+    if has("tomorrow") {
+        let day = today + Duration::days(1);
+        return Some(DateRange {
+            start: day,
+            end: day,
+        });
+    }
+    if has("weekend") {
+        let week = week_range(if has_pair("next", "weekend") {
+            today + Duration::days(7)
+        } else {
+            today
+        });
+        let saturday = week.start + Duration::days(Weekday::Sat.num_days_from_monday() as i64);
+        return Some(DateRange {
+            start: saturday,
+            end: week.end,
+        });
+    }
+    if has_pair("next", "week") {
+        return Some(week_range(today + Duration::days(7)));
+    }
+    if has_pair("this", "week") {
+        return Some(week_range(today));
+    }
+    if has_pair("next", "month") {
+        let (year, month) = if today.month() == 12 {
+            (today.year() + 1, 1)
+        } else {
+            (today.year(), today.month() + 1)
+        };
+        return month_range(year, month);
+    }
+    if has_pair("this", "month") {
+        return month_range(today.year(), today.month());
+    }
+
+    for (i, name) in MONTHS.iter().enumerate() {
+        // "may" is too common a word on its own; only treat "in may" as a month.
+        let found = if *name == "may" {
+            has_pair("in", "may")
+        } else {
+            has(name)
+        };
+        if found {
+            let month = i as u32 + 1;
+            let year = if month < today.month() {
+                today.year() + 1
+            } else {
+                today.year()
+            };
+            return month_range(year, month);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    fn range(start: &str, end: &str) -> Option<DateRange> {
+        Some(DateRange {
+            start: date(start),
+            end: date(end),
+        })
+    }
+
+    /// A Wednesday.
+    fn today() -> NaiveDate {
+        date("2026-02-18")
+    }
+
+    #[test]
+    fn single_days() {
+        let day = range("2026-02-18", "2026-02-18");
+        assert_eq!(parse_date_range("What's on today?", today()), day);
+        assert_eq!(parse_date_range("anything tonight", today()), day);
+        let next = range("2026-02-19", "2026-02-19");
+        assert_eq!(parse_date_range("Do I have plans Tomorrow?", today()), next);
+    }
+
+    #[test]
+    fn weeks_run_monday_to_sunday() {
+        let this_week = range("2026-02-16", "2026-02-22");
+        assert_eq!(parse_date_range("what's happening this week", today()), this_week);
+        let next_week = range("2026-02-23", "2026-03-01");
+        assert_eq!(parse_date_range("anything next week?", today()), next_week);
+        let weekend = range("2026-02-21", "2026-02-22");
+        assert_eq!(parse_date_range("plans for this weekend", today()), weekend);
+        let next_weekend = range("2026-02-28", "2026-03-01");
+        assert_eq!(parse_date_range("next weekend", today()), next_weekend);
+    }
+
+    #[test]
+    fn months() {
+        let this_month = range("2026-02-01", "2026-02-28");
+        assert_eq!(parse_date_range("this month", today()), this_month);
+        let next_month = range("2026-03-01", "2026-03-31");
+        assert_eq!(parse_date_range("What about next month?", today()), next_month);
+        let july = range("2026-07-01", "2026-07-31");
+        assert_eq!(parse_date_range("events in July", today()), july);
+        let january = range("2027-01-01", "2027-01-31");
+        assert_eq!(parse_date_range("january trips", today()), january, "already past");
+        let may = range("2026-05-01", "2026-05-31");
+        assert_eq!(parse_date_range("anything in May?", today()), may);
+    }
+
+    #[test]
+    fn december_rolls_over_to_next_year() {
+        let today = date("2026-12-31");
+        let next = range("2027-01-01", "2027-01-01");
+        assert_eq!(parse_date_range("tomorrow", today), next);
+        let next_month = range("2027-01-01", "2027-01-31");
+        assert_eq!(parse_date_range("next month", today), next_month);
+        let next_week = range("2027-01-04", "2027-01-10");
+        assert_eq!(parse_date_range("next week", today), next_week);
+        let july = range("2027-07-01", "2027-07-31");
+        assert_eq!(parse_date_range("events in July", today), july);
+    }
+
+    #[test]
+    fn no_date_phrase() {
+        assert_eq!(parse_date_range("when is the dentist?", today()), None);
+        assert_eq!(parse_date_range("may I reschedule the dentist", today()), None);
+        assert_eq!(parse_date_range("", today()), None);
+    }
+
+    #[test]
+    fn times_of_day() {
+        let time = |h, m, s| NaiveTime::from_hms_opt(h, m, s);
+        assert_eq!(parse_time("14:30"), time(14, 30, 0));
+        assert_eq!(parse_time(" 14:30:00 "), time(14, 30, 0));
+        assert_eq!(parse_time("2 PM"), time(14, 0, 0));
+        assert_eq!(parse_time("2:30pm"), time(14, 30, 0));
+        assert_eq!(parse_time("12 am"), time(0, 0, 0));
+        assert_eq!(parse_time("noon"), None);
+        assert_eq!(parse_time("25:00"), None);
+        assert_eq!(parse_time(""), None);
+    }
+}
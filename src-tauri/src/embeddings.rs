@@ -0,0 +1,105 @@
+//! Sentence embeddings from a BERT-style encoder (e.g. all-MiniLM-L6-v2).
+//!
+//! candle's `Llama` only returns logits for the last position, not hidden states, so the chat
+//! model can't be pooled into embeddings; `embed` takes its own encoder model directory instead.
+
+use crate::llm::{self, LlmError};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use std::path::{Path, PathBuf};
+use tokenizers::{PaddingParams, Tokenizer};
+
+pub struct EmbeddingEngine {
+    pub model: BertModel,
+    pub tokenizer: Tokenizer,
+    pub device: Device,
+    pub model_dir: PathBuf,
+}
+
+fn load_config(model_dir: &Path) -> Result<Config, LlmError> {
+    let config_path = model_dir.join("config.json");
+    let config_bytes = std::fs::read(&config_path)
+        .map_err(|e| LlmError::ModelFiles(format!("Failed to read config.json: {}", e)))?;
+    serde_json::from_slice(&config_bytes).map_err(|e| {
+        LlmError::ModelFiles(format!(
+            "config.json is not a BERT-style embedding model config: {}",
+            e
+        ))
+    })
+}
+
+pub fn load(model_dir: &Path) -> Result<EmbeddingEngine, LlmError> {
+    let device = Device::Cpu;
+
+    let config = load_config(model_dir)?;
+    let tokenizer_path = model_dir.join("tokenizer.json");
+    let mut tokenizer = Tokenizer::from_file(tokenizer_path)
+        .map_err(|e| LlmError::ModelFiles(format!("Failed to load tokenizer: {}", e)))?;
+    tokenizer.with_padding(Some(PaddingParams::default()));
+
+    let paths = llm::safetensors_paths(model_dir)?;
+    if paths.is_empty() {
+        return Err(LlmError::ModelFiles("No .safetensors files found in model dir".into()));
+    }
+
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&paths, DTYPE, &device) }
+        .map_err(|e| LlmError::load("Failed to load weights", e))?;
+
+    let model = BertModel::load(vb, &config)
+        .map_err(|e| LlmError::load("Failed to load embedding model", e))?;
+
+    Ok(EmbeddingEngine {
+        model,
+        tokenizer,
+        device,
+        model_dir: model_dir.to_path_buf(),
+    })
+}
+
+impl EmbeddingEngine {
+    /// Mean-pools the last hidden state over non-padding tokens and L2-normalizes each vector.
+    pub fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, LlmError> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts.to_vec(), true)
+            .map_err(|e| LlmError::Tokenizer(format!("Encode error: {}", e)))?;
+
+        let ids = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_ids(), &self.device))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|rows| Tensor::stack(&rows, 0))
+            .map_err(|e| LlmError::inference("Tensor creation failed", e))?;
+        let mask = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_attention_mask(), &self.device))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|rows| Tensor::stack(&rows, 0))
+            .map_err(|e| LlmError::inference("Tensor creation failed", e))?;
+        let token_type_ids = ids
+            .zeros_like()
+            .map_err(|e| LlmError::inference("Tensor creation failed", e))?;
+
+        let hidden = self
+            .model
+            .forward(&ids, &token_type_ids, Some(&mask))
+            .map_err(|e| LlmError::inference("Forward failed", e))?;
+
+        let pooled = (|| {
+            let mask = mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+            let summed = hidden.broadcast_mul(&mask)?.sum(1)?;
+            let counts = mask.sum(1)?;
+            let mean = summed.broadcast_div(&counts)?;
+            let norms = mean.sqr()?.sum_keepdim(1)?.sqrt()?;
+            mean.broadcast_div(&norms)?.to_vec2::<f32>()
+        })()
+        .map_err(|e| LlmError::inference("Pooling failed", e))?;
+
+        Ok(pooled)
+    }
+}
@@ -0,0 +1,471 @@
+//! Grammar-constrained decoding: a character-level JSON recognizer used to mask logits so
+//! the model can only produce text that is a valid prefix of the grammar.
+//!
+//! Supported subset: the top level must be a single JSON object; inside it, any standard
+//! JSON value (objects, arrays, strings with escapes including `\uXXXX`, numbers, `true`,
+//! `false`, `null`). JSON schemas are not enforced, and nothing may follow the closing brace.
+
+/// Grammars accepted by the `grammar` parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grammar {
+    /// A single JSON object.
+    JsonObject,
+}
+
+impl Grammar {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "json" | "json_object" => Ok(Grammar::JsonObject),
+            other => Err(format!("Unsupported grammar: {} (expected \"json\")", other)),
+        }
+    }
+
+    pub fn start(self) -> JsonState {
+        JsonState::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Container {
+    Object,
+    Array,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Number {
+    Minus,
+    Zero,
+    Int,
+    Dot,
+    Frac,
+    Exp,
+    ExpSign,
+    ExpDigits,
+}
+
+impl Number {
+    fn is_complete(self) -> bool {
+        matches!(self, Number::Zero | Number::Int | Number::Frac | Number::ExpDigits)
+    }
+
+    fn next(self, c: char) -> Option<Number> {
+        use Number::*;
+        match (self, c) {
+            (Minus, '0') => Some(Zero),
+            (Minus, '1'..='9') => Some(Int),
+            (Int, '0'..='9') => Some(Int),
+            (Zero | Int, '.') => Some(Dot),
+            (Dot | Frac, '0'..='9') => Some(Frac),
+            (Zero | Int | Frac, 'e' | 'E') => Some(Exp),
+            (Exp, '+' | '-') => Some(ExpSign),
+            (Exp | ExpSign | ExpDigits, '0'..='9') => Some(ExpDigits),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expect {
+    /// Start of input: only an object may open.
+    TopLevel,
+    Value,
+    KeyOrObjectEnd,
+    Key,
+    Colon,
+    CommaOrObjectEnd,
+    ValueOrArrayEnd,
+    CommaOrArrayEnd,
+    /// Inside a string; `escape` counts pending escape chars (1 after `\`, 4 for `\u`).
+    String { key: bool, escape: u8, unicode: bool },
+    Number(Number),
+    Literal(&'static str),
+    Done,
+}
+
+/// Recognizer state; cheap to clone so candidate tokens can be tried without committing.
+#[derive(Debug, Clone)]
+pub struct JsonState {
+    stack: Vec<Container>,
+    expect: Expect,
+}
+
+impl Default for JsonState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JsonState {
+    pub fn new() -> Self {
+        Self {
+            stack: Vec::new(),
+            expect: Expect::TopLevel,
+        }
+    }
+
+    /// True once the top-level object has been closed.
+    pub fn is_complete(&self) -> bool {
+        self.expect == Expect::Done
+    }
+
+    fn value_done(&mut self) {
+        self.expect = match self.stack.last() {
+            Some(Container::Object) => Expect::CommaOrObjectEnd,
+            Some(Container::Array) => Expect::CommaOrArrayEnd,
+            None => Expect::Done,
+        };
+    }
+
+    fn close(&mut self, container: Container) -> bool {
+        if self.stack.pop() != Some(container) {
+            return false;
+        }
+        self.value_done();
+        true
+    }
+
+    fn start_value(&mut self, c: char) -> bool {
+        match c {
+            '{' => {
+                self.stack.push(Container::Object);
+                self.expect = Expect::KeyOrObjectEnd;
+            }
+            '[' => {
+                self.stack.push(Container::Array);
+                self.expect = Expect::ValueOrArrayEnd;
+            }
+            '"' => {
+                self.expect = Expect::String {
+                    key: false,
+                    escape: 0,
+                    unicode: false,
+                }
+            }
+            '-' => self.expect = Expect::Number(Number::Minus),
+            '0' => self.expect = Expect::Number(Number::Zero),
+            '1'..='9' => self.expect = Expect::Number(Number::Int),
+            't' => self.expect = Expect::Literal("rue"),
+            'f' => self.expect = Expect::Literal("alse"),
+            'n' => self.expect = Expect::Literal("ull"),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Advance by one character; false if it can't continue a valid document (the state is
+    /// then unspecified, so callers try candidates on a clone).
+    pub fn accept(&mut self, c: char) -> bool {
+        let ws = matches!(c, ' ' | '\t' | '\n' | '\r');
+        match self.expect {
+            Expect::String {
+                key,
+                escape,
+                unicode,
+            } => {
+                if escape > 0 {
+                    let ok = if unicode {
+                        c.is_ascii_hexdigit()
+                    } else if c == 'u' {
+                        self.expect = Expect::String {
+                            key,
+                            escape: 4,
+                            unicode: true,
+                        };
+                        return true;
+                    } else {
+                        matches!(c, '"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't')
+                    };
+                    if ok {
+                        let escape = escape - 1;
+                        self.expect = Expect::String {
+                            key,
+                            escape,
+                            unicode: unicode && escape > 0,
+                        };
+                    }
+                    return ok;
+                }
+                match c {
+                    '\\' => {
+                        self.expect = Expect::String {
+                            key,
+                            escape: 1,
+                            unicode: false,
+                        };
+                        true
+                    }
+                    '"' if key => {
+                        self.expect = Expect::Colon;
+                        true
+                    }
+                    '"' => {
+                        self.value_done();
+                        true
+                    }
+                    c if (c as u32) < 0x20 => false,
+                    _ => true,
+                }
+            }
+            Expect::Number(n) => match n.next(c) {
+                Some(next) => {
+                    self.expect = Expect::Number(next);
+                    true
+                }
+                None if n.is_complete() => {
+                    self.value_done();
+                    self.accept(c)
+                }
+                None => false,
+            },
+            Expect::Literal(rest) => {
+                let mut chars = rest.chars();
+                if chars.next() != Some(c) {
+                    return false;
+                }
+                let rest = chars.as_str();
+                if rest.is_empty() {
+                    self.value_done();
+                } else {
+                    self.expect = Expect::Literal(rest);
+                }
+                true
+            }
+            _ if ws => self.expect != Expect::Done,
+            Expect::TopLevel => c == '{' && self.start_value(c),
+            Expect::Value => self.start_value(c),
+            Expect::ValueOrArrayEnd => {
+                if c == ']' {
+                    self.close(Container::Array)
+                } else {
+                    self.start_value(c)
+                }
+            }
+            Expect::KeyOrObjectEnd | Expect::Key => match c {
+                '"' => {
+                    self.expect = Expect::String {
+                        key: true,
+                        escape: 0,
+                        unicode: false,
+                    };
+                    true
+                }
+                '}' if self.expect == Expect::KeyOrObjectEnd => self.close(Container::Object),
+                _ => false,
+            },
+            Expect::Colon => {
+                if c == ':' {
+                    self.expect = Expect::Value;
+                }
+                c == ':'
+            }
+            Expect::CommaOrObjectEnd => match c {
+                ',' => {
+                    self.expect = Expect::Key;
+                    true
+                }
+                '}' => self.close(Container::Object),
+                _ => false,
+            },
+            Expect::CommaOrArrayEnd => match c {
+                ',' => {
+                    self.expect = Expect::Value;
+                    true
+                }
+                ']' => self.close(Container::Array),
+                _ => false,
+            },
+            Expect::Done => false,
+        }
+    }
+
+    /// The state after `text`, or None if `text` would make the document invalid.
+    pub fn after(&self, text: &str) -> Option<JsonState> {
+        let mut next = self.clone();
+        text.chars().all(|c| next.accept(c)).then_some(next)
+    }
+
+    /// Text that ends the document from here: the rest of the current string value, number
+    /// or literal, then a closer for every open container. None where input would have to
+    /// be undone instead: after a key, colon or comma, in an escape or in a number such as
+    /// "1." that can't end yet.
+    fn closing(&self) -> Option<String> {
+        let mut closing = match self.expect {
+            Expect::String {
+                key: false,
+                escape: 0,
+                ..
+            } => "\"".to_string(),
+            Expect::Number(n) if n.is_complete() => String::new(),
+            Expect::Literal(rest) => rest.to_string(),
+            Expect::KeyOrObjectEnd
+            | Expect::CommaOrObjectEnd
+            | Expect::ValueOrArrayEnd
+            | Expect::CommaOrArrayEnd
+            | Expect::Done => String::new(),
+            _ => return None,
+        };
+        closing.extend(self.stack.iter().rev().map(|container| match container {
+            Container::Object => '}',
+            Container::Array => ']',
+        }));
+        Some(closing)
+    }
+}
+
+/// `text` as a parseable JSON object (in the subset above), repairing output cut off
+/// mid-object, e.g. at the token limit: an unterminated string is closed, and open objects
+/// and arrays are closed in order. A dangling key, colon or comma is dropped, and so is
+/// anything before the first "{" or after the object ends. Text that is already valid comes
+/// back unchanged; None when it has no "{" at all.
+pub fn repair_json(text: &str) -> Option<String> {
+    let text = &text[text.find('{')?..];
+    let mut state = JsonState::new();
+    // The longest prefix that `closing` can end, and its closing text.
+    let mut closable = (0, String::new());
+    let mut end = 0;
+    for (i, c) in text.char_indices() {
+        if !state.accept(c) {
+            break;
+        }
+        end = i + c.len_utf8();
+        if state.is_complete() {
+            return Some(text[..end].to_string());
+        }
+        if let Some(closing) = state.closing() {
+            closable = (end, closing);
+        }
+    }
+    let (end, closing) = match state.closing() {
+        Some(closing) => (end, closing),
+        None => closable,
+    };
+    Some(format!("{}{}", &text[..end], closing))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `text` is a valid prefix of a document.
+    fn accepts(text: &str) -> bool {
+        JsonState::new().after(text).is_some()
+    }
+
+    /// Whether `text` is a whole document.
+    fn complete(text: &str) -> bool {
+        JsonState::new().after(text).is_some_and(|state| state.is_complete())
+    }
+
+    #[test]
+    fn nested_objects_and_arrays() {
+        assert!(complete(r#"{"a": {"b": [1, [2, {"c": []}], {}]}, "d": "e"}"#));
+        assert!(accepts(r#"{"a": [1, {"b": "#));
+        assert!(!complete(r#"{"a": [1, {"b": 2}]"#));
+        assert!(!accepts(r#"{"a": [1}"#), "closers must match");
+        assert!(!accepts(r#"{"a": 1]"#));
+        assert!(!accepts(r#"{"a" 1}"#), "key needs a colon");
+        assert!(!accepts(r#"{"a": 1,}"#), "no trailing comma");
+        assert!(!accepts("[1]"), "the top level is an object");
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert!(complete(r#"{"a": "quote \" slash \/ tab \t \\"}"#));
+        assert!(complete(r#"{"étÉ": "😀"}"#));
+        assert!(accepts(r#"{"a": "\u00"#));
+        assert!(!accepts(r#"{"a": "\u00g9"}"#));
+        assert!(!accepts(r#"{"a": "\x41"}"#));
+    }
+
+    #[test]
+    fn control_characters_must_be_escaped() {
+        assert!(!accepts("{\"a\": \"line\nbreak\"}"));
+        assert!(!accepts("{\"a\": \"tab\there\"}"));
+        assert!(complete(r#"{"a": "line\nbreak"}"#));
+        assert!(complete("{\"a\": \"caf\u{e9} \u{1f600}\"}"));
+    }
+
+    #[test]
+    fn numbers() {
+        for number in ["0", "-0", "7", "-12", "0.5", "-0.5e+3", "1E9", "2.50e-07"] {
+            assert!(complete(&format!(r#"{{"n": {}}}"#, number)), "{}", number);
+        }
+        for number in ["01", "1.", "-", ".5", "1e", "1e+", "+1", "--1", "1.2.3"] {
+            assert!(!complete(&format!(r#"{{"n": {}}}"#, number)), "{}", number);
+        }
+    }
+
+    #[test]
+    fn literals() {
+        assert!(complete(r#"{"a": true, "b": false, "c": null}"#));
+        assert!(accepts(r#"{"a": tr"#));
+        assert!(!accepts(r#"{"a": tru}"#));
+        assert!(!accepts(r#"{"a": True}"#));
+        assert!(!accepts(r#"{"a": nil}"#));
+    }
+
+    #[test]
+    fn nothing_after_the_closing_brace() {
+        assert!(complete("{}"));
+        assert!(complete(" { \"a\" : 1 }"), "whitespace before and inside is fine");
+        assert!(!accepts("{} "));
+        assert!(!accepts("{}{}"));
+        assert!(!accepts(r#"{"a": 1}x"#));
+    }
+
+    #[test]
+    fn rejected_character_leaves_the_state_alone() {
+        let state = JsonState::new().after(r#"{"a": "#).unwrap();
+        assert!(state.after("}").is_none());
+        assert!(state.after("1}").is_some_and(|s| s.is_complete()));
+    }
+
+    #[test]
+    fn valid_object_comes_back_unchanged() {
+        let json = r#"{"a": [1, {"b": null}], "c": "d"}"#;
+        assert_eq!(repair_json(json).as_deref(), Some(json));
+    }
+
+    #[test]
+    fn unterminated_string_is_closed() {
+        assert_eq!(repair_json(r#"{"a":"x"#).as_deref(), Some(r#"{"a":"x"}"#));
+        assert_eq!(repair_json(r#"{"a":["x"#).as_deref(), Some(r#"{"a":["x"]}"#));
+        assert_eq!(repair_json(r#"{"a":"x\"#).as_deref(), Some(r#"{"a":"x"}"#), "cut in an escape");
+    }
+
+    #[test]
+    fn dangling_key_colon_or_comma_is_dropped() {
+        assert_eq!(repair_json(r#"{"a":1,"b"#).as_deref(), Some(r#"{"a":1}"#));
+        assert_eq!(repair_json(r#"{"a":1,"b""#).as_deref(), Some(r#"{"a":1}"#));
+        assert_eq!(repair_json(r#"{"a":1,"b":"#).as_deref(), Some(r#"{"a":1}"#));
+        assert_eq!(repair_json(r#"{"a":1,"#).as_deref(), Some(r#"{"a":1}"#));
+        assert_eq!(repair_json(r#"{"a":[1,"#).as_deref(), Some(r#"{"a":[1]}"#));
+        assert_eq!(repair_json(r#"{"a"#).as_deref(), Some("{}"));
+    }
+
+    #[test]
+    fn cut_off_literal_is_completed() {
+        assert_eq!(repair_json(r#"{"a":tr"#).as_deref(), Some(r#"{"a":true}"#));
+        assert_eq!(repair_json(r#"{"a":[nu"#).as_deref(), Some(r#"{"a":[null]}"#));
+    }
+
+    #[test]
+    fn number_that_cannot_end_is_cut_back() {
+        assert_eq!(repair_json(r#"{"a":1."#).as_deref(), Some(r#"{"a":1}"#));
+        assert_eq!(repair_json(r#"{"a":2e"#).as_deref(), Some(r#"{"a":2}"#));
+        assert_eq!(repair_json(r#"{"a":1.5"#).as_deref(), Some(r#"{"a":1.5}"#));
+    }
+
+    #[test]
+    fn text_around_the_object_is_dropped() {
+        let text = r#"Sure! Here it is: {"a": 1} Hope that helps."#;
+        assert_eq!(repair_json(text).as_deref(), Some(r#"{"a": 1}"#));
+        assert_eq!(repair_json(r#"Result: {"a": "#).as_deref(), Some(r#"{}"#));
+    }
+
+    #[test]
+    fn no_object_gives_none() {
+        assert_eq!(repair_json("I can't answer that."), None);
+        assert_eq!(repair_json(""), None);
+    }
+}
@@ -0,0 +1,121 @@
+//! Which language to ask the model to reply in: a caller's hint, or a guess from the prompt.
+
+/// Languages the detector knows, with common short words that rarely appear in the others.
+/// No single letters: "y" or "e" turn up in any language as initials and list labels.
+const LANGUAGES: &[(&str, &str, &[&str])] = &[
+    (
+        "en",
+        "English",
+        &["the", "and", "is", "are", "what", "when", "my", "have", "do", "with", "about"],
+    ),
+    (
+        "de",
+        "German",
+        &["der", "die", "das", "und", "ist", "ich", "nicht", "was", "wann", "mein", "habe", "mit"],
+    ),
+    (
+        "fr",
+        "French",
+        &["le", "la", "les", "et", "est", "je", "pas", "quand", "mon", "avec", "une", "des"],
+    ),
+    (
+        "es",
+        "Spanish",
+        &["el", "los", "las", "es", "yo", "qué", "cuándo", "mi", "con", "una", "tengo", "para"],
+    ),
+    (
+        "it",
+        "Italian",
+        &["il", "gli", "sono", "io", "non", "che", "quando", "mio", "con", "una", "ho", "per"],
+    ),
+    (
+        "nl",
+        "Dutch",
+        &["de", "het", "een", "en", "is", "ik", "niet", "wat", "wanneer", "mijn", "met", "heb"],
+    ),
+];
+
+/// Fewest stopword hits before a guess is trusted; short prompts are easy to misread.
+const MIN_HITS: usize = 2;
+
+/// Guess the language of `text` by counting each language's stopwords. None when no
+/// language reaches `MIN_HITS` or two languages tie.
+pub fn detect(text: &str) -> Option<&'static str> {
+    let text = text.to_lowercase();
+    let words: Vec<&str> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .collect();
+    let mut scores: Vec<(usize, &'static str)> = LANGUAGES
+        .iter()
+        .map(|(_, name, stopwords)| {
+            let hits = words.iter().filter(|w| stopwords.contains(w)).count();
+            // Letters only German uses among these languages.
+            let umlauts = if *name == "German" {
+                text.chars().filter(|c| matches!(c, 'ä' | 'ö' | 'ü' | 'ß')).count()
+            } else {
+                0
+            };
+            (hits + umlauts, *name)
+        })
+        .collect();
+    scores.sort_by_key(|&(hits, _)| std::cmp::Reverse(hits));
+    match scores.as_slice() {
+        [(best, name), (second, _), ..] if *best >= MIN_HITS && best > second => Some(name),
+        _ => None,
+    }
+}
+
+/// The language name for the system prompt. `hint` may be "auto" (detect from `prompt`), a
+/// code the detector knows ("de") or any language name, used as given.
+pub fn resolve(hint: Option<&str>, prompt: &str) -> Option<String> {
+    let hint = hint.map(str::trim).filter(|h| !h.is_empty())?;
+    if hint.eq_ignore_ascii_case("auto") {
+        let detected = detect(prompt);
+        log::info!("Detected prompt language: {:?}", detected);
+        return detected.map(String::from);
+    }
+    let name = LANGUAGES
+        .iter()
+        .find(|(code, _, _)| code.eq_ignore_ascii_case(hint))
+        .map_or(hint, |(_, name, _)| name);
+    Some(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_common_languages() {
+        assert_eq!(detect("What do I have on Monday with the dentist?"), Some("English"));
+        assert_eq!(detect("Was habe ich am Montag? Ist der Termin früh?"), Some("German"));
+        assert_eq!(detect("Quand est mon rendez-vous avec le dentiste ?"), Some("French"));
+        assert_eq!(detect("¿Cuándo tengo la cita con el dentista?"), Some("Spanish"));
+        assert_eq!(detect("Quando ho il dentista? Non ricordo l'ora"), Some("Italian"));
+        assert_eq!(detect("Wanneer heb ik een afspraak met de tandarts?"), Some("Dutch"));
+    }
+
+    #[test]
+    fn single_letters_are_not_evidence() {
+        assert_eq!(detect("Plan A y plan B, e.g. gym"), None);
+        assert_eq!(detect("e e e y y y"), None);
+    }
+
+    #[test]
+    fn short_or_ambiguous_text_is_not_guessed() {
+        assert_eq!(detect("Dentist?"), None);
+        assert_eq!(detect(""), None);
+        // "con" and "una" count for both Spanish and Italian.
+        assert_eq!(detect("con una"), None);
+    }
+
+    #[test]
+    fn hint_resolves_to_a_language_name() {
+        assert_eq!(resolve(Some("de"), "anything").as_deref(), Some("German"));
+        assert_eq!(resolve(Some("Klingon"), "anything").as_deref(), Some("Klingon"));
+        assert_eq!(resolve(Some("auto"), "Was ist das und wann?").as_deref(), Some("German"));
+        assert_eq!(resolve(Some(" "), "Was ist das?"), None);
+        assert_eq!(resolve(None, "Was ist das?"), None);
+    }
+}
@@ -1,36 +1,330 @@
-mod llm;
+mod dates;
+mod embeddings;
+mod grammar;
+mod language;
+pub mod llm;
+mod logging;
 mod ollama;
+mod openai;
+mod pool;
+mod prompt;
 mod rag;
+mod sentencepiece;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
-use std::sync::Mutex;
-use std::path::PathBuf;
-use tauri::Emitter;
+use std::sync::{Arc, Mutex, RwLock};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tauri::{Emitter, Manager};
 
 struct AppState {
-    llm: Mutex<Option<llm::LlmEngine>>,
+    llm: pool::EnginePool,
+    embedder: Mutex<Option<embeddings::EmbeddingEngine>>,
+    /// Hard ceiling on `max_tokens` for every generate call, so a runaway request can't
+    /// keep the CPU busy indefinitely.
+    max_tokens_cap: u32,
+    /// Cancel flags of the model loads in progress, one per load, so `cancel_load` can't
+    /// reach a load that starts after it.
+    active_loads: Mutex<Vec<Arc<AtomicBool>>>,
+    /// Retrieval scoring tunables; see `rag::RagConfig`.
+    rag_config: RwLock<rag::RagConfig>,
+    /// Tokenizers shared by model loads and the tokenize commands; see `llm::TokenizerCache`.
+    tokenizers: llm::TokenizerCache,
+    /// KV caches of local streaming sessions and their turns in progress; see `Sessions`.
+    sessions: Mutex<Sessions>,
+    /// Base for relative `model_dir`/`events_path` arguments; the app data directory
+    /// unless changed with `set_data_dir`.
+    data_dir: RwLock<Option<PathBuf>>,
+    /// Cancel flags of the streams running without a `request_id`, one per stream, so
+    /// `cancel_generation` can't reach a stream that starts after it.
+    unnamed_requests: Mutex<Vec<Arc<AtomicBool>>>,
+    /// Cancel flags of the streams running with a `request_id`, by id; see `cancel_request`.
+    active_requests: Mutex<HashMap<String, Arc<AtomicBool>>>,
 }
 
-/// TinyLlama chat format so the model only generates the assistant reply.
-/// If current_date is Some, inject it so the model knows today's date.
-fn build_prompt_with_rag(
+/// Error from the commands that run a model, also the payload of `generate_stream`'s error
+/// event. `kind` is an `llm::LlmError::kind` (e.g. "model_files", "out_of_memory"),
+/// "not_loaded", or "other" for failures outside the engine such as bad arguments or a
+/// remote backend; `message` is for display.
+#[derive(Debug, Clone, serde::Serialize)]
+struct CommandError {
+    kind: &'static str,
+    message: String,
+}
+
+impl CommandError {
+    fn not_loaded() -> Self {
+        CommandError {
+            kind: "not_loaded",
+            message: "Model not loaded".to_string(),
+        }
+    }
+
+    /// Whether the local model couldn't be loaded, so another backend may still answer:
+    /// missing or unsupported model files, a failed build, or too little memory on every
+    /// device tried. A cancelled load, bad arguments or a failed lock aren't.
+    fn is_load_failure(&self) -> bool {
+        matches!(
+            self.kind,
+            "model_files" | "unsupported" | "load" | "out_of_memory" | "insufficient_memory"
+        )
+    }
+}
+
+impl From<llm::LlmError> for CommandError {
+    fn from(e: llm::LlmError) -> Self {
+        CommandError {
+            kind: e.kind(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<String> for CommandError {
+    fn from(message: String) -> Self {
+        CommandError {
+            kind: "other",
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// `path` as given when absolute or a URL, otherwise joined onto the configured data directory
+/// (or left relative to the working directory if there is none).
+fn resolve_path(state: &AppState, path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() || rag::events_url(path).is_some() {
+        return path.to_path_buf();
+    }
+    match state.data_dir.read().ok().and_then(|dir| dir.clone()) {
+        Some(base) => base.join(path),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Read at startup from the app config directory, if present.
+const RAG_CONFIG_FILE: &str = "rag_config.json";
+
+const DEFAULT_MAX_TOKENS: u32 = 128;
+const DEFAULT_MAX_TOKENS_CAP: u32 = 2048;
+
+/// Most completions one `generate` call may ask for with `n`; each is a full generation.
+const MAX_N: usize = 8;
+
+/// Sessions whose KV caches are kept. A full context is around 90 MB for TinyLlama, so
+/// beyond this the least recently used session starts afresh on its next turn.
+const MAX_SESSIONS: usize = 6;
+
+/// `max_tokens_cap` from the CONCIERGE_MAX_TOKENS_CAP environment variable, or the default.
+fn max_tokens_cap_from_env() -> u32 {
+    match std::env::var("CONCIERGE_MAX_TOKENS_CAP") {
+        Ok(v) => v.trim().parse().unwrap_or_else(|_| {
+            log::warn!("Invalid CONCIERGE_MAX_TOKENS_CAP {:?}; using {}", v, DEFAULT_MAX_TOKENS_CAP);
+            DEFAULT_MAX_TOKENS_CAP
+        }),
+        Err(_) => DEFAULT_MAX_TOKENS_CAP,
+    }
+}
+
+/// Requested `max_tokens` (or the default), clamped to the app-wide cap.
+fn clamp_max_tokens(requested: Option<u32>, cap: u32) -> u32 {
+    let requested = requested.unwrap_or(DEFAULT_MAX_TOKENS);
+    if requested > cap {
+        log::warn!("max_tokens {} exceeds cap {}; clamping", requested, cap);
+        cap
+    } else {
+        requested
+    }
+}
+
+/// The user's prompt, trimmed unless `raw`. An empty or whitespace-only prompt is rejected
+/// before any model work.
+fn user_prompt(prompt: String, raw: bool) -> Result<String, String> {
+    if prompt.trim().is_empty() {
+        return Err("empty prompt".to_string());
+    }
+    Ok(if raw { prompt } else { prompt.trim().to_string() })
+}
+
+const DEFAULT_RAG_LIMIT: usize = 5;
+const MAX_RAG_LIMIT: usize = 50;
+
+/// Requested number of events to inject (or the default), clamped to `1..=MAX_RAG_LIMIT`.
+fn clamp_rag_limit(requested: Option<usize>) -> usize {
+    let requested = requested.unwrap_or(DEFAULT_RAG_LIMIT);
+    let clamped = requested.clamp(1, MAX_RAG_LIMIT);
+    if clamped != requested {
+        log::warn!("rag_limit {} outside 1..={}; clamping", requested, MAX_RAG_LIMIT);
+    }
+    clamped
+}
+
+/// Retrieval limits from the commands' parameters. The candidate count defaults to the
+/// injected count and is never below it.
+fn rag_limits(
+    limit: Option<usize>,
+    candidate_limit: Option<usize>,
+    offset: Option<usize>,
+) -> rag::Limits {
+    let inject = clamp_rag_limit(limit);
+    let candidates = candidate_limit.map_or(inject, |n| clamp_rag_limit(Some(n)).max(inject));
+    rag::Limits {
+        candidates,
+        inject,
+        offset: offset.unwrap_or(0),
+    }
+}
+
+/// What the system block says when retrieval finds no matching events.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NoEventsMode {
+    /// Tell the model there is no record and to answer from general knowledge.
+    Instruct,
+    /// Leave the events block out entirely.
+    Omit,
+    /// The old "(No relevant events found.)" line under "Relevant events:".
+    Placeholder,
+}
+
+impl NoEventsMode {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "instruct" => Ok(NoEventsMode::Instruct),
+            "omit" => Ok(NoEventsMode::Omit),
+            "placeholder" => Ok(NoEventsMode::Placeholder),
+            other => Err(format!(
+                "Unknown no_events mode: {} (expected \"instruct\", \"omit\" or \"placeholder\")",
+                other
+            )),
+        }
+    }
+
+    fn events_block(self) -> String {
+        match self {
+            NoEventsMode::Instruct => "No matching events; answer from general knowledge and say \
+                 you have no record of such an event.\n"
+                .to_string(),
+            NoEventsMode::Omit => String::new(),
+            NoEventsMode::Placeholder => {
+                "Relevant events:\n(No relevant events found.)\n".to_string()
+            }
+        }
+    }
+}
+
+/// When `generate_stream` sends reply text as token events.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FlushMode {
+    /// Each chunk as soon as it is decoded.
+    Token,
+    /// Whole sentences only (see `sentence_end`), e.g. for text-to-speech.
+    Sentence,
+}
+
+impl FlushMode {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "token" => Ok(FlushMode::Token),
+            "sentence" => Ok(FlushMode::Sentence),
+            other => Err(format!(
+                "Unknown flush_mode: {} (expected \"token\" or \"sentence\")",
+                other
+            )),
+        }
+    }
+}
+
+fn no_events_mode(no_events: Option<&str>) -> Result<NoEventsMode, String> {
+    no_events.map_or(Ok(NoEventsMode::Instruct), NoEventsMode::parse)
+}
+
+fn context_verbosity(verbosity: Option<&str>) -> Result<rag::Verbosity, String> {
+    verbosity.map_or(Ok(rag::Verbosity::Full), rag::Verbosity::parse)
+}
+
+fn parse_seed_mode(seed_mode: Option<&str>) -> Result<llm::SeedMode, CommandError> {
+    let Some(mode) = seed_mode else {
+        return Ok(llm::SeedMode::Fixed(llm::DEFAULT_SEED));
+    };
+    Ok(llm::SeedMode::parse(mode)?)
+}
+
+const OUTPUT_GUARD: &str =
+    "Only output the assistant reply. Do not generate any user message or \"User:\" line.";
+
+/// The reply under `strict_rag` when no event was retrieved; the model isn't run.
+const STRICT_RAG_REPLY: &str = "I don't have that in your schedule.";
+
+/// Added after the events under `strict_rag`, since the retrieved events may not cover the
+/// question.
+const STRICT_RAG_INSTRUCTION: &str = "Answer only from the events listed above. If they \
+     don't answer the question, reply exactly: \"I don't have that in your schedule.\"\n";
+
+/// `build_system_block` under `strict_rag` found no events to answer from.
+#[derive(Debug)]
+struct NoSources;
+
+/// System instructions for the reply, one layer per line in this order: the caller's
+/// `instructions` (a base persona, then any per-session ones), the reply language, today's
+/// date (and the time, when `current_date` is an ISO datetime such as "2026-02-16T14:30"),
+/// retrieved events when available, and the output guard. Empty layers are left out, and
+/// None means there is nothing to add besides the guard.
+/// Events are retrieved within `limits` (see `rag::Limits`), re-ranked by `rerank` when
+/// given, from the events tagged with one of `filter_tags` when that is set; `no_events`
+/// decides what to say when none match. With `strict`, no matching events
+/// (or no events file) is `NoSources` instead, and found events come with an instruction to
+/// answer from them alone.
+#[allow(clippy::too_many_arguments)]
+fn build_system_block(
     prompt: &str,
-    events_path: Option<&str>,
+    events_path: Option<&Path>,
     current_date: Option<&str>,
-) -> String {
-    let date_line = current_date
-        .map(|d| format!("Today's date: {}.\n", d))
-        .unwrap_or_default();
+    filter_tags: Option<&[String]>,
+    instructions: &[&str],
+    language: Option<&str>,
+    search: &rag::SearchOptions,
+    limits: rag::Limits,
+    no_events: NoEventsMode,
+    rerank: Option<rag::Reranker>,
+    strict: bool,
+) -> Result<Option<String>, NoSources> {
+    let mut layers: Vec<String> = instructions.iter().map(|s| s.to_string()).collect();
+    layers.extend(language.map(|l| format!("Respond in {}.", l)));
+    let (current_date, current_time) = match current_date.map(dates::split_current_date) {
+        Some((date, time)) => (Some(date), time),
+        None => (None, None),
+    };
+    let current_date = current_date.as_deref();
+    layers.extend(current_date.map(|d| format!("Today's date: {}.", d)));
+    layers.extend(current_time.map(|t| format!("Current time: {}.", t.format("%H:%M"))));
 
+    let mut events_block = String::new();
+    let mut found = false;
     if let Some(path) = events_path {
-        let path = std::path::Path::new(path);
-        if path.exists() {
-            match rag::retrieve_context(path, prompt, 5) {
-                Ok(context) => {
-                    return format!(
-                        "<|system|>\n{}Relevant events:\n{}\nOnly output the assistant reply. Do not generate any user message or \"User:\" line.</s>\n<|user|>\n{}</s>\n<|assistant|>\n",
-                        date_line, context, prompt
-                    );
+        if path.exists() || rag::events_url(path).is_some() {
+            let context = rag::retrieve_context(
+                path,
+                prompt,
+                current_date,
+                filter_tags,
+                limits,
+                search,
+                rerank,
+            );
+            match context {
+                Ok(Some(context)) => {
+                    events_block = format!("Relevant events:\n{}\n", context);
+                    found = true;
                 }
+                Ok(None) => events_block = no_events.events_block(),
                 Err(e) => {
                     log::warn!("RAG retrieval failed: {}; using raw prompt", e);
                 }
@@ -39,144 +333,2239 @@ fn build_prompt_with_rag(
             log::warn!("Events file not found: {}; using raw prompt", path.display());
         }
     }
-    if date_line.is_empty() {
-        format!("<|user|>\n{}</s>\n<|assistant|>\n", prompt)
-    } else {
-        format!(
-            "<|system|>\n{}Only output the assistant reply. Do not generate any user message or \"User:\" line.</s>\n<|user|>\n{}</s>\n<|assistant|>\n",
-            date_line, prompt
-        )
+    if strict {
+        if !found {
+            log::info!("Strict RAG: no events to answer from; not running the model");
+            return Err(NoSources);
+        }
+        events_block.push_str(STRICT_RAG_INSTRUCTION);
     }
-}
+    layers.push(events_block);
 
-/// Strip any model-generated "User:" or "<|user|>" so we never show fake user prompts.
-fn strip_fake_user_prompts(response: &str) -> String {
-    let markers = ["\nUser:", "\n<|user|>", "\n\nUser:"];
-    let truncate_at = markers
+    let mut layers: Vec<&str> = layers
         .iter()
-        .filter_map(|m| response.find(m))
-        .min()
-        .unwrap_or(response.len());
-    response[..truncate_at].trim_end().to_string()
+        .map(|layer| layer.trim())
+        .filter(|layer| !layer.is_empty())
+        .collect();
+    if layers.is_empty() {
+        return Ok(None);
+    }
+    layers.push(OUTPUT_GUARD);
+    Ok(Some(layers.join("\n")))
 }
 
-#[tauri::command]
+/// The instruction layers for `build_system_block`: the persona from `system_prompt`, then
+/// the per-session `instructions` in order.
+fn instruction_layers<'a>(
+    system_prompt: Option<&'a str>,
+    instructions: Option<&'a [String]>,
+) -> Vec<&'a str> {
+    system_prompt
+        .into_iter()
+        .chain(instructions.unwrap_or_default().iter().map(String::as_str))
+        .collect()
+}
+
+/// Retrieval options from the generate commands' parameters; unset ones keep their defaults.
+fn search_options(
+    state: &AppState,
+    rag_stemming: Option<bool>,
+    rag_chunk_chars: Option<usize>,
+    rag_max_chars: Option<usize>,
+    verbosity: rag::Verbosity,
+) -> rag::SearchOptions {
+    let defaults = rag::SearchOptions::default();
+    rag::SearchOptions {
+        stem: rag_stemming.unwrap_or(defaults.stem),
+        chunk_chars: rag_chunk_chars.filter(|&n| n > 0).or(defaults.chunk_chars),
+        max_context_chars: rag_max_chars.or(defaults.max_context_chars),
+        verbosity,
+        today: None,
+        config: state.rag_config.read().map(|c| *c).unwrap_or_default(),
+    }
+}
+
+/// Payload of the `prompt-debug` event.
+#[derive(Clone, serde::Serialize)]
+struct PromptDebug {
+    backend: String,
+    /// Exactly what the backend receives: the templated prompt, or for "openai" the
+    /// messages as JSON.
+    prompt: String,
+}
+
+fn emit_prompt_debug(window: &tauri::Window, backend: &str, prompt: String) {
+    log::debug!("Prompt for {}:\n{}", backend, prompt);
+    let payload = PromptDebug {
+        backend: backend.to_string(),
+        prompt,
+    };
+    let _ = window.emit("prompt-debug", payload);
+}
+
+/// `template`'s prompt with the system block from `build_system_block`.
+/// If current_date is Some, inject it so the model knows today's date (and time).
+#[allow(clippy::too_many_arguments)]
+fn build_prompt_with_rag(
+    template: &dyn prompt::ChatTemplate,
+    prompt: &str,
+    events_path: Option<&Path>,
+    current_date: Option<&str>,
+    filter_tags: Option<&[String]>,
+    instructions: &[&str],
+    language: Option<&str>,
+    search: &rag::SearchOptions,
+    limits: rag::Limits,
+    no_events: NoEventsMode,
+    rerank: Option<rag::Reranker>,
+    strict: bool,
+) -> Result<Vec<llm::PromptPart>, NoSources> {
+    let system = build_system_block(
+        prompt,
+        events_path,
+        current_date,
+        filter_tags,
+        instructions,
+        language,
+        search,
+        limits,
+        no_events,
+        rerank,
+        strict,
+    )?;
+    Ok(template.parts(system.as_deref(), &[], prompt))
+}
+
+/// Score `event` (a prompt line from `rag`) for relevance to `query` with a short greedy
+/// generation, laid out by the request's `template`: the model is asked for a number from 0
+/// to 10. None when it answers with anything else, or generation fails.
+fn llm_relevance(
+    engine: &llm::LlmEngine,
+    template: &dyn prompt::ChatTemplate,
+    query: &str,
+    event: &str,
+) -> Option<f32> {
+    let question = format!(
+        "Question: {}\nEvent: {}\nHow relevant is this event to the question? \
+         Answer with a single number from 0 (unrelated) to 10 (exactly what is asked).",
+        query,
+        event.trim_start_matches("- ")
+    );
+    let options = llm::GenerateOptions {
+        max_tokens: 4,
+        ..Default::default()
+    };
+    let reply = match engine.generate(&template.parts(None, &[], &question), &options) {
+        Ok(generation) => generation.text,
+        Err(e) => {
+            log::warn!("Re-ranking generation failed: {}", e);
+            return None;
+        }
+    };
+    let score = parse_relevance(&reply);
+    log::debug!("Relevance {:?} for {}", score, event);
+    score
+}
+
+/// The 0-10 score a `llm_relevance` reply starts with, e.g. "7" or " 10/10"; None for
+/// anything else, "11" included.
+fn parse_relevance(reply: &str) -> Option<f32> {
+    let digits: String = reply
+        .trim_start()
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+    digits.parse::<f32>().ok().filter(|score| *score <= 10.0)
+}
+
+/// Fake-turn markers for the TinyLlama template, used when a request doesn't pick others.
+const DEFAULT_FAKE_TURN_MARKERS: &[&str] = &["User:", "<|user|>"];
+
+/// Markers that start a model-invented user turn in each known chat template.
+fn fake_turn_markers(template: &str) -> Option<&'static [&'static str]> {
+    match template.to_ascii_lowercase().as_str() {
+        "tinyllama" | "zephyr" => Some(DEFAULT_FAKE_TURN_MARKERS),
+        "llama2" | "mistral" => Some(&["[INST]", "User:"]),
+        "chatml" => Some(&["<|im_start|>user", "User:"]),
+        "alpaca" | "human" => Some(&["### Human:", "### Instruction:", "User:"]),
+        _ => None,
+    }
+}
+
+/// The layout a `chat_template` name selects (see `prompt::template`): TinyLlama without
+/// one, and for names that only pick fake-turn markers ("alpaca").
+fn prompt_template(name: Option<&str>) -> Result<&'static dyn prompt::ChatTemplate, String> {
+    let Some(name) = name else {
+        return Ok(&prompt::TinyLlama);
+    };
+    match (prompt::template(name), fake_turn_markers(name)) {
+        (Some(template), _) => Ok(template),
+        (None, Some(_)) => Ok(&prompt::TinyLlama),
+        (None, None) => Err(format!("Unknown chat template {:?}", name)),
+    }
+}
+
+/// Markers for a request: explicit `markers` win, then the named `template`'s set, then
+/// the TinyLlama defaults.
+fn resolve_fake_turn_markers(
+    template: Option<&str>,
+    markers: Option<Vec<String>>,
+) -> Result<Vec<String>, String> {
+    if let Some(markers) = markers {
+        return Ok(markers.into_iter().filter(|m| !m.is_empty()).collect());
+    }
+    let set = match template {
+        Some(name) => fake_turn_markers(name)
+            .ok_or_else(|| format!("Unknown chat template {:?}", name))?,
+        None => DEFAULT_FAKE_TURN_MARKERS,
+    };
+    Ok(set.iter().map(|m| m.to_string()).collect())
+}
+
+/// Strip any model-generated user turn so we never show fake user prompts. A marker counts
+/// at the start of the response or of any line; one at the very start means the whole
+/// response is a fake turn, so nothing is left. Lines inside fenced code blocks (``` or ~~~)
+/// are skipped, so code that mentions "User:" survives.
+fn strip_fake_user_prompts<S: AsRef<str>>(response: &str, markers: &[S]) -> String {
+    let mut fence: Option<&str> = None;
+    let mut offset = 0;
+    for line in response.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        match ["```", "~~~"].into_iter().find(|f| trimmed.starts_with(f)) {
+            Some(marker) if fence.is_none() => fence = Some(marker),
+            Some(marker) if fence == Some(marker) => fence = None,
+            _ if fence.is_some() => {}
+            _ => {
+                let at_start = response[..offset].trim().is_empty();
+                let candidate = if at_start { trimmed } else { line };
+                if markers.iter().any(|m| candidate.starts_with(m.as_ref())) {
+                    break;
+                }
+            }
+        }
+        offset += line.len();
+    }
+    response[..offset].trim_end().to_string()
+}
+
+/// Role prefixes a model sometimes opens its reply with, matched ignoring ASCII case.
+const ASSISTANT_PREFIXES: &[&str] = &["<|assistant|>", "assistant:"];
+
+/// Drop leading whitespace and any `ASSISTANT_PREFIXES` from the start of the response, so
+/// "<|assistant|>\nAssistant: Sure." becomes "Sure.". The same words later on are kept.
+fn strip_assistant_prefix(response: &str) -> &str {
+    let mut rest = response.trim_start();
+    while let Some(prefix) = ASSISTANT_PREFIXES.iter().find(|prefix| {
+        rest.get(..prefix.len()).is_some_and(|head| head.eq_ignore_ascii_case(prefix))
+    }) {
+        rest = rest[prefix.len()..].trim_start();
+    }
+    rest
+}
+
+/// The next chunk of a streamed reply with `strip_assistant_prefix` applied to its start:
+/// chunks are collected in `lead` (while it is Some) and None is returned until the reply
+/// is more than whitespace and the beginning of an `ASSISTANT_PREFIXES` entry.
+fn past_assistant_prefix<'t>(lead: &mut Option<String>, text: &'t str) -> Option<Cow<'t, str>> {
+    let Some(held) = lead.as_mut() else {
+        return Some(Cow::Borrowed(text));
+    };
+    held.push_str(text);
+    let rest = strip_assistant_prefix(held);
+    let partial = ASSISTANT_PREFIXES.iter().any(|prefix| {
+        rest.len() < prefix.len()
+            && prefix.get(..rest.len()).is_some_and(|head| head.eq_ignore_ascii_case(rest))
+    });
+    if rest.is_empty() || partial {
+        return None;
+    }
+    let rest = rest.to_string();
+    *lead = None;
+    Some(Cow::Owned(rest))
+}
+
+/// Load the engine on first use, or reload it when it was loaded from another `model_dir`,
+/// a different dtype is requested or the loaded engine's device isn't in
+/// `device_preference`. The devices are tried in order (see
+/// `llm::load_preferring`; the CPU without a preference), and the one used is named in a
+/// `device-selected` event, followed by `model-ready` with the model's `llm::ModelInfo`.
+/// An engine on any of the preferred devices is kept, not just on the first: with
+/// `["cuda", "cpu"]`, a model that fell back to the CPU stays there instead of being
+/// reloaded on every request to try CUDA again; asking for `["cuda"]` alone retries it.
+/// A reload drops the old engine first, so the slot is left empty if the load fails or is
+/// cancelled. The tokenizer is taken from `tokenizers` when cached.
+fn ensure_engine<'a>(
+    window: &tauri::Window,
+    active_loads: &Mutex<Vec<Arc<AtomicBool>>>,
+    tokenizers: &llm::TokenizerCache,
+    guard: &'a mut Option<llm::LlmEngine>,
+    model_dir: &Path,
+    dtype: Option<&str>,
+    device_preference: Option<&[String]>,
+) -> Result<&'a llm::LlmEngine, CommandError> {
+    let dtype_changed = match (guard.as_ref(), dtype) {
+        (Some(engine), Some(name)) => llm::parse_dtype(name)? != engine.dtype,
+        _ => false,
+    };
+    let device_changed = match (guard.as_ref(), device_preference) {
+        (Some(engine), Some(names)) if !names.is_empty() => {
+            !names.iter().any(|name| llm::device_matches(name, &engine.device))
+        }
+        _ => false,
+    };
+    let dir_changed = guard.as_ref().is_some_and(|engine| engine.model_dir != model_dir);
+    if guard.is_none() || dir_changed || dtype_changed || device_changed {
+        log::info!("Loading model from {}", model_dir.display());
+        *guard = None;
+        let cancel = Arc::new(AtomicBool::new(false));
+        active_loads.lock().map_err(|e| e.to_string())?.push(cancel.clone());
+        let preference = device_preference.unwrap_or_default();
+        let loaded = llm::load_preferring(model_dir, dtype, preference, tokenizers, &cancel);
+        if let Ok(mut loads) = active_loads.lock() {
+            loads.retain(|load| !Arc::ptr_eq(load, &cancel));
+        }
+        let engine = loaded?;
+        let _ = window.emit("device-selected", llm::device_name(&engine.device));
+        let _ = window.emit("model-ready", engine.info());
+        *guard = Some(engine);
+    }
+    guard.as_ref().ok_or_else(CommandError::not_loaded)
+}
+
+/// Cut back to the end of the last complete sentence as `sentence_end` finds it, with the
+/// end of the text counting as the whitespace after it, so "costs 3." is kept and "costs 3"
+/// isn't cut to "costs". Returns the text unchanged if it contains no complete sentence.
+fn trim_incomplete_sentence(text: &str) -> String {
+    let trimmed = text.trim_end();
+    match sentence_end(&format!("{} ", trimmed)) {
+        Some(end) => trimmed[..end.min(trimmed.len())].trim_end().to_string(),
+        None => text.to_string(),
+    }
+}
+
+/// Titles and abbreviations whose "." doesn't end a sentence, as in "Dr. Smith".
+const ABBREVIATIONS: &[&str] =
+    &["mr", "mrs", "ms", "dr", "prof", "st", "jr", "sr", "vs", "e.g", "i.e"];
+
+/// Byte offset just past the last complete sentence in `text`: sentence-ending punctuation
+/// (plus any closing quote or bracket) and the whitespace after it, so "3.5", "Dr. Smith" and
+/// a "." still waiting for what follows don't count.
+fn sentence_end(text: &str) -> Option<usize> {
+    let mut end = None;
+    let mut after_punctuation = false;
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() && after_punctuation {
+            end = Some(i + c.len_utf8());
+        }
+        after_punctuation = match c {
+            '.' => !ends_with_abbreviation(&text[..i]),
+            '!' | '?' => true,
+            '"' | '\'' | ')' | ']' | '*' => after_punctuation,
+            _ => false,
+        };
+    }
+    end
+}
+
+/// Whether the last word of `text` is one of `ABBREVIATIONS`, ignoring case.
+fn ends_with_abbreviation(text: &str) -> bool {
+    let word = text.rsplit(char::is_whitespace).next().unwrap_or_default();
+    let word = word.trim_start_matches(|c: char| !c.is_alphanumeric());
+    ABBREVIATIONS.iter().any(|abbreviation| word.eq_ignore_ascii_case(abbreviation))
+}
+
+/// Reply text held back in `FlushMode::Sentence` until a sentence (see `sentence_end`) or a
+/// line completes.
+#[derive(Default)]
+struct SentenceBuffer {
+    pending: String,
+}
+
+impl SentenceBuffer {
+    /// Adds `text`, returning the complete sentences and lines now ready to send.
+    fn push(&mut self, text: &str) -> Option<String> {
+        self.pending.push_str(text);
+        let line_end = self.pending.rfind('\n').map(|i| i + 1);
+        let end = sentence_end(&self.pending).max(line_end)?;
+        let rest = self.pending.split_off(end);
+        Some(std::mem::replace(&mut self.pending, rest))
+    }
+
+    /// Whatever is left once the reply has ended.
+    fn finish(&mut self) -> Option<String> {
+        Some(std::mem::take(&mut self.pending)).filter(|rest| !rest.is_empty())
+    }
+}
+
+/// `chat-event` payload: one event of a `generate_stream` reply, tagged by `type`.
+#[derive(Clone, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ChatEvent<'a> {
+    /// The prompt is being processed; tokens follow.
+    Generating,
+    Token {
+        text: &'a str,
+        /// The token that completed `text`, with `token_ids`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u32>,
+    },
+    Progress(llm::Progress),
+    /// The reply is complete. `metrics` is set for Ollama (see `ollama::Metrics`).
+    Done { metrics: Option<&'a ollama::Metrics> },
+    Error(&'a CommandError),
+}
+
+/// Sends `generate_stream`'s events on the `chat-event` channel, and with `legacy` also as
+/// the separate `chat-generating`, `chat-token`, `chat-progress`, `chat-done` and
+/// `chat-error` events of earlier versions.
+#[derive(Clone, Copy)]
+struct ChatEvents<'a> {
+    window: &'a tauri::Window,
+    legacy: bool,
+}
+
+impl ChatEvents<'_> {
+    fn send(&self, event: ChatEvent) {
+        let _ = self.window.emit("chat-event", event);
+    }
+
+    fn generating(&self) {
+        self.send(ChatEvent::Generating);
+        if self.legacy {
+            let _ = self.window.emit("chat-generating", ());
+        }
+    }
+
+    /// `text` as completed by token `id`; the legacy payload is `{ id, text }` with an id and
+    /// the bare text without.
+    fn token(&self, id: Option<u32>, text: &str) {
+        self.send(ChatEvent::Token { text, id });
+        if self.legacy {
+            let _ = match id {
+                Some(id) => self.window.emit("chat-token", TokenChunk { id, text }),
+                None => self.window.emit("chat-token", text),
+            };
+        }
+    }
+
+    fn progress(&self, progress: llm::Progress) {
+        self.send(ChatEvent::Progress(progress));
+        if self.legacy {
+            let _ = self.window.emit("chat-progress", progress);
+        }
+    }
+
+    fn done(&self, metrics: Option<&ollama::Metrics>) {
+        self.send(ChatEvent::Done { metrics });
+        if self.legacy {
+            let _ = self.window.emit("chat-done", metrics);
+        }
+    }
+
+    fn error(&self, error: &CommandError) {
+        self.send(ChatEvent::Error(error));
+        if self.legacy {
+            let _ = self.window.emit("chat-error", error.clone());
+        }
+    }
+}
+
+/// Sends reply text as token events, with ids when `token_ids`. The start of the reply is
+/// held until it can't be an assistant prefix any more, which is dropped as
+/// `strip_assistant_prefix` does. In `FlushMode::Sentence` text is held until a sentence or
+/// line completes (see `SentenceBuffer`); `finish` sends the rest.
+struct TokenEmitter<'a> {
+    events: ChatEvents<'a>,
+    flush: FlushMode,
+    token_ids: bool,
+    /// The reply so far while it is whitespace or (part of) `ASSISTANT_PREFIXES`.
+    lead: Option<String>,
+    sentences: SentenceBuffer,
+    /// The token that completed the last pushed text.
+    last_id: u32,
+}
+
+impl<'a> TokenEmitter<'a> {
+    fn new(events: ChatEvents<'a>, flush: FlushMode, token_ids: bool) -> Self {
+        TokenEmitter {
+            events,
+            flush,
+            token_ids,
+            lead: Some(String::new()),
+            sentences: SentenceBuffer::default(),
+            last_id: 0,
+        }
+    }
+
+    /// `text` as completed by token `id` (ignored without `token_ids`).
+    fn push(&mut self, id: u32, text: &str) {
+        self.last_id = id;
+        let Some(text) = past_assistant_prefix(&mut self.lead, text) else {
+            return;
+        };
+        if self.flush == FlushMode::Token {
+            self.send(id, &text);
+            return;
+        }
+        if let Some(sentences) = self.sentences.push(&text) {
+            self.send(id, &sentences);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(lead) = self.lead.take() {
+            self.sentences.pending.push_str(strip_assistant_prefix(&lead));
+        }
+        if let Some(rest) = self.sentences.finish() {
+            self.send(self.last_id, &rest);
+        }
+    }
+
+    fn send(&self, id: u32, text: &str) {
+        self.events.token(self.token_ids.then_some(id), text);
+    }
+}
+
+/// With `raw`, `prompt` is sent to the model exactly as given: no chat template, system
+/// block, RAG context or BOS token (write "<s>" if the model needs it).
+/// `strip_fake_user_prompts` is still applied to the reply.
+///
+/// `chat_template` lays out the prompt as "tinyllama" (the default, also "zephyr"),
+/// "llama2" (or "mistral") or "chatml" (see `prompt::template`), and picks the fake-turn
+/// markers cut from the reply; "alpaca" only picks markers. `fake_turn_markers` overrides
+/// the markers.
+///
+/// A leading "Assistant:" or "<|assistant|>" the model echoes is removed from the reply
+/// unless `strip_assistant_prefix` is false.
+///
+/// `grammar: "json"` constrains the reply to a single JSON object (see `grammar` for the
+/// supported subset).
+///
+/// `format: "json"` checks that each reply is a JSON object and repairs one cut off
+/// mid-way (see `grammar::repair_json`); replies then come as objects whose `json` field
+/// is "valid", "repaired" or "invalid" (no object found). Combine it with `grammar:
+/// "json"` so the only way to go wrong is running out of tokens.
+///
+/// `rag_no_events` picks what the model is told when no event matches: "instruct" (the
+/// default; answer from general knowledge and admit there's no record), "omit" or
+/// "placeholder".
+///
+/// `filter_tags` restricts retrieval to events tagged with at least one of the tags.
+///
+/// `rag_candidate_limit` retrieves that many best matches, re-ranks them by how close they
+/// are to `current_date` and injects the first `rag_limit`; by default no re-ranking is done.
+///
+/// `rag_max_chars` caps the injected events block, dropping the lowest-ranked events first
+/// and noting that some were omitted. `context_verbosity` trims each event instead: "full"
+/// (the default), "titles" (no descriptions) or "compact" (title and date only).
+///
+/// With `llm_rerank`, the model itself scores each retrieved candidate's relevance to the
+/// prompt and the events are injected best first. This costs a short generation per
+/// candidate, so it is off by default.
+///
+/// `device_preference` lists devices to load the model on, in order, e.g. ["cuda:0",
+/// "metal", "cpu"]; each that can't be opened or can't hold the model is skipped, and a
+/// `device-selected` event names the one used. Without it the model loads on the CPU.
+///
+/// `language` adds "Respond in <language>." to the system block: a language name, a code
+/// such as "de", or "auto" to guess it from the prompt (see `language::detect`).
+///
+/// With `debug`, the assembled prompt is emitted as a `prompt-debug` event before
+/// generating; the return value stays the plain reply.
+///
+/// `top_k`/`top_p` narrow sampling; at temperature 0 decoding is greedy and they are
+/// ignored with a warning (see `llm::GenerateOptions`).
+///
+/// `stop_tokens` are extra tokens (e.g. "<|im_end|>", "<|eot_id|>") that end the reply like
+/// EOS, for models whose config.json doesn't name their turn-end token.
+///
+/// `max_lines` ends the reply once it holds that many newlines, for short answers; the
+/// finish is a normal stop, so `trim_incomplete_sentence` leaves it alone.
+///
+/// `min_tokens` keeps the model from ending the reply (EOS or a stop token) before that
+/// many tokens, e.g. to make it continue a reply it cut short. `max_tokens` still caps it.
+///
+/// `repeat_penalty_completion_only` keeps the repeat penalty off the prompt's tokens, so
+/// the reply can echo names from the question (see `llm::GenerateOptions`).
+///
+/// `timeout_ms` stops generating after that long and returns the reply so far, which
+/// `trim_incomplete_sentence` then trims as if `max_tokens` had been reached.
+///
+/// `seed_mode` picks the sampling seed: "fixed" (the default), a number, "random", or
+/// "prompt" to derive it from the prompt so the same question gets the same answer at any
+/// temperature (see `llm::SeedMode`).
+///
+/// With `n`, that many independent completions are generated, the i-th seeded with the
+/// chosen seed plus i, and a list of replies is returned instead of a single string. They
+/// only differ when sampling (temperature above 0). At most `MAX_N` (8) are allowed.
+///
+/// With `return_logprobs`, each reply is an object with its `text` and the `logprobs` of
+/// every generated token (see `llm::TokenLogprob`) instead of a plain string.
+///
+/// With `strict_rag`, a prompt that retrieves no events gets "I don't have that in your
+/// schedule." without running the model, and otherwise the model is told to answer only
+/// from the retrieved events. It has no effect with `raw`.
+///
+/// `cache_prefix` keeps the system block's KV cache for the next call with the same block
+/// (see `llm::GenerateOptions::cache_prefix`). It is off by default since replies then
+/// aren't bit-identical to uncached ones; `benchmark` with `prefix` shows whether it pays.
+#[tauri::command(async)]
+#[allow(clippy::too_many_arguments)]
 fn generate(
     prompt: String,
     model_dir: String,
     events_path: Option<String>,
     current_date: Option<String>,
+    filter_tags: Option<Vec<String>>,
     max_tokens: Option<u32>,
+    min_tokens: Option<usize>,
     temperature: Option<f64>,
+    top_k: Option<usize>,
+    top_p: Option<f64>,
+    stop_tokens: Option<Vec<String>>,
+    max_lines: Option<usize>,
+    repeat_penalty_completion_only: Option<bool>,
+    seed_mode: Option<String>,
+    n: Option<usize>,
+    timeout_ms: Option<u64>,
+    system_prompt: Option<String>,
+    instructions: Option<Vec<String>>,
+    language: Option<String>,
+    dtype: Option<String>,
+    device_preference: Option<Vec<String>>,
+    trim_incomplete_sentence: Option<bool>,
+    rag_stemming: Option<bool>,
+    rag_chunk_chars: Option<usize>,
+    rag_max_chars: Option<usize>,
+    context_verbosity: Option<String>,
+    rag_limit: Option<usize>,
+    rag_candidate_limit: Option<usize>,
+    rag_offset: Option<usize>,
+    rag_no_events: Option<String>,
+    strict_rag: Option<bool>,
+    llm_rerank: Option<bool>,
+    raw: Option<bool>,
+    grammar: Option<String>,
+    format: Option<String>,
+    chat_template: Option<String>,
+    fake_turn_markers: Option<Vec<String>>,
+    strip_assistant_prefix: Option<bool>,
+    return_logprobs: Option<bool>,
+    cache_prefix: Option<bool>,
+    debug: Option<bool>,
+    window: tauri::Window,
     state: tauri::State<AppState>,
-) -> Result<String, String> {
-    let path = PathBuf::from(&model_dir);
+) -> Result<Reply, CommandError> {
+    let raw = raw.unwrap_or(false);
+    let prompt = user_prompt(prompt, raw)?;
+    let markers = resolve_fake_turn_markers(chat_template.as_deref(), fake_turn_markers)?;
+    let template = prompt_template(chat_template.as_deref())?;
+    let seed_mode = parse_seed_mode(seed_mode.as_deref())?;
+    let verbosity = self::context_verbosity(context_verbosity.as_deref())?;
+    let json_format = self::json_format(format.as_deref())?;
+    if n == Some(0) {
+        return Err("n must be at least 1".to_string().into());
+    }
+    if n.is_some_and(|n| n > MAX_N) {
+        return Err(format!("n must be at most {}", MAX_N).into());
+    }
+    let mut options = llm::GenerateOptions {
+        max_tokens: clamp_max_tokens(max_tokens, state.max_tokens_cap) as usize,
+        min_tokens: min_tokens.unwrap_or(0),
+        temperature: temperature.unwrap_or(0.0),
+        top_k,
+        top_p,
+        seed: llm::DEFAULT_SEED,
+        grammar: grammar.as_deref().map(grammar::Grammar::parse).transpose()?,
+        stop_tokens: stop_tokens.unwrap_or_default(),
+        max_lines,
+        repeat_penalty_completion_only: repeat_penalty_completion_only.unwrap_or(false),
+        timeout: timeout_ms.map(Duration::from_millis),
+        add_special_tokens: !raw,
+        logprobs: return_logprobs.unwrap_or(false),
+        cache_prefix: cache_prefix.unwrap_or(false),
+    };
+    let model_dir = resolve_path(&state, &model_dir);
+    let events_path = events_path.map(|p| resolve_path(&state, &p));
+    let mut guard = state.llm.acquire(Some(&model_dir))?;
+    let engine = ensure_engine(
+        &window,
+        &state.active_loads,
+        &state.tokenizers,
+        &mut guard,
+        &model_dir,
+        dtype.as_deref(),
+        device_preference.as_deref(),
+    )?;
 
-    let mut guard = state.llm.lock().map_err(|e| e.to_string())?;
+    let prompt_to_use = if raw {
+        vec![llm::PromptPart::Text(prompt.clone())]
+    } else {
+        let rerank = |query: &str, event: &str| llm_relevance(engine, template, query, event);
+        let parts = build_prompt_with_rag(
+            template,
+            &prompt,
+            events_path.as_deref(),
+            current_date.as_deref(),
+            filter_tags.as_deref(),
+            &instruction_layers(system_prompt.as_deref(), instructions.as_deref()),
+            language::resolve(language.as_deref(), &prompt).as_deref(),
+            &search_options(&state, rag_stemming, rag_chunk_chars, rag_max_chars, verbosity),
+            rag_limits(rag_limit, rag_candidate_limit, rag_offset),
+            no_events_mode(rag_no_events.as_deref())?,
+            llm_rerank.unwrap_or(false).then_some(&rerank as rag::Reranker),
+            strict_rag.unwrap_or(false),
+        );
+        match parts {
+            Ok(parts) => parts,
+            Err(NoSources) => return Ok(strict_rag_reply(n, options.logprobs, json_format)),
+        }
+    };
+    if debug.unwrap_or(false) {
+        emit_prompt_debug(&window, "local", prompt::render_parts(&prompt_to_use));
+    }
+    let base_seed = seed_mode.seed(&prompt_to_use);
+    if let Some(n) = n.filter(|&n| n > 1 && options.temperature <= 0.0) {
+        log::warn!("{} completions at temperature 0 will be identical", n);
+    }
 
-    if guard.is_none() {
-        log::info!("Loading model from {}", model_dir);
-        let engine = llm::load(&path).map_err(|e| e.to_string())?;
-        *guard = Some(engine);
+    let mut replies = Vec::new();
+    let mut detailed = Vec::new();
+    for i in 0..n.unwrap_or(1) {
+        // Each run starts from its own cache, so completions don't see each other.
+        options.seed = base_seed.wrapping_add(i as u64);
+        let generation = engine.generate(&prompt_to_use, &options)?;
+        let text = if strip_assistant_prefix.unwrap_or(true) {
+            self::strip_assistant_prefix(&generation.text)
+        } else {
+            &generation.text
+        };
+        let text = strip_fake_user_prompts(text, &markers);
+        let hit_limit = matches!(
+            generation.finish_reason,
+            llm::FinishReason::Length | llm::FinishReason::Timeout
+        );
+        let (text, json) = if json_format {
+            match grammar::repair_json(&text) {
+                Some(repaired) if repaired == text.trim() => (text, Some(JsonStatus::Valid)),
+                Some(repaired) => (repaired, Some(JsonStatus::Repaired)),
+                None => (text, Some(JsonStatus::Invalid)),
+            }
+        } else if trim_incomplete_sentence.unwrap_or(false) && hit_limit {
+            (self::trim_incomplete_sentence(&text), None)
+        } else {
+            (text, None)
+        };
+        if generation.logprobs.is_some() || json.is_some() {
+            detailed.push(DetailedReply {
+                text,
+                logprobs: generation.logprobs,
+                json,
+            });
+        } else {
+            replies.push(text);
+        }
+    }
+    if !detailed.is_empty() {
+        return match n {
+            Some(_) => Ok(Reply::ManyDetailed(detailed)),
+            None => Ok(Reply::Detailed(detailed.remove(0))),
+        };
     }
+    match n {
+        Some(_) => Ok(Reply::Many(replies)),
+        None => Ok(Reply::One(replies.remove(0))),
+    }
+}
 
-    let engine = guard.as_ref().ok_or("Model not loaded")?;
-    let max_tokens = max_tokens.unwrap_or(128) as usize;
-    let temperature = temperature.unwrap_or(0.0);
-    let seed = 299792458u64;
+/// `generate`'s `STRICT_RAG_REPLY` for each of the `n` replies, in the shape the request
+/// asked for: with `logprobs` or `json_format`, as a `DetailedReply` with no log-probs (no
+/// token was generated) and `JsonStatus::Invalid`.
+fn strict_rag_reply(n: Option<usize>, logprobs: bool, json_format: bool) -> Reply {
+    let text = || STRICT_RAG_REPLY.to_string();
+    let detailed = || DetailedReply {
+        text: text(),
+        logprobs: logprobs.then(Vec::new),
+        json: json_format.then_some(JsonStatus::Invalid),
+    };
+    match (n, logprobs || json_format) {
+        (Some(n), true) => Reply::ManyDetailed((0..n).map(|_| detailed()).collect()),
+        (Some(n), false) => Reply::Many((0..n).map(|_| text()).collect()),
+        (None, true) => Reply::Detailed(detailed()),
+        (None, false) => Reply::One(text()),
+    }
+}
 
-    let prompt_to_use = build_prompt_with_rag(&prompt, events_path.as_deref(), current_date.as_deref());
+/// `generate`'s result: the reply, or with `n` a list of them; with `return_logprobs` or
+/// `format: "json"` each reply is a `DetailedReply`.
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum Reply {
+    One(String),
+    Many(Vec<String>),
+    Detailed(DetailedReply),
+    ManyDetailed(Vec<DetailedReply>),
+}
 
-    let raw = engine
-        .generate(&prompt_to_use, max_tokens, temperature, seed)
-        .map_err(|e| e.to_string())?;
-    Ok(strip_fake_user_prompts(&raw))
+/// A reply with what was asked about it. The text is after clean-up (prefix and fake-turn
+/// stripping, sentence trimming or JSON repair), so it need not match the tokens.
+#[derive(serde::Serialize)]
+struct DetailedReply {
+    text: String,
+    /// With `return_logprobs`: one entry per generated token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<Vec<llm::TokenLogprob>>,
+    /// With `format: "json"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json: Option<JsonStatus>,
 }
 
-#[tauri::command]
+/// Whether a `format: "json"` reply parsed as it was, needed `grammar::repair_json`, or
+/// held no JSON object.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum JsonStatus {
+    Valid,
+    Repaired,
+    Invalid,
+}
+
+/// `generate`'s `format`: true for "json", false for "text" (the default).
+fn json_format(format: Option<&str>) -> Result<bool, String> {
+    match format.map(|f| f.trim().to_lowercase()).as_deref() {
+        None | Some("text") => Ok(false),
+        Some("json") => Ok(true),
+        Some(other) => Err(format!("Unknown format: {} (expected \"text\" or \"json\")", other)),
+    }
+}
+
+/// Forward chunks from a remote client's worker thread as token events (grouped by `flush`),
+/// after a generating event for the wait while the server reads the prompt.
+fn forward_chunks<E: std::fmt::Display>(
+    events: ChatEvents,
+    rx: mpsc::Receiver<Result<String, E>>,
+    flush: FlushMode,
+) -> Result<(), String> {
+    events.generating();
+    let mut emitter = TokenEmitter::new(events, flush, false);
+    let mut result = Ok(());
+    while let Ok(msg) = rx.recv() {
+        match msg {
+            Ok(chunk) => emitter.push(0, &chunk),
+            Err(e) => {
+                result = Err(e.to_string());
+                break;
+            }
+        }
+    }
+    emitter.finish();
+    result
+}
+
+/// Stream from Ollama on a worker thread, forwarding chunks as token events. Setting
+/// `cancel` stops reading and closes the connection; the stream then ends as if complete.
+/// Returns Ollama's token counts and speed when the stream finished normally.
+#[allow(clippy::too_many_arguments)]
+fn stream_ollama(
+    events: ChatEvents,
+    cancel: Arc<AtomicBool>,
+    url: &str,
+    model: &str,
+    prompt: &str,
+    max_tokens: u32,
+    temperature: f64,
+    raw: bool,
+    keep_alive: Option<&str>,
+    flush: FlushMode,
+) -> Result<Option<ollama::Metrics>, String> {
+    let (tx, rx) = mpsc::channel::<Result<String, ollama::OllamaError>>();
+    let url = url.to_string();
+    let model = model.to_string();
+    let prompt = prompt.to_string();
+    let keep_alive = keep_alive.map(String::from);
+    let worker = std::thread::spawn(move || {
+        let client = reqwest::blocking::Client::new();
+        match ollama::stream_generate(
+            &client,
+            &url,
+            &model,
+            &prompt,
+            Some(max_tokens),
+            Some(temperature),
+            raw,
+            keep_alive.as_deref(),
+            &cancel,
+            tx.clone(),
+        ) {
+            Ok(metrics) => metrics,
+            Err(e) => {
+                let _ = tx.send(Err(e));
+                None
+            }
+        }
+    });
+    forward_chunks(events, rx, flush)?;
+    Ok(worker.join().ok().flatten())
+}
+
+/// Stream from an OpenAI-compatible server on a worker thread, forwarding chunks as token
+/// events. The system block goes in its own message since the server applies the model's
+/// chat template itself.
+fn stream_openai(
+    events: ChatEvents,
+    url: &str,
+    model: &str,
+    messages: Vec<openai::ChatMessage>,
+    max_tokens: u32,
+    temperature: f64,
+    flush: FlushMode,
+) -> Result<(), String> {
+    let (tx, rx) = mpsc::channel::<Result<String, String>>();
+    let url = url.to_string();
+    let model = model.to_string();
+    std::thread::spawn(move || {
+        let client = reqwest::blocking::Client::new();
+        if let Err(e) = openai::stream_chat(
+            &client,
+            &url,
+            &model,
+            messages,
+            Some(max_tokens),
+            Some(temperature),
+            tx.clone(),
+        ) {
+            let _ = tx.send(Err(e));
+        }
+    });
+    forward_chunks(events, rx, flush)
+}
+
+/// Generate from prompt token ids the caller built (e.g. with its own template); no BOS,
+/// template or RAG context is added. Returns the reply text with the generated ids.
+#[tauri::command(async)]
+#[allow(clippy::too_many_arguments)]
+fn generate_from_tokens(
+    tokens: Vec<u32>,
+    model_dir: String,
+    max_tokens: Option<u32>,
+    temperature: Option<f64>,
+    top_k: Option<usize>,
+    top_p: Option<f64>,
+    stop_tokens: Option<Vec<String>>,
+    timeout_ms: Option<u64>,
+    dtype: Option<String>,
+    window: tauri::Window,
+    state: tauri::State<AppState>,
+) -> Result<llm::Generation, CommandError> {
+    let options = llm::GenerateOptions {
+        max_tokens: clamp_max_tokens(max_tokens, state.max_tokens_cap) as usize,
+        temperature: temperature.unwrap_or(0.0),
+        top_k,
+        top_p,
+        stop_tokens: stop_tokens.unwrap_or_default(),
+        timeout: timeout_ms.map(Duration::from_millis),
+        ..Default::default()
+    };
+    let model_dir = resolve_path(&state, &model_dir);
+    let mut guard = state.llm.acquire(Some(&model_dir))?;
+    let engine = ensure_engine(
+        &window,
+        &state.active_loads,
+        &state.tokenizers,
+        &mut guard,
+        &model_dir,
+        dtype.as_deref(),
+        None,
+    )?;
+    Ok(engine.generate_from_tokens(tokens, &options)?)
+}
+
+/// How the tokenizer of the model in `model_dir` splits `text`. Only the tokenizer is
+/// loaded (and cached, see `llm::TokenizerCache`), not the model. No BOS or template tokens
+/// are added.
+#[tauri::command(async)]
+fn tokenize(
+    text: String,
+    model_dir: String,
+    state: tauri::State<AppState>,
+) -> Result<Vec<llm::TokenPiece>, CommandError> {
+    let tokenizer = state.tokenizers.get(&resolve_path(&state, &model_dir))?;
+    Ok(llm::tokenize(&tokenizer, &text)?)
+}
+
+/// How many tokens `text` is with the tokenizer of the model in `model_dir`, as for
+/// `tokenize`.
+#[tauri::command(async)]
+fn count_tokens(
+    text: String,
+    model_dir: String,
+    state: tauri::State<AppState>,
+) -> Result<usize, CommandError> {
+    let tokenizer = state.tokenizers.get(&resolve_path(&state, &model_dir))?;
+    Ok(llm::tokenize(&tokenizer, &text)?.len())
+}
+
+/// Text for token ids with the tokenizer of the model in `model_dir`, special tokens
+/// included. As for `tokenize`, the model needn't be loaded.
+#[tauri::command(async)]
+fn detokenize(
+    ids: Vec<u32>,
+    model_dir: String,
+    state: tauri::State<AppState>,
+) -> Result<String, CommandError> {
+    let tokenizer = state.tokenizers.get(&resolve_path(&state, &model_dir))?;
+    Ok(llm::detokenize(&tokenizer, &ids)?)
+}
+
+/// Legacy `chat-token` payload when the caller asks for token ids.
+#[derive(Clone, serde::Serialize)]
+struct TokenChunk<'a> {
+    id: u32,
+    text: &'a str,
+}
+
+/// Stream from a loaded local engine (see `ensure_engine`). Also emits a progress event
+/// every `llm::PROGRESS_INTERVAL` tokens. With a `session_id`, that session's KV cache is
+/// reused and updated (see `llm::Session`; only `MAX_SESSIONS` are kept). With
+/// `token_ids`, each token event carries the id of the token that completed its text, and
+/// `flush` decides how text is grouped into events. Stops after the next token once
+/// `cancel` is set.
+#[allow(clippy::too_many_arguments)]
+fn stream_local(
+    events: ChatEvents,
+    state: &AppState,
+    engine: &llm::LlmEngine,
+    prompt: &[llm::PromptPart],
+    options: &llm::GenerateOptions,
+    session_id: Option<&str>,
+    token_ids: bool,
+    flush: FlushMode,
+    cancel: &AtomicBool,
+) -> Result<(), CommandError> {
+    // Taken out of the map while generating so a concurrent turn of the same session
+    // can't share the cache; it starts fresh instead.
+    let (turn, mut session) = match session_id {
+        Some(id) => {
+            let mut sessions = state.sessions.lock().map_err(|e| e.to_string())?;
+            let (turn, session) = sessions.begin(id);
+            (turn, Some(session))
+        }
+        None => (0, None),
+    };
+
+    events.generating();
+    let mut emitter = TokenEmitter::new(events, flush, token_ids);
+    let result = engine
+        .generate_stream(
+            prompt,
+            options,
+            session.as_mut(),
+            |id, text| {
+                emitter.push(id, text);
+                !cancel.load(Ordering::Relaxed)
+            },
+            |progress| events.progress(progress),
+        )
+        .map(|_| ())
+        .map_err(CommandError::from);
+    emitter.finish();
+
+    if let (Some(id), Some(session)) = (session_id, session) {
+        let mut sessions = state.sessions.lock().map_err(|e| e.to_string())?;
+        sessions.end(turn, id, session);
+    }
+    result
+}
+
+/// `AppState::sessions`: the KV caches kept between turns, and the turns generating.
+#[derive(Default)]
+struct Sessions {
+    /// Caches with their session ids, least recently used first and at most `MAX_SESSIONS`.
+    cached: Vec<(String, llm::Session)>,
+    /// Turns in progress: their number, session id, and whether `reset_session` was called
+    /// for the session since the turn began.
+    running: Vec<(u64, String, bool)>,
+    next_turn: u64,
+}
+
+impl Sessions {
+    /// Start a turn of session `id`: its number, for `end`, and the session's cache (a fresh
+    /// one when none is kept).
+    fn begin(&mut self, id: &str) -> (u64, llm::Session) {
+        let turn = self.next_turn;
+        self.next_turn += 1;
+        self.running.push((turn, id.to_string(), false));
+        (turn, take_session(&mut self.cached, id).unwrap_or_default())
+    }
+
+    /// Finish `turn` of session `id`, keeping `session` unless the session was reset
+    /// meanwhile.
+    fn end(&mut self, turn: u64, id: &str, session: llm::Session) {
+        let Some(i) = self.running.iter().position(|(number, ..)| *number == turn) else {
+            return;
+        };
+        let (_, _, reset) = self.running.remove(i);
+        if reset {
+            log::debug!("Session {} was reset during its turn; cache dropped", id);
+        } else {
+            put_session(&mut self.cached, id, session);
+        }
+    }
+
+    /// Drop the cache kept for session `id`, and those of its turns still generating.
+    /// Returns whether there was any.
+    fn reset(&mut self, id: &str) -> bool {
+        let mut found = take_session(&mut self.cached, id).is_some();
+        for (_, key, reset) in self.running.iter_mut() {
+            if key == id {
+                log::debug!("Session {} reset while generating", id);
+                *reset = true;
+                found = true;
+            }
+        }
+        found
+    }
+}
+
+fn take_session(sessions: &mut Vec<(String, llm::Session)>, id: &str) -> Option<llm::Session> {
+    let i = sessions.iter().position(|(key, _)| key == id)?;
+    Some(sessions.remove(i).1)
+}
+
+/// Keep `session` as the most recently used, dropping the least recently used beyond
+/// `MAX_SESSIONS`.
+fn put_session(sessions: &mut Vec<(String, llm::Session)>, id: &str, session: llm::Session) {
+    sessions.retain(|(key, _)| key != id);
+    if sessions.len() >= MAX_SESSIONS {
+        let (evicted, _) = sessions.remove(0);
+        log::debug!("Session {} dropped to stay within {} sessions", evicted, MAX_SESSIONS);
+    }
+    sessions.push((id.to_string(), session));
+}
+
+/// Streams the reply as `chat-event` events, each an object tagged by `type`:
+/// "generating", then "token"s (`{ text, id? }`) and "progress"es (local backend only; see
+/// `llm::Progress`), then exactly one terminal event, "done" (`{ metrics }`) on success or
+/// "error" (the `CommandError`'s fields) on failure. With `legacy_events` the same events
+/// are also sent as the earlier separate `chat-generating`, `chat-token`, `chat-progress`,
+/// `chat-done` and `chat-error` events. Runs off the main thread (`async`) so concurrent
+/// calls can use separate pool engines.
+///
+/// `raw` skips templating and RAG as in `generate`; the OpenAI backend then sends the prompt
+/// as a lone user message, and Ollama is asked not to apply its own template either.
+///
+/// `backend` selects "local", "ollama" or "openai" (an OpenAI-compatible
+/// `/v1/chat/completions` server such as llama.cpp or LocalAI). When omitted, Ollama is used
+/// if both `ollama_url` and `ollama_model` are set, otherwise the local engine. "auto" tries
+/// the local engine and falls back to Ollama (when configured) if the model can't be loaded
+/// (see `CommandError::is_load_failure`); a cancelled load or a bad argument still fails.
+/// A `backend-selected` event names the backend that runs, and "generating" follows once
+/// the prompt is being processed (after any model load), before the first token. For
+/// Ollama, "done" carries its token counts and tokens/sec (`ollama::Metrics`); otherwise
+/// its `metrics` are null.
+///
+/// `ollama_keep_alive` tells Ollama how long to keep the model loaded after the reply, e.g.
+/// "30m", or "-1" to keep it loaded; without it the server unloads idle models on its own
+/// schedule.
+///
+/// `debug` emits the exact prompt sent to the backend as a `prompt-debug` event.
+///
+/// `llm_rerank` is rejected rather than ignored: the events are retrieved before the
+/// backend is chosen, when no local model is at hand to score them.
+///
+/// `chat_template` lays out the prompt for the local and Ollama backends as in `generate`.
+///
+/// `history` holds the conversation's earlier exchanges, laid out by the template before
+/// `prompt` (and sent as earlier messages to the OpenAI backend). It is ignored with `raw`.
+///
+/// `session_id` (local backend only) keeps the KV cache between calls with the same id, so
+/// a turn whose prompt extends the previous one only processes the new tokens. Templated
+/// prompts extend it when the previous turn is passed in `history` and the system block is
+/// unchanged; retrieved events that differ between turns change the block, and the cache
+/// then starts fresh.
+///
+/// `request_id` names the stream so `cancel_request` can stop it alone; ids must be unique
+/// among running streams.
+///
+/// `token_ids` (local backend only) adds to each token event the `id` of the token that
+/// completed the chunk (the legacy `chat-token` payload becomes `{ id, text }`).
+///
+/// `flush_mode` "sentence" holds text back until a sentence ends (".", "!" or "?" followed
+/// by whitespace) and sends whole sentences, e.g. for text-to-speech; whatever is left is
+/// sent when the reply ends. The default "token" sends each chunk as it is decoded.
+/// Either way a leading "Assistant:" or "<|assistant|>" is dropped as in `generate`, so the
+/// first chunks are held until the reply can't be one.
+///
+/// `top_k`/`top_p`, `stop_tokens`, `min_tokens`, `max_lines`,
+/// `repeat_penalty_completion_only`, `seed_mode`, `timeout_ms` and `device_preference`
+/// (local backend only) behave as in `generate`, as do `filter_tags`, `rag_candidate_limit`,
+/// `rag_max_chars`, `context_verbosity`, `strict_rag` and `language`. A `strict_rag` refusal
+/// streams its fixed reply as a single token event with no backend run.
+#[tauri::command(async)]
+#[allow(clippy::too_many_arguments)]
 fn generate_stream(
     prompt: String,
     model_dir: String,
     events_path: Option<String>,
     current_date: Option<String>,
+    filter_tags: Option<Vec<String>>,
     max_tokens: Option<u32>,
+    min_tokens: Option<usize>,
     temperature: Option<f64>,
+    top_k: Option<usize>,
+    top_p: Option<f64>,
+    stop_tokens: Option<Vec<String>>,
+    max_lines: Option<usize>,
+    repeat_penalty_completion_only: Option<bool>,
+    seed_mode: Option<String>,
+    timeout_ms: Option<u64>,
     ollama_url: Option<String>,
     ollama_model: Option<String>,
+    ollama_keep_alive: Option<String>,
+    backend: Option<String>,
+    openai_url: Option<String>,
+    openai_model: Option<String>,
+    system_prompt: Option<String>,
+    instructions: Option<Vec<String>>,
+    language: Option<String>,
+    dtype: Option<String>,
+    device_preference: Option<Vec<String>>,
+    rag_stemming: Option<bool>,
+    rag_chunk_chars: Option<usize>,
+    rag_max_chars: Option<usize>,
+    context_verbosity: Option<String>,
+    rag_limit: Option<usize>,
+    rag_candidate_limit: Option<usize>,
+    rag_offset: Option<usize>,
+    rag_no_events: Option<String>,
+    strict_rag: Option<bool>,
+    llm_rerank: Option<bool>,
+    raw: Option<bool>,
+    debug: Option<bool>,
+    chat_template: Option<String>,
+    history: Option<Vec<HistoryTurn>>,
+    session_id: Option<String>,
+    token_ids: Option<bool>,
+    flush_mode: Option<String>,
+    request_id: Option<String>,
+    legacy_events: Option<bool>,
     window: tauri::Window,
     state: tauri::State<AppState>,
-) -> Result<(), String> {
-    let prompt_to_use = build_prompt_with_rag(&prompt, events_path.as_deref(), current_date.as_deref());
-    let max_tokens_val = max_tokens.unwrap_or(128);
+) -> Result<(), CommandError> {
+    let events = ChatEvents {
+        window: &window,
+        legacy: legacy_events.unwrap_or(false),
+    };
+    if llm_rerank.unwrap_or(false) {
+        let e = CommandError::from(
+            "llm_rerank isn't supported when streaming; use generate".to_string(),
+        );
+        events.error(&e);
+        return Err(e);
+    }
+    let max_tokens_val = clamp_max_tokens(max_tokens, state.max_tokens_cap);
     let temperature_val = temperature.unwrap_or(0.0);
+    let raw = raw.unwrap_or(false);
+    let debug = debug.unwrap_or(false);
+    let prompt = match user_prompt(prompt, raw) {
+        Ok(prompt) => prompt,
+        Err(e) => {
+            let e = CommandError::from(e);
+            events.error(&e);
+            return Err(e);
+        }
+    };
+    let model_dir = resolve_path(&state, &model_dir);
+    let events_path = events_path.map(|p| resolve_path(&state, &p));
+    let no_events = match no_events_mode(rag_no_events.as_deref()) {
+        Ok(mode) => mode,
+        Err(e) => {
+            let e = CommandError::from(e);
+            events.error(&e);
+            return Err(e);
+        }
+    };
+    let seed_mode = match parse_seed_mode(seed_mode.as_deref()) {
+        Ok(mode) => mode,
+        Err(e) => {
+            events.error(&e);
+            return Err(e);
+        }
+    };
+    let verbosity = match self::context_verbosity(context_verbosity.as_deref()) {
+        Ok(verbosity) => verbosity,
+        Err(e) => {
+            let e = CommandError::from(e);
+            events.error(&e);
+            return Err(e);
+        }
+    };
+    let template = match prompt_template(chat_template.as_deref()) {
+        Ok(template) => template,
+        Err(e) => {
+            let e = CommandError::from(e);
+            events.error(&e);
+            return Err(e);
+        }
+    };
+    let flush = match flush_mode.as_deref().map_or(Ok(FlushMode::Token), FlushMode::parse) {
+        Ok(flush) => flush,
+        Err(e) => {
+            let e = CommandError::from(e);
+            events.error(&e);
+            return Err(e);
+        }
+    };
+    let system = if raw {
+        None
+    } else {
+        let system = build_system_block(
+            &prompt,
+            events_path.as_deref(),
+            current_date.as_deref(),
+            filter_tags.as_deref(),
+            &instruction_layers(system_prompt.as_deref(), instructions.as_deref()),
+            language::resolve(language.as_deref(), &prompt).as_deref(),
+            &search_options(&state, rag_stemming, rag_chunk_chars, rag_max_chars, verbosity),
+            rag_limits(rag_limit, rag_candidate_limit, rag_offset),
+            no_events,
+            None,
+            strict_rag.unwrap_or(false),
+        );
+        match system {
+            Ok(system) => system,
+            Err(NoSources) => {
+                events.token(None, STRICT_RAG_REPLY);
+                events.done(None);
+                return Ok(());
+            }
+        }
+    };
+    let history = history.unwrap_or_default();
+    let turns = history_turns(&history);
+    let prompt_parts = if raw {
+        vec![llm::PromptPart::Text(prompt.clone())]
+    } else {
+        template.parts(system.as_deref(), &turns, &prompt)
+    };
 
-    if let (Some(ref url), Some(ref model)) = (ollama_url, ollama_model) {
-        let (tx, rx) = mpsc::channel::<Result<String, String>>();
-        let url = url.clone();
-        let model = model.clone();
-        let prompt = prompt_to_use.clone();
-        std::thread::spawn(move || {
-            let client = reqwest::blocking::Client::new();
-            if let Err(e) = ollama::stream_generate(
-                &client,
-                &url,
-                &model,
-                &prompt,
-                Some(max_tokens_val),
-                Some(temperature_val as f64),
-                tx.clone(),
-            ) {
-                let _ = tx.send(Err(e));
+    let backend = match backend.as_deref() {
+        Some(b) => b.to_string(),
+        None if ollama_url.is_some() && ollama_model.is_some() => "ollama".to_string(),
+        None => "local".to_string(),
+    };
+
+    let cancel = match &request_id {
+        Some(id) => match register_request(&state, id) {
+            Ok(cancel) => cancel,
+            Err(e) => {
+                let e = CommandError::from(e);
+                events.error(&e);
+                return Err(e);
+            }
+        },
+        None => {
+            let cancel = Arc::new(AtomicBool::new(false));
+            if let Ok(mut requests) = state.unnamed_requests.lock() {
+                requests.push(cancel.clone());
             }
-        });
-        while let Ok(msg) = rx.recv() {
-            match msg {
-                Ok(chunk) => {
-                    let _ = window.emit("chat-token", chunk);
+            cancel
+        }
+    };
+
+    let select = |name: &str| {
+        let _ = window.emit("backend-selected", name);
+    };
+    let run_ollama = |url: &str, model: &str| {
+        let prompt_text = if raw {
+            prompt.clone()
+        } else {
+            template.render(system.as_deref(), &turns, &prompt)
+        };
+        if debug {
+            emit_prompt_debug(&window, "ollama", prompt_text.clone());
+        }
+        select("ollama");
+        stream_ollama(
+            events,
+            cancel.clone(),
+            url,
+            model,
+            &prompt_text,
+            max_tokens_val,
+            temperature_val,
+            raw,
+            ollama_keep_alive.as_deref(),
+            flush,
+        )
+        .map_err(CommandError::from)
+    };
+
+    let result: Result<Option<ollama::Metrics>, CommandError> = match backend.as_str() {
+        "openai" => match (openai_url, openai_model) {
+            (Some(url), Some(model)) => {
+                let mut messages = Vec::new();
+                if let Some(system) = system {
+                    messages.push(openai::ChatMessage::new("system", system));
+                }
+                if !raw {
+                    for turn in &history {
+                        let (user, assistant) = (turn.user.clone(), turn.assistant.clone());
+                        messages.push(openai::ChatMessage::new("user", user));
+                        messages.push(openai::ChatMessage::new("assistant", assistant));
+                    }
                 }
-                Err(e) => return Err(e),
+                messages.push(openai::ChatMessage::new("user", prompt.clone()));
+                if debug {
+                    let json = serde_json::to_string_pretty(&messages).unwrap_or_default();
+                    emit_prompt_debug(&window, "openai", json);
+                }
+                select("openai");
+                stream_openai(
+                    events,
+                    &url,
+                    &model,
+                    messages,
+                    max_tokens_val,
+                    temperature_val,
+                    flush,
+                )
+                .map(|()| None)
+                .map_err(CommandError::from)
+            }
+            _ => Err("openai backend requires openai_url and openai_model".to_string().into()),
+        },
+        "ollama" => match (&ollama_url, &ollama_model) {
+            (Some(url), Some(model)) => run_ollama(url, model),
+            _ => Err("ollama backend requires ollama_url and ollama_model".to_string().into()),
+        },
+        "local" | "auto" => {
+            let loaded = state.llm.acquire(Some(&model_dir));
+            let loaded = loaded.map_err(CommandError::from).and_then(|mut guard| {
+                ensure_engine(
+                    &window,
+                    &state.active_loads,
+                    &state.tokenizers,
+                    &mut guard,
+                    &model_dir,
+                    dtype.as_deref(),
+                    device_preference.as_deref(),
+                )?;
+                Ok(guard)
+            });
+            match (loaded, &ollama_url, &ollama_model) {
+                (Err(e), Some(url), Some(model)) if backend == "auto" && e.is_load_failure() => {
+                    log::warn!("Local model unavailable ({}); falling back to Ollama", e);
+                    run_ollama(url, model)
+                }
+                (Err(e), ..) => Err(e),
+                (Ok(guard), ..) => match guard.as_ref() {
+                    Some(engine) => {
+                        if debug {
+                            emit_prompt_debug(&window, "local", prompt::render_parts(&prompt_parts));
+                        }
+                        select("local");
+                        stream_local(
+                            events,
+                            &state,
+                            engine,
+                            &prompt_parts,
+                            &llm::GenerateOptions {
+                                max_tokens: max_tokens_val as usize,
+                                min_tokens: min_tokens.unwrap_or(0),
+                                temperature: temperature_val,
+                                top_k,
+                                top_p,
+                                seed: seed_mode.seed(&prompt_parts),
+                                stop_tokens: stop_tokens.unwrap_or_default(),
+                                max_lines,
+                                repeat_penalty_completion_only: repeat_penalty_completion_only
+                                    .unwrap_or(false),
+                                timeout: timeout_ms.map(Duration::from_millis),
+                                add_special_tokens: !raw,
+                                ..Default::default()
+                            },
+                            session_id.as_deref(),
+                            token_ids.unwrap_or(false),
+                            flush,
+                            &cancel,
+                        )
+                        .map(|()| None)
+                    }
+                    None => Err(CommandError::not_loaded()),
+                },
+            }
+        }
+        other => Err(format!("Unknown backend: {}", other).into()),
+    };
+    match &request_id {
+        Some(id) => {
+            if let Ok(mut requests) = state.active_requests.lock() {
+                requests.remove(id);
+            }
+        }
+        None => {
+            if let Ok(mut requests) = state.unnamed_requests.lock() {
+                requests.retain(|request| !Arc::ptr_eq(request, &cancel));
             }
         }
-        return Ok(());
     }
 
-    let path = PathBuf::from(&model_dir);
-    let mut guard = state.llm.lock().map_err(|e| e.to_string())?;
+    match &result {
+        Ok(metrics) => events.done(metrics.as_ref()),
+        Err(e) => {
+            log::error!("Streaming generation failed: {}", e);
+            events.error(e);
+        }
+    }
+    result.map(|_| ())
+}
 
-    if guard.is_none() {
-        log::info!("Loading model from {}", model_dir);
-        let engine = llm::load(&path).map_err(|e| e.to_string())?;
-        *guard = Some(engine);
+/// Abort any model load in progress (see `llm::load_cancellable` for when it takes effect).
+/// The waiting generate call then fails with "Model load cancelled".
+#[tauri::command]
+fn cancel_load(state: tauri::State<AppState>) {
+    log::info!("Model load cancel requested");
+    cancel_all_loads(&state);
+}
+
+fn cancel_all_loads(state: &AppState) {
+    if let Ok(loads) = state.active_loads.lock() {
+        for cancel in loads.iter() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A cancel flag for a stream started with `request_id`, until `generate_stream` removes
+/// it when the stream ends. An id already in use is rejected, since cancelling it would be
+/// ambiguous.
+fn register_request(state: &AppState, request_id: &str) -> Result<Arc<AtomicBool>, String> {
+    let mut requests = state.active_requests.lock().map_err(|e| e.to_string())?;
+    if requests.contains_key(request_id) {
+        return Err(format!("Request {:?} is already running", request_id));
+    }
+    let cancel = Arc::new(AtomicBool::new(false));
+    requests.insert(request_id.to_string(), cancel.clone());
+    Ok(cancel)
+}
+
+/// Stop the local or Ollama streams in progress (closing Ollama's connection), including
+/// those started with a `request_id`. The reply so far stays and "done" follows as
+/// usual. The OpenAI-compatible backend doesn't check this.
+#[tauri::command]
+fn cancel_generation(state: tauri::State<AppState>) {
+    log::info!("Generation cancel requested");
+    cancel_all_requests(&state);
+}
+
+fn cancel_all_requests(state: &AppState) {
+    if let Ok(requests) = state.unnamed_requests.lock() {
+        for cancel in requests.iter() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+    }
+    if let Ok(requests) = state.active_requests.lock() {
+        for cancel in requests.values() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Stop only the stream started with this `request_id`, as `cancel_generation` would, so
+/// other chats keep generating. Returns false when no such stream is running.
+#[tauri::command]
+fn cancel_request(request_id: String, state: tauri::State<AppState>) -> Result<bool, String> {
+    let requests = state.active_requests.lock().map_err(|e| e.to_string())?;
+    let Some(cancel) = requests.get(&request_id) else {
+        return Ok(false);
+    };
+    log::info!("Cancel requested for {}", request_id);
+    cancel.store(true, Ordering::Relaxed);
+    Ok(true)
+}
+
+/// Forget the KV cache kept for `session_id` (see `generate_stream`), so its next turn
+/// processes the whole prompt afresh, as for a new conversation, without reloading the
+/// model. A turn of that session still generating finishes, but its cache is dropped rather
+/// than kept. Returns false when nothing was cached or generating for it.
+#[tauri::command]
+fn reset_session(session_id: String, state: tauri::State<AppState>) -> Result<bool, String> {
+    let mut sessions = state.sessions.lock().map_err(|e| e.to_string())?;
+    let removed = sessions.reset(&session_id);
+    if removed {
+        log::info!("Session {} reset", session_id);
+    }
+    Ok(removed)
+}
+
+/// Change the log level at runtime: "off", "error", "warn", "info", "debug" or "trace".
+/// With `module` (e.g. "rag" or "llm"), only that module's level changes.
+#[tauri::command]
+fn set_log_level(level: String, module: Option<String>) -> Result<(), String> {
+    let filter = logging::parse_level(&level)?;
+    logging::set_level(filter, module.as_deref())?;
+    log::warn!("Log level for {} set to {}", module.as_deref().unwrap_or("all modules"), filter);
+    Ok(())
+}
+
+/// Replace the retrieval tunables for this session after validating them; returns the
+/// config now in effect.
+#[tauri::command]
+fn configure_rag(
+    config: rag::RagConfig,
+    state: tauri::State<AppState>,
+) -> Result<rag::RagConfig, String> {
+    config.validate()?;
+    *state.rag_config.write().map_err(|e| e.to_string())? = config;
+    log::info!("RAG config updated: {:?}", config);
+    Ok(config)
+}
+
+/// Preview the events retrieval would inject for `query`, so the UI can show them before
+/// generating. Uses the same defaults as the generate commands, including re-ranking when
+/// `candidate_limit` exceeds `limit`, and `filter_tags` as in `generate`. Each event carries
+/// a `relevance` from 0 to 1 (see `rag::Source`).
+#[tauri::command(async)]
+fn retrieve_events(
+    query: String,
+    events_path: String,
+    limit: Option<usize>,
+    current_date: Option<String>,
+    candidate_limit: Option<usize>,
+    filter_tags: Option<Vec<String>>,
+    state: tauri::State<AppState>,
+) -> Result<Vec<rag::Source>, String> {
+    rag::retrieve_events(
+        &resolve_path(&state, &events_path),
+        &query,
+        current_date.as_deref(),
+        filter_tags.as_deref(),
+        rag_limits(limit, candidate_limit, None),
+        &search_options(&state, None, None, None, rag::Verbosity::default()),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Append `event` to the events file and return the new number of events (see
+/// `rag::append_event`).
+#[tauri::command]
+fn add_event(
+    events_path: String,
+    event: rag::Event,
+    state: tauri::State<AppState>,
+) -> Result<usize, String> {
+    rag::append_event(&resolve_path(&state, &events_path), event).map_err(|e| e.to_string())
+}
+
+/// Set the base directory for relative `model_dir`/`events_path` arguments for the rest of
+/// the session. Returns the directory now in effect.
+#[tauri::command]
+fn set_data_dir(path: String, state: tauri::State<AppState>) -> Result<String, String> {
+    let dir = PathBuf::from(&path);
+    if !dir.is_absolute() {
+        return Err(format!("Data directory must be an absolute path: {}", path));
     }
+    if !dir.is_dir() {
+        return Err(format!("Data directory not found: {}", path));
+    }
+    log::info!("Data directory set to {}", dir.display());
+    *state.data_dir.write().map_err(|e| e.to_string())? = Some(dir);
+    Ok(path)
+}
+
+/// Validate a model directory before enabling chat, without loading the weights.
+#[tauri::command]
+fn check_model(model_dir: String, state: tauri::State<AppState>) -> llm::ModelCheck {
+    llm::check_model(&resolve_path(&state, &model_dir))
+}
+
+/// Context length and shape of the model in `model_dir`. Reuses a loaded engine for the same
+/// directory when one is idle; otherwise only config.json is read.
+#[tauri::command]
+fn model_info(
+    model_dir: String,
+    state: tauri::State<AppState>,
+) -> Result<llm::ModelInfo, CommandError> {
+    let path = resolve_path(&state, &model_dir);
+    if let Some(info) = state.llm.loaded_info(&path) {
+        return Ok(info);
+    }
+    Ok(llm::model_info(&path)?)
+}
+
+/// Throughput of the model in `model_dir` on a fixed workload (see `LlmEngine::benchmark`):
+/// `prompt` (default `llm::BENCHMARK_PROMPT`) and up to `max_tokens` generated tokens
+/// (default `llm::BENCHMARK_TOKENS`, clamped to the app-wide cap), so the UI can show e.g.
+/// "~8 tok/s on CPU". `cancel_generation` stops it early, like a stream. `device`
+/// (e.g. "cuda:0") and `dtype` pick what to measure; a pool slot already holding this model
+/// is used, and it is reloaded if it is loaded elsewhere or in another dtype.
+///
+/// With `prefix` (e.g. a typical system block), `prefix_cache` also reports what
+/// `generate`'s `cache_prefix` costs and saves for `prompt` after it (see
+/// `LlmEngine::benchmark_prefix_cache`).
+#[tauri::command(async)]
+#[allow(clippy::too_many_arguments)]
+fn benchmark(
+    model_dir: String,
+    device: Option<String>,
+    dtype: Option<String>,
+    prompt: Option<String>,
+    max_tokens: Option<u32>,
+    prefix: Option<String>,
+    window: tauri::Window,
+    state: tauri::State<AppState>,
+) -> Result<llm::Benchmark, CommandError> {
+    let model_dir = resolve_path(&state, &model_dir);
+    let mut guard = state.llm.acquire(Some(&model_dir))?;
+    let engine = ensure_engine(
+        &window,
+        &state.active_loads,
+        &state.tokenizers,
+        &mut guard,
+        &model_dir,
+        dtype.as_deref(),
+        device.map(|device| vec![device]).as_deref(),
+    )?;
+    let prompt = prompt.unwrap_or_else(|| llm::BENCHMARK_PROMPT.to_string());
+    let max_tokens = max_tokens.unwrap_or(llm::BENCHMARK_TOKENS as u32);
+    let max_tokens = clamp_max_tokens(Some(max_tokens), state.max_tokens_cap) as usize;
+    let cancel = Arc::new(AtomicBool::new(false));
+    if let Ok(mut requests) = state.unnamed_requests.lock() {
+        requests.push(cancel.clone());
+    }
+    let run = || {
+        let mut result = engine.benchmark(&prompt, max_tokens, &cancel)?;
+        log::info!(
+            "Benchmark: {:.1} prompt tok/s, {:.1} tok/s over {} tokens",
+            result.prompt_tokens_per_second,
+            result.tokens_per_second,
+            result.generated_tokens
+        );
+        if let Some(prefix) = &prefix {
+            let prefix_cache =
+                engine.benchmark_prefix_cache(prefix, &prompt, max_tokens, &cancel)?;
+            log::info!(
+                "Prefix cache: {} ms uncached, {} ms storing, {} ms cached",
+                prefix_cache.uncached_ms,
+                prefix_cache.storing_ms,
+                prefix_cache.cached_ms
+            );
+            result.prefix_cache = Some(prefix_cache);
+        }
+        Ok(result)
+    };
+    let result = run();
+    if let Ok(mut requests) = state.unnamed_requests.lock() {
+        requests.retain(|request| !Arc::ptr_eq(request, &cancel));
+    }
+    result
+}
+
+/// Events in `events_path` whose times overlap on the same day, so the UI can warn about
+/// double-bookings (see `rag::detect_conflicts`).
+#[tauri::command(async)]
+fn detect_conflicts(
+    events_path: String,
+    state: tauri::State<AppState>,
+) -> Result<Vec<rag::Conflict>, String> {
+    rag::find_conflicts(&resolve_path(&state, &events_path)).map_err(|e| e.to_string())
+}
+
+/// Which entries of the events file are broken and why, for a settings screen; generation
+/// keeps loading the file as before (see `rag::validate_events`).
+#[tauri::command(async)]
+fn validate_events(
+    events_path: String,
+    state: tauri::State<AppState>,
+) -> Result<rag::Validation, String> {
+    rag::validate_events(&resolve_path(&state, &events_path)).map_err(|e| e.to_string())
+}
+
+/// Statistics about the events file to explain weak retrieval (see `rag::CorpusStats`);
+/// with `current_date`, also how many events are still ahead.
+#[tauri::command(async)]
+fn rag_stats(
+    events_path: String,
+    current_date: Option<String>,
+    state: tauri::State<AppState>,
+) -> Result<rag::CorpusStats, String> {
+    let today = current_date.as_deref().and_then(dates::parse_date);
+    rag::rag_stats(&resolve_path(&state, &events_path), today).map_err(|e| e.to_string())
+}
 
-    let engine = guard.as_ref().ok_or("Model not loaded")?;
-    let max_tokens = max_tokens_val as usize;
-    let seed = 299792458u64;
+/// An earlier exchange as `generate_stream` and `preview_template` receive it.
+#[derive(serde::Deserialize)]
+struct HistoryTurn {
+    user: String,
+    assistant: String,
+}
 
-    engine
-        .generate_stream(&prompt_to_use, max_tokens, temperature_val, seed, |chunk| {
-            let _ = window.emit("chat-token", chunk);
+fn history_turns(history: &[HistoryTurn]) -> Vec<prompt::Turn<'_>> {
+    history
+        .iter()
+        .map(|turn| prompt::Turn {
+            user: &turn.user,
+            assistant: &turn.assistant,
         })
-        .map_err(|e| e.to_string())
+        .collect()
+}
+
+/// The prompt `template` (a `chat_template` name, see `generate`) builds from `system`,
+/// `history` and `user`, exactly as a text backend receives it, with EOS written as
+/// `llm::EOS_TOKEN`; the leading BOS is added when encoding and isn't shown. Nothing is
+/// run, so the UI can check the markers a model will see.
+#[tauri::command]
+fn preview_template(
+    template: String,
+    system: Option<String>,
+    history: Option<Vec<HistoryTurn>>,
+    user: String,
+) -> Result<String, String> {
+    let chat_template = prompt::template(&template).ok_or_else(|| {
+        format!(
+            "Unknown chat template {:?} (expected tinyllama, zephyr, llama2, mistral or chatml)",
+            template
+        )
+    })?;
+    let history = history_turns(history.as_deref().unwrap_or_default());
+    let system = system.as_deref().map(str::trim).filter(|s| !s.is_empty());
+    Ok(chat_template.render(system, &history, &user))
+}
+
+/// Embed each text with the encoder in `model_dir`, reloading if a different directory is given.
+#[tauri::command(async)]
+fn embed(
+    texts: Vec<String>,
+    model_dir: String,
+    state: tauri::State<AppState>,
+) -> Result<Vec<Vec<f32>>, CommandError> {
+    let path = resolve_path(&state, &model_dir);
+    let mut guard = state.embedder.lock().map_err(|e| e.to_string())?;
+
+    if guard.as_ref().map(|e| e.model_dir != path).unwrap_or(true) {
+        log::info!("Loading embedding model from {}", path.display());
+        let engine = embeddings::load(&path)?;
+        *guard = Some(engine);
+    }
+
+    let engine = guard.as_ref().ok_or_else(CommandError::not_loaded)?;
+    Ok(engine.embed(&texts)?)
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
   let state = AppState {
-    llm: Mutex::new(None),
+    llm: pool::EnginePool::new(pool::DEFAULT_POOL_SIZE),
+    embedder: Mutex::new(None),
+    max_tokens_cap: max_tokens_cap_from_env(),
+    active_loads: Mutex::new(Vec::new()),
+    rag_config: RwLock::new(rag::RagConfig::default()),
+    tokenizers: llm::TokenizerCache::default(),
+    sessions: Mutex::new(Sessions::default()),
+    data_dir: RwLock::new(None),
+    unnamed_requests: Mutex::new(Vec::new()),
+    active_requests: Mutex::new(HashMap::new()),
   };
   tauri::Builder::default()
     .setup(|app| {
-      if cfg!(debug_assertions) {
-        app.handle().plugin(
-          tauri_plugin_log::Builder::default()
-            .level(log::LevelFilter::Info)
-            .build(),
-        )?;
+      // Everything reaches the plugin; `logging` decides what is kept, so `set_log_level`
+      // can change it at runtime.
+      app.handle().plugin(
+        tauri_plugin_log::Builder::default()
+          .level(log::LevelFilter::Trace)
+          .filter(logging::enabled)
+          .build(),
+      )?;
+      let default_level = if cfg!(debug_assertions) {
+        log::LevelFilter::Info
+      } else {
+        log::LevelFilter::Warn
+      };
+      logging::set_level(default_level, None)?;
+      if let Ok(dir) = app.path().app_config_dir() {
+        let path = dir.join(RAG_CONFIG_FILE);
+        match rag::RagConfig::load(&path) {
+          Ok(config) => {
+            if let Ok(mut current) = app.state::<AppState>().rag_config.write() {
+              *current = config;
+            }
+          }
+          Err(e) => log::warn!("{}; using default RAG config", e),
+        }
+      }
+      if let Ok(dir) = app.path().app_data_dir() {
+        if let Ok(mut data_dir) = app.state::<AppState>().data_dir.write() {
+          *data_dir = Some(dir);
+        }
       }
       Ok(())
     }).manage(state)
-    .invoke_handler(tauri::generate_handler![generate, generate_stream])
-    .run(tauri::generate_context!())
-    .expect("error while running tauri application");
+    .invoke_handler(tauri::generate_handler![
+      generate,
+      generate_from_tokens,
+      generate_stream,
+      embed,
+      check_model,
+      cancel_load,
+      cancel_generation,
+      cancel_request,
+      reset_session,
+      set_log_level,
+      configure_rag,
+      set_data_dir,
+      retrieve_events,
+      tokenize,
+      count_tokens,
+      detokenize,
+      add_event,
+      model_info,
+      preview_template,
+      detect_conflicts,
+      benchmark,
+      validate_events,
+      rag_stats
+    ])
+    .build(tauri::generate_context!())
+    .expect("error while building tauri application")
+    .run(|app, event| {
+      if let tauri::RunEvent::Exit = event {
+        release_models(&app.state::<AppState>());
+      }
+    });
+}
+
+/// How long `release_models` waits for cancelled work to free its pool slot. A generation
+/// stops after its next token, but a load only between steps (see `llm::load_cancellable`).
+const UNLOAD_WAIT: Duration = Duration::from_secs(2);
+
+/// Free the models on exit rather than leaving it to process teardown, which on GPU
+/// backends can hold device memory until the process is gone. In-flight generations and
+/// loads are cancelled first so their slots come free; one still busy after `UNLOAD_WAIT`
+/// is left to teardown.
+fn release_models(state: &AppState) {
+    cancel_all_requests(state);
+    cancel_all_loads(state);
+    if let Ok(mut sessions) = state.sessions.lock() {
+        sessions.cached.clear();
+    }
+    let engines = state.llm.unload_all(UNLOAD_WAIT);
+    let embedder = state.embedder.lock().ok().and_then(|mut e| e.take()).is_some();
+    log::info!(
+        "Shutting down: released {} model(s){}",
+        engines,
+        if embedder { " and the embedder" } else { "" }
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip_for(template: &str, response: &str) -> String {
+        let markers = resolve_fake_turn_markers(Some(template), None).unwrap();
+        strip_fake_user_prompts(response, &markers)
+    }
+
+    #[test]
+    fn tinyllama_markers_truncate_fake_turns() {
+        assert_eq!(strip_for("tinyllama", "Sure.\n<|user|>\nMore?"), "Sure.");
+        assert_eq!(strip_for("tinyllama", "Sure.\n\nUser: thanks"), "Sure.");
+        assert_eq!(strip_for("tinyllama", "No markers here."), "No markers here.");
+    }
+
+    #[test]
+    fn llama2_markers_truncate_fake_turns() {
+        assert_eq!(strip_for("llama2", "Hi there.\n[INST] next [/INST]"), "Hi there.");
+        assert_eq!(strip_for("llama2", "Hi.\nUser: again"), "Hi.");
+    }
+
+    #[test]
+    fn chatml_markers_truncate_fake_turns() {
+        assert_eq!(
+            strip_for("chatml", "Done.<|im_end|>\n<|im_start|>user\nok"),
+            "Done.<|im_end|>"
+        );
+    }
+
+    #[test]
+    fn alpaca_markers_truncate_fake_turns() {
+        assert_eq!(strip_for("alpaca", "Answer.\n### Human: more"), "Answer.");
+        assert_eq!(strip_for("alpaca", "Answer.\n### Instruction: x"), "Answer.");
+    }
+
+    #[test]
+    fn marker_at_start_strips_everything() {
+        assert_eq!(strip_for("tinyllama", "User: what's on today?"), "");
+        assert_eq!(strip_for("tinyllama", "\n<|user|>\nhello"), "");
+        assert_eq!(strip_for("llama2", "[INST] hi [/INST]"), "");
+    }
+
+    #[test]
+    fn explicit_markers_override_template() {
+        let markers = resolve_fake_turn_markers(Some("llama2"), Some(vec!["Q:".into()])).unwrap();
+        assert_eq!(strip_fake_user_prompts("A.\n[INST] x\nQ: y", &markers), "A.\n[INST] x");
+    }
+
+    #[test]
+    fn unknown_template_is_an_error() {
+        assert!(resolve_fake_turn_markers(Some("nope"), None).is_err());
+    }
+
+    #[test]
+    fn markers_inside_code_fences_are_kept() {
+        let reply = "Here is the script:\n\n```text\nUser: alice\n<|user|>\n```\nDone.";
+        assert_eq!(strip_for("tinyllama", reply), reply);
+        let tilde = "Log:\n~~~\n```\nUser: bob\n~~~\nOk.";
+        assert_eq!(strip_for("tinyllama", tilde), tilde);
+    }
+
+    #[test]
+    fn markers_after_code_fences_still_truncate() {
+        let reply = "Example:\n```\nUser: alice\n```\nDone.\nUser: next question";
+        assert_eq!(strip_for("tinyllama", reply), "Example:\n```\nUser: alice\n```\nDone.");
+    }
+
+    #[test]
+    fn assistant_prefix_is_stripped() {
+        assert_eq!(strip_assistant_prefix("Assistant: Sure."), "Sure.");
+        assert_eq!(strip_assistant_prefix("  <|assistant|>\nHello."), "Hello.");
+        assert_eq!(strip_assistant_prefix("<|assistant|>\nassistant:  Hi."), "Hi.");
+        assert_eq!(strip_assistant_prefix("ASSISTANT:Done."), "Done.");
+    }
+
+    #[test]
+    fn streamed_assistant_prefix_is_stripped() {
+        let stream = |chunks: &[&str]| {
+            let mut lead = Some(String::new());
+            let mut sent: Vec<String> = chunks
+                .iter()
+                .filter_map(|chunk| past_assistant_prefix(&mut lead, chunk))
+                .map(Cow::into_owned)
+                .collect();
+            sent.extend(lead.map(|lead| strip_assistant_prefix(&lead).to_string()));
+            sent.concat()
+        };
+        assert_eq!(stream(&["<", "|assistant", "|>", "\n", "Sure", "."]), "Sure.");
+        assert_eq!(stream(&["  ", "Assist", "ant:", " Hi", " there"]), "Hi there");
+        assert_eq!(stream(&["Assistants", " are", " here."]), "Assistants are here.");
+        assert_eq!(stream(&["\n", "Your", " dentist"]), "Your dentist");
+        assert_eq!(stream(&["Ok", " Assistant: no"]), "Ok Assistant: no");
+        // A reply that ends while it could still be a prefix is sent as it is.
+        assert_eq!(stream(&[" assist"]), "assist");
+    }
+
+    #[test]
+    fn reply_without_prefix_is_kept() {
+        assert_eq!(strip_assistant_prefix("Sure, at 3pm."), "Sure, at 3pm.");
+        assert_eq!(strip_assistant_prefix("\n  Your dentist visit."), "Your dentist visit.");
+        assert_eq!(
+            strip_assistant_prefix("Ask the Assistant: it knows."),
+            "Ask the Assistant: it knows."
+        );
+        assert_eq!(strip_assistant_prefix("Assistants are here."), "Assistants are here.");
+        assert_eq!(strip_assistant_prefix(""), "");
+    }
+
+    #[test]
+    fn incomplete_sentence_is_trimmed() {
+        assert_eq!(trim_incomplete_sentence("It starts at 9. Bring the"), "It starts at 9.");
+        assert_eq!(trim_incomplete_sentence("Done! Then \"call\" Bob"), "Done!");
+        assert_eq!(trim_incomplete_sentence("She said \"hi.\" And"), "She said \"hi.\"");
+        assert_eq!(trim_incomplete_sentence("Lunch (noon.) Then"), "Lunch (noon.)");
+    }
+
+    #[test]
+    fn decimals_do_not_end_sentences() {
+        assert_eq!(trim_incomplete_sentence("The fee is 3.5 dollars"), "The fee is 3.5 dollars");
+        assert_eq!(trim_incomplete_sentence("Pay it. The fee is 3.5"), "Pay it.");
+        assert_eq!(trim_incomplete_sentence("The fee is 3.5.  "), "The fee is 3.5.");
+    }
+
+    #[test]
+    fn sentences_end_after_punctuation_and_whitespace() {
+        assert_eq!(sentence_end("The fee is 3.5 dollars"), None);
+        assert_eq!(sentence_end("It costs 3."), None);
+        assert_eq!(sentence_end("See Dr. Smith at noon"), None);
+        assert_eq!(sentence_end("Ask MR. Lee, e.g. today"), None);
+        assert_eq!(sentence_end("See Dr. Smith. Then"), Some("See Dr. Smith. ".len()));
+        assert_eq!(sentence_end("She said \"hi.\" And"), Some("She said \"hi.\" ".len()));
+        assert_eq!(sentence_end("Go! Now? Yes"), Some("Go! Now? ".len()));
+    }
+
+    #[test]
+    fn sentence_buffer_flushes_sentences_lines_and_the_rest() {
+        let mut buffer = SentenceBuffer::default();
+        assert_eq!(buffer.push("Dr. Smith is in"), None);
+        assert_eq!(buffer.push(" at 3.5 pm. Bring"), Some("Dr. Smith is in at 3.5 pm. ".into()));
+        assert_eq!(buffer.push(" forms:\n- ID"), Some("Bring forms:\n".into()));
+        assert_eq!(buffer.push("\n- Card"), Some("- ID\n".into()));
+        assert_eq!(buffer.finish(), Some("- Card".into()));
+        assert_eq!(buffer.finish(), None);
+    }
+
+    #[test]
+    fn text_without_a_sentence_end_is_kept() {
+        assert_eq!(trim_incomplete_sentence("no punctuation here"), "no punctuation here");
+        assert_eq!(trim_incomplete_sentence(""), "");
+    }
+
+    #[test]
+    fn only_load_failures_fall_back() {
+        let load = CommandError::from(llm::LlmError::Load("bad weights".into()));
+        assert!(load.is_load_failure());
+        let missing = CommandError::from(llm::LlmError::ModelFiles("no config.json".into()));
+        assert!(missing.is_load_failure());
+        assert!(!CommandError::from(llm::LlmError::Cancelled).is_load_failure());
+        let dtype = CommandError::from(llm::LlmError::InvalidInput("dtype".into()));
+        assert!(!dtype.is_load_failure());
+        assert!(!CommandError::from("poisoned lock".to_string()).is_load_failure());
+    }
+
+    #[test]
+    fn relevance_score_is_read_from_the_reply() {
+        assert_eq!(parse_relevance("7"), Some(7.0));
+        assert_eq!(parse_relevance(" 10/10"), Some(10.0));
+        assert_eq!(parse_relevance("\n3. It mentions the dentist."), Some(3.0));
+        assert_eq!(parse_relevance("0"), Some(0.0));
+        assert_eq!(parse_relevance("11"), None);
+        assert_eq!(parse_relevance("Seven"), None);
+        assert_eq!(parse_relevance(""), None);
+    }
+
+    /// `build_system_block` for `prompt` with default search settings and no persona.
+    fn system_block(
+        prompt: &str,
+        events_path: Option<&Path>,
+        strict: bool,
+    ) -> Result<Option<String>, NoSources> {
+        build_system_block(
+            prompt,
+            events_path,
+            None,
+            None,
+            &[],
+            None,
+            &rag::SearchOptions::default(),
+            rag_limits(None, None, None),
+            NoEventsMode::Instruct,
+            None,
+            strict,
+        )
+    }
+
+    #[test]
+    fn empty_layers_leave_no_blank_lines() {
+        let block = |persona: Option<&str>, instructions: &[String], date: Option<&str>| {
+            build_system_block(
+                "hello",
+                None,
+                date,
+                None,
+                &instruction_layers(persona, Some(instructions)),
+                None,
+                &rag::SearchOptions::default(),
+                rag_limits(None, None, None),
+                NoEventsMode::Instruct,
+                None,
+                false,
+            )
+            .unwrap()
+        };
+        let empty = [String::new(), "  ".to_string()];
+        assert_eq!(block(Some(""), &empty, None), None);
+        assert_eq!(
+            block(Some(""), &empty, Some("2026-02-16T14:30")).unwrap(),
+            format!("Today's date: 2026-02-16.\nCurrent time: 14:30.\n{}", OUTPUT_GUARD)
+        );
+        let instructions = [String::new(), "Be brief.".to_string()];
+        assert_eq!(
+            block(Some("You are a concierge."), &instructions, Some("2026-02-16")).unwrap(),
+            format!(
+                "You are a concierge.\nBe brief.\nToday's date: 2026-02-16.\n{}",
+                OUTPUT_GUARD
+            )
+        );
+    }
+
+    #[test]
+    fn strict_block_needs_matching_events() {
+        assert!(system_block("dentist", None, true).is_err());
+        let missing = std::env::temp_dir().join("no-such-events-file.json");
+        assert!(system_block("dentist", Some(&missing), true).is_err());
+
+        let path = std::env::temp_dir().join(format!("events-strict-{}.json", std::process::id()));
+        let json = r#"[{"title": "Dentist", "date": "2025-03-01", "description": "Checkup"}]"#;
+        std::fs::write(&path, json).unwrap();
+        let unmatched = system_block("volcano tour", Some(&path), true);
+        let matched = system_block("dentist", Some(&path), true);
+        let lenient = system_block("dentist", Some(&path), false);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(unmatched.is_err());
+        let block = matched.unwrap().unwrap();
+        assert!(block.contains("Dentist"), "{}", block);
+        assert!(block.contains(STRICT_RAG_INSTRUCTION.trim()), "{}", block);
+        assert!(!lenient.unwrap().unwrap().contains(STRICT_RAG_INSTRUCTION.trim()));
+    }
+
+    #[test]
+    fn strict_reply_keeps_the_requested_shape() {
+        let json = |reply: Reply| serde_json::to_value(reply).unwrap();
+        assert_eq!(json(strict_rag_reply(None, false, false)), STRICT_RAG_REPLY);
+        assert_eq!(
+            json(strict_rag_reply(Some(3), false, false)),
+            serde_json::json!([STRICT_RAG_REPLY, STRICT_RAG_REPLY, STRICT_RAG_REPLY])
+        );
+        assert_eq!(
+            json(strict_rag_reply(None, true, false)),
+            serde_json::json!({ "text": STRICT_RAG_REPLY, "logprobs": [] })
+        );
+        let detailed = serde_json::json!({ "text": STRICT_RAG_REPLY, "json": "invalid" });
+        assert_eq!(
+            json(strict_rag_reply(Some(2), false, true)),
+            serde_json::json!([detailed, detailed])
+        );
+    }
+
+    #[test]
+    fn sessions_keep_the_most_recently_used() {
+        let mut sessions = Vec::new();
+        for i in 0..MAX_SESSIONS {
+            put_session(&mut sessions, &i.to_string(), llm::Session::default());
+        }
+        let first = take_session(&mut sessions, "0").unwrap();
+        put_session(&mut sessions, "0", first);
+        put_session(&mut sessions, "new", llm::Session::default());
+        assert_eq!(sessions.len(), MAX_SESSIONS);
+        assert!(take_session(&mut sessions, "1").is_none(), "least recently used is dropped");
+        assert!(take_session(&mut sessions, "0").is_some());
+        assert!(take_session(&mut sessions, "new").is_some());
+    }
+
+    #[test]
+    fn reset_during_a_turn_drops_its_cache() {
+        let mut sessions = Sessions::default();
+        let (turn, session) = sessions.begin("a");
+        sessions.end(turn, "a", session);
+        assert_eq!(sessions.cached.len(), 1);
+
+        let (turn, session) = sessions.begin("a");
+        assert!(sessions.reset("a"));
+        sessions.end(turn, "a", session);
+        assert!(sessions.cached.is_empty());
+        assert!(!sessions.reset("a"));
+
+        // A turn begun after the reset keeps its cache.
+        let (early, early_session) = sessions.begin("a");
+        let (other, other_session) = sessions.begin("b");
+        assert!(sessions.reset("a"));
+        let (late, late_session) = sessions.begin("a");
+        sessions.end(early, "a", early_session);
+        sessions.end(other, "b", other_session);
+        assert!(sessions.cached.iter().all(|(id, _)| id == "b"));
+        sessions.end(late, "a", late_session);
+        assert_eq!(sessions.cached.len(), 2);
+        assert!(sessions.running.is_empty());
+    }
 }
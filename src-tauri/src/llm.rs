@@ -1,36 +1,901 @@
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use tokenizers::Tokenizer;
-use candle_core::{Device, DType, Tensor};
+use candle_core::{Device, DeviceLocation, DType, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::generation::{LogitsProcessor, Sampling};
 use candle_transformers::models::llama::{Llama, LlamaConfig, Cache, LlamaEosToks};
+use candle_transformers::models::{llama, mistral, qwen2};
 use candle_transformers::utils::apply_repeat_penalty;
+use crate::grammar::{Grammar, JsonState};
 
+/// What went wrong in the engine; `kind` names the variant for the frontend.
 #[derive(Debug)]
-pub struct LlmError(String);
+pub enum LlmError {
+    /// config.json, the tokenizer or the weights are missing or invalid.
+    ModelFiles(String),
+    /// The model is of an architecture this build can't run.
+    Unsupported(String),
+    /// Building the model from its weights failed.
+    Load(String),
+    /// An allocation failed while loading or running the model.
+    OutOfMemory(String),
+    /// The pre-load estimate (see `check_memory`) exceeds available memory, in bytes.
+    InsufficientMemory { required: u64, available: u64 },
+    /// `cancel_load` stopped the load.
+    Cancelled,
+    /// A request parameter was rejected: a dtype, stop token, token id, ...
+    InvalidInput(String),
+    /// Text couldn't be encoded, or token ids decoded.
+    Tokenizer(String),
+    /// A tensor operation failed while generating.
+    Inference(String),
+}
+
+impl LlmError {
+    /// `Load`, or `OutOfMemory` when `e` is an allocation failure.
+    pub(crate) fn load(context: &str, e: impl std::fmt::Display) -> Self {
+        let message = format!("{}: {}", context, e);
+        if is_out_of_memory(&message) {
+            LlmError::OutOfMemory(message)
+        } else {
+            LlmError::Load(message)
+        }
+    }
+
+    /// `Inference`, or `OutOfMemory` when `e` is an allocation failure.
+    pub(crate) fn inference(context: &str, e: impl std::fmt::Display) -> Self {
+        let message = format!("{}: {}", context, e);
+        if is_out_of_memory(&message) {
+            LlmError::OutOfMemory(message)
+        } else {
+            LlmError::Inference(message)
+        }
+    }
+
+    /// Whether another device might not hit this error: the device is unavailable, or the
+    /// model failed to build or fit on it.
+    fn is_device_specific(&self) -> bool {
+        matches!(
+            self,
+            LlmError::Unsupported(_)
+                | LlmError::Load(_)
+                | LlmError::OutOfMemory(_)
+                | LlmError::InsufficientMemory { .. }
+                | LlmError::Inference(_)
+        )
+    }
+
+    /// Snake-case variant name, e.g. "model_files" or "out_of_memory".
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LlmError::ModelFiles(_) => "model_files",
+            LlmError::Unsupported(_) => "unsupported",
+            LlmError::Load(_) => "load",
+            LlmError::OutOfMemory(_) => "out_of_memory",
+            LlmError::InsufficientMemory { .. } => "insufficient_memory",
+            LlmError::Cancelled => "cancelled",
+            LlmError::InvalidInput(_) => "invalid_input",
+            LlmError::Tokenizer(_) => "tokenizer",
+            LlmError::Inference(_) => "inference",
+        }
+    }
+}
+
+/// Allocation failures as reported by candle's backends and the OS.
+fn is_out_of_memory(message: &str) -> bool {
+    let message = message.to_lowercase();
+    ["out of memory", "cannot allocate", "memory allocation", "alloc error"]
+        .iter()
+        .any(|pattern| message.contains(pattern))
+}
 
 impl std::fmt::Display for LlmError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        match self {
+            LlmError::ModelFiles(message)
+            | LlmError::Unsupported(message)
+            | LlmError::Load(message)
+            | LlmError::OutOfMemory(message)
+            | LlmError::InvalidInput(message)
+            | LlmError::Tokenizer(message)
+            | LlmError::Inference(message) => write!(f, "{}", message),
+            LlmError::Cancelled => write!(f, "Model load cancelled"),
+            LlmError::InsufficientMemory {
+                required,
+                available,
+            } => write!(
+                f,
+                "Not enough memory to load the model: needs about {:.1} GiB, {:.1} GiB available",
+                *required as f64 / GIB,
+                *available as f64 / GIB
+            ),
+        }
     }
 }
 
 impl std::error::Error for LlmError {}
 
-const EOS_TOKEN: &str = "</s>";
+const GIB: f64 = (1u64 << 30) as f64;
+
+/// Text form of the EOS token, for remote backends that take the prompt as a string.
+pub const EOS_TOKEN: &str = "</s>";
+/// EOS spellings looked up in the vocabulary, in order, when config.json names no EOS id.
+const FALLBACK_EOS_TOKENS: &[&str] =
+    &[EOS_TOKEN, "<|im_end|>", "<|eot_id|>", "<|endoftext|>", "<|end|>"];
+const BOS_TOKEN: &str = "<s>";
 const DEFAULT_REPEAT_PENALTY: f32 = 1.1;
 const DEFAULT_REPEAT_LAST_N: usize = 64;
 
+/// A prompt segment. Special tokens are separate parts so they are inserted by id instead
+/// of relying on the tokenizer to recognise their text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PromptPart {
+    Text(String),
+    Eos,
+}
+
+/// Why the decode loop stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model emitted an EOS token.
+    Stop,
+    /// `max_tokens` was reached.
+    Length,
+    /// The streaming callback asked to stop.
+    Halted,
+    /// `GenerateOptions::timeout` ran out.
+    Timeout,
+}
+
+/// Periodic throughput stats while streaming; `elapsed_ms` includes prompt processing.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Progress {
+    pub tokens_generated: usize,
+    pub elapsed_ms: u64,
+    pub tokens_per_second: f64,
+}
+
+/// `generate_stream` reports `Progress` after every this many generated tokens.
+pub const PROGRESS_INTERVAL: usize = 8;
+
+/// Throughput measured by `LlmEngine::benchmark`.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Benchmark {
+    pub prompt_tokens: usize,
+    /// Fewer than requested when the model ended its reply early.
+    pub generated_tokens: usize,
+    /// Running the prompt and sampling the first token.
+    pub prompt_ms: u64,
+    /// Every token after the first.
+    pub generation_ms: u64,
+    pub prompt_tokens_per_second: f64,
+    pub tokens_per_second: f64,
+    /// Set when the benchmark also measured `GenerateOptions::cache_prefix`.
+    pub prefix_cache: Option<PrefixCacheBenchmark>,
+}
+
+/// What `GenerateOptions::cache_prefix` costs and saves for one prompt, measured by
+/// `LlmEngine::benchmark_prefix_cache`. Caching wins once the requests sharing the prefix
+/// save more (`uncached_ms - cached_ms` each) than storing it costs once
+/// (`storing_ms - uncached_ms`).
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct PrefixCacheBenchmark {
+    pub prefix_tokens: usize,
+    /// A request without prefix caching.
+    pub uncached_ms: u64,
+    /// The request that runs the prefix on its own and keeps its cache.
+    pub storing_ms: u64,
+    /// A request starting from the kept cache.
+    pub cached_ms: u64,
+    /// Whether the cached request generated the same tokens as the uncached one.
+    pub identical: bool,
+}
+
+/// The prompt `LlmEngine::benchmark` runs unless given another.
+pub const BENCHMARK_PROMPT: &str = "Write a detailed description of a busy city street on a \
+     rainy morning, covering the people, the traffic, the shops and the sounds.";
+
+/// Tokens `LlmEngine::benchmark` generates unless told otherwise.
+pub const BENCHMARK_TOKENS: usize = 128;
+
+/// Seed used when the caller doesn't pick one.
+pub const DEFAULT_SEED: u64 = 299792458;
+
+/// How a request's sampler seed is chosen (see `GenerateOptions::seed`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedMode {
+    /// A different seed each request, taken from the clock.
+    Random,
+    /// A hash of the whole prompt (history included), so asking the same thing again gives
+    /// the same reply even when sampling.
+    Prompt,
+    Fixed(u64),
+}
+
+impl SeedMode {
+    /// "random", "prompt", "fixed" (`DEFAULT_SEED`) or a number to use as the seed.
+    pub fn parse(name: &str) -> Result<Self, LlmError> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "random" => Ok(SeedMode::Random),
+            "prompt" => Ok(SeedMode::Prompt),
+            "fixed" => Ok(SeedMode::Fixed(DEFAULT_SEED)),
+            other => other.parse().map(SeedMode::Fixed).map_err(|_| {
+                LlmError::InvalidInput(format!(
+                    "Unknown seed mode: {} (expected random, prompt, fixed or a number)",
+                    name
+                ))
+            }),
+        }
+    }
+
+    /// The seed for a request with this prompt.
+    pub fn seed(self, prompt: &[PromptPart]) -> u64 {
+        let seed = match self {
+            SeedMode::Fixed(seed) => return seed,
+            SeedMode::Random => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(DEFAULT_SEED, |d| d.as_nanos() as u64),
+            SeedMode::Prompt => prompt_hash(prompt),
+        };
+        log::info!("Using {:?} seed {}", self, seed);
+        seed
+    }
+}
+
+/// FNV-1a. Unlike `DefaultHasher` it is the same across Rust versions and runs, so a
+/// prompt keeps its seed after an update.
+fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    bytes
+        .into_iter()
+        .fold(OFFSET, |hash, byte| (hash ^ byte as u64).wrapping_mul(PRIME))
+}
+
+fn prompt_hash(prompt: &[PromptPart]) -> u64 {
+    fnv1a(prompt.iter().flat_map(|part| match part {
+        PromptPart::Text(text) => text.bytes(),
+        PromptPart::Eos => EOS_TOKEN.bytes(),
+    }))
+}
+
+fn token_hash(tokens: &[u32]) -> u64 {
+    fnv1a(tokens.iter().flat_map(|id| id.to_le_bytes()))
+}
+
+/// Per-request decoding settings.
+#[derive(Debug, Clone)]
+pub struct GenerateOptions {
+    pub max_tokens: usize,
+    /// Mask the EOS and stop tokens until this many tokens have been generated, so a model
+    /// that stops after a terse reply keeps going. `max_tokens` still ends generation first
+    /// if it is lower. A grammar whose document is complete can still end the reply.
+    pub min_tokens: usize,
+    /// 0 or below means greedy (argmax) decoding, which always picks the most likely token:
+    /// `top_k`, `top_p` and `seed` are then ignored (with a warning for `top_k`/`top_p`).
+    pub temperature: f64,
+    /// Sample only from the `top_k` most likely tokens.
+    pub top_k: Option<usize>,
+    /// Sample only from the smallest set of tokens whose probabilities add up to `top_p`.
+    pub top_p: Option<f64>,
+    /// Seeds the sampler, so the same prompt, options and seed give the same tokens.
+    /// Greedy decoding (`Sampling::ArgMax`, temperature 0 or below) ignores it: it is
+    /// deterministic regardless of the seed.
+    pub seed: u64,
+    /// Mask logits so the output follows this grammar (see the `grammar` module).
+    pub grammar: Option<Grammar>,
+    /// Tokens (e.g. "<|im_end|>") that end generation like EOS, in addition to the model's
+    /// own EOS ids. Each must be a single token in the vocabulary.
+    pub stop_tokens: Vec<String>,
+    /// End the reply (`FinishReason::Stop`) once its text holds this many newlines, e.g. 1
+    /// for a single line or paragraph. Newlines before the first visible text don't count.
+    pub max_lines: Option<usize>,
+    /// Apply the repeat penalty to generated tokens only, so words from the prompt (an event
+    /// name the user asked about) can be repeated freely. By default the penalty window
+    /// also covers the end of the prompt.
+    pub repeat_penalty_completion_only: bool,
+    /// Stop after this long, prompt processing included, keeping what was generated so far.
+    /// Checked after each token, so a slow step can overrun it.
+    pub timeout: Option<std::time::Duration>,
+    /// Start the prompt with the model's BOS token. Off for raw prompts, which are used as
+    /// given and spell out any special tokens themselves.
+    pub add_special_tokens: bool,
+    /// Record each sampled token's log-probability in `Generation::logprobs`.
+    pub logprobs: bool,
+    /// Keep the prompt's shared prefix (see `LlmEngine::encode`) for the next request, and
+    /// start from it when it is already kept. Off by default: the prefix then runs as a
+    /// separate forward pass, which rounds differently, so a reply isn't bit-identical to
+    /// an uncached one (a greedy reply can change on a near-tie). Whether it pays off for a
+    /// model and prefix is measured by `LlmEngine::benchmark_prefix_cache`.
+    pub cache_prefix: bool,
+}
+
+impl GenerateOptions {
+    fn sampling(&self) -> Sampling {
+        let temperature = self.temperature;
+        if temperature <= 0.0 {
+            if self.top_k.is_some() || self.top_p.is_some() {
+                log::warn!(
+                    "temperature {} means greedy decoding; ignoring top_k {:?} and top_p {:?}",
+                    temperature,
+                    self.top_k,
+                    self.top_p
+                );
+            }
+            return Sampling::ArgMax;
+        }
+        match (self.top_k, self.top_p) {
+            (None, None) => Sampling::All { temperature },
+            (Some(k), None) => Sampling::TopK { k, temperature },
+            (None, Some(p)) => Sampling::TopP { p, temperature },
+            (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        }
+    }
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        Self {
+            max_tokens: 128,
+            min_tokens: 0,
+            temperature: 0.0,
+            top_k: None,
+            top_p: None,
+            seed: DEFAULT_SEED,
+            grammar: None,
+            stop_tokens: Vec::new(),
+            max_lines: None,
+            repeat_penalty_completion_only: false,
+            timeout: None,
+            add_special_tokens: true,
+            logprobs: false,
+            cache_prefix: false,
+        }
+    }
+}
+
+/// Token ids for `parts`: with `add_special_tokens`, exactly one `bos` first (a text that
+/// starts with the BOS token itself doesn't add a second), then each text part encoded
+/// without special tokens and each `Eos` as `eos`. Without it the first part starts the
+/// prompt, as raw prompts need.
+///
+/// Also returns the length of the prefix that ends with the first `Eos`, such as the
+/// system turn, for `decode_loop` to cache; 0 when no `Eos` comes before the last part.
+fn encode_parts(
+    tokenizer: &Tokenizer,
+    bos: Option<u32>,
+    eos: Option<u32>,
+    parts: &[PromptPart],
+    add_special_tokens: bool,
+) -> Result<(Vec<u32>, usize), LlmError> {
+    let bos = bos.filter(|_| add_special_tokens);
+    let mut ids: Vec<u32> = bos.into_iter().collect();
+    let mut prefix_len = 0;
+    for (i, part) in parts.iter().enumerate() {
+        match part {
+            PromptPart::Text(text) => {
+                let encoding = tokenizer
+                    .encode(text.as_str(), false)
+                    .map_err(|e| LlmError::Tokenizer(format!("Encode error: {}", e)))?;
+                let mut text_ids = encoding.get_ids();
+                if bos.is_some() && ids.len() == 1 && text_ids.first() == bos.as_ref() {
+                    text_ids = &text_ids[1..];
+                }
+                ids.extend_from_slice(text_ids);
+            }
+            PromptPart::Eos => {
+                let eos = eos.ok_or_else(|| {
+                    let message = "Model has no EOS token in config.json or tokenizer";
+                    LlmError::ModelFiles(message.into())
+                })?;
+                ids.push(eos);
+                if prefix_len == 0 && i + 1 < parts.len() {
+                    prefix_len = ids.len();
+                }
+            }
+        }
+    }
+    Ok((ids, prefix_len))
+}
+
+/// Text a vocab piece contributes to the output, for grammar checks. Handles SentencePiece
+/// ("▁" space, "<0x0A>" byte fallback) and byte-level BPE ("Ġ" space, "Ċ" newline) pieces.
+/// Control tokens such as "<s>" give None. Non-ASCII byte pieces become U+FFFD, which the
+/// grammar only accepts inside strings.
+fn piece_text(piece: &str) -> Option<String> {
+    if let Some(hex) = piece.strip_prefix("<0x").and_then(|p| p.strip_suffix('>')) {
+        let byte = u8::from_str_radix(hex, 16).ok()?;
+        return Some(if byte.is_ascii() {
+            (byte as char).to_string()
+        } else {
+            '\u{FFFD}'.to_string()
+        });
+    }
+    if piece.len() > 2 && piece.starts_with('<') && piece.ends_with('>') && !piece.contains(' ') {
+        return None;
+    }
+    Some(
+        piece
+            .chars()
+            .map(|c| match c {
+                '▁' | 'Ġ' => ' ',
+                'Ċ' => '\n',
+                'ĉ' => '\t',
+                c => c,
+            })
+            .collect(),
+    )
+}
+
+/// Incremental detokenizer for streaming. Byte-fallback tokens can split a multi-byte
+/// character (an emoji is four `<0x..>` tokens), so text is only released once it decodes
+/// without a trailing replacement character.
+///
+/// Decoding happens over a window starting at the last emitted token rather than per token,
+/// so decoders that strip a leading space (SentencePiece) don't drop spaces between chunks.
+pub struct TokenOutputStream<'a> {
+    tokenizer: &'a Tokenizer,
+    tokens: Vec<u32>,
+    prev_index: usize,
+    current_index: usize,
+}
+
+impl<'a> TokenOutputStream<'a> {
+    pub fn new(tokenizer: &'a Tokenizer) -> Self {
+        Self {
+            tokenizer,
+            tokens: Vec::new(),
+            prev_index: 0,
+            current_index: 0,
+        }
+    }
+
+    fn decode(&self, ids: &[u32]) -> Result<String, LlmError> {
+        self.tokenizer
+            .decode(ids, true)
+            .map_err(|e| LlmError::Tokenizer(format!("Decode error: {}", e)))
+    }
+
+    /// Add a generated token; returns the newly completed text, if any.
+    pub fn next_token(&mut self, id: u32) -> Result<Option<String>, LlmError> {
+        let prev_text = self.decode(&self.tokens[self.prev_index..self.current_index])?;
+        self.tokens.push(id);
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        if text.len() <= prev_text.len() || text.ends_with(char::REPLACEMENT_CHARACTER) {
+            return Ok(None);
+        }
+        match text.get(prev_text.len()..) {
+            Some(chunk) => {
+                let chunk = chunk.to_string();
+                self.prev_index = self.current_index;
+                self.current_index = self.tokens.len();
+                Ok(Some(chunk))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Whatever is still buffered (e.g. an incomplete character at the end of generation).
+    pub fn finish(&mut self) -> Result<Option<String>, LlmError> {
+        let prev_text = self.decode(&self.tokens[self.prev_index..self.current_index])?;
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        self.prev_index = self.tokens.len();
+        self.current_index = self.tokens.len();
+        Ok(text
+            .get(prev_text.len()..)
+            .filter(|rest| !rest.is_empty())
+            .map(String::from))
+    }
+}
+
+/// KV cache kept between turns of a conversation so a follow-up only processes the tokens
+/// it adds. Reused only when the new prompt extends exactly the tokens already cached, on
+/// an engine with the same model and dtype; anything else starts a fresh cache.
+#[derive(Default)]
+pub struct Session {
+    cached: Option<SessionCache>,
+}
+
+/// KV cache holding just a prompt's shared prefix (see `LlmEngine::encode`), so the next
+/// prompt starting with the same tokens skips them.
+struct PrefixCache {
+    /// `token_hash` of the prefix.
+    key: u64,
+    len: usize,
+    /// None while the prefix has been seen only once (see `LlmEngine::decode_loop`).
+    cache: Option<DecodeState>,
+}
+
+struct SessionCache {
+    cache: DecodeState,
+    /// Tokens already run through the model (and so held in `cache`).
+    tokens: Vec<u32>,
+    model_dir: std::path::PathBuf,
+    dtype: DType,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Generation {
+    pub text: String,
+    /// The generated ids `text` was decoded from, including any final EOS.
+    pub token_ids: Vec<u32>,
+    pub finish_reason: FinishReason,
+    /// One entry per id in `token_ids`, with `GenerateOptions::logprobs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Vec<TokenLogprob>>,
+}
+
+/// A generated token with its natural-log probability, taken from the softmax of the logits
+/// the sampler saw: after the repeat penalty and any grammar mask, before temperature,
+/// top-k and top-p.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TokenLogprob {
+    pub token: u32,
+    /// The token on its own, decoded with special tokens kept; part of a multi-token
+    /// character decodes to U+FFFD.
+    pub text: String,
+    pub logprob: f32,
+}
+
+/// Newlines in a reply decoded chunk by chunk, for `GenerateOptions::max_lines`. Whitespace
+/// before the first visible text (models often open with a blank line) isn't counted.
+#[derive(Default)]
+struct LineCounter {
+    started: bool,
+    lines: usize,
+}
+
+impl LineCounter {
+    fn push(&mut self, chunk: &str) {
+        let chunk = if self.started { chunk } else { chunk.trim_start() };
+        self.started |= !chunk.is_empty();
+        self.lines += chunk.matches('\n').count();
+    }
+}
+
+/// Log-softmax of `logits` at `index`.
+fn logprob(logits: &Tensor, index: usize) -> Result<f32, LlmError> {
+    let values: Vec<f32> = logits
+        .to_vec1()
+        .map_err(|e| LlmError::inference("Reading logits failed", e))?;
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = values.iter().map(|v| (v - max).exp()).sum();
+    Ok(values[index] - max - sum.ln())
+}
+
+/// `logits` with the `ids` set to -inf, so they can't be sampled.
+fn suppress(logits: &Tensor, ids: &[u32]) -> Result<Tensor, LlmError> {
+    let mut values: Vec<f32> = logits
+        .to_vec1()
+        .map_err(|e| LlmError::inference("Reading logits failed", e))?;
+    for &id in ids {
+        if let Some(value) = values.get_mut(id as usize) {
+            *value = f32::NEG_INFINITY;
+        }
+    }
+    Tensor::new(values, logits.device())
+        .map_err(|e| LlmError::inference("Tensor creation failed", e))
+}
+
+/// One token of `LlmEngine::tokenize`: its id and the vocabulary piece it stands for.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TokenPiece {
+    pub id: u32,
+    pub piece: String,
+}
+
+/// Model families `load` can build, chosen from config.json's `architecture_name`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    Llama,
+    Mistral,
+    Qwen2,
+}
+
+impl Architecture {
+    /// Configs naming neither field are assumed to be Llama, as before other architectures
+    /// were supported.
+    fn detect(config: &serde_json::Value) -> Result<Self, LlmError> {
+        let Some(name) = architecture_name(config) else {
+            return Ok(Architecture::Llama);
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "llama" | "llamaforcausallm" => Ok(Architecture::Llama),
+            "mistral" | "mistralforcausallm" => Ok(Architecture::Mistral),
+            "qwen2" | "qwen2forcausallm" => Ok(Architecture::Qwen2),
+            _ => Err(LlmError::Unsupported(format!(
+                "Unsupported model architecture: {} (expected llama, mistral or qwen2)",
+                name
+            ))),
+        }
+    }
+}
+
+/// The config.json fields every supported architecture shares.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ModelConfig {
+    pub vocab_size: usize,
+    #[serde(default = "default_max_position_embeddings")]
+    pub max_position_embeddings: usize,
+    pub num_hidden_layers: usize,
+    pub hidden_size: usize,
+    pub num_attention_heads: usize,
+    #[serde(default)]
+    pub num_key_value_heads: Option<usize>,
+    pub intermediate_size: usize,
+    /// The LM head shares the token embedding matrix instead of having its own weights.
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
+    #[serde(default)]
+    pub bos_token_id: Option<u32>,
+    #[serde(default)]
+    pub eos_token_id: Option<LlamaEosToks>,
+}
+
+fn default_max_position_embeddings() -> usize {
+    4096
+}
+
+/// config.json parsed for its architecture.
+enum ArchConfig {
+    Llama(llama::Config),
+    Mistral(mistral::Config),
+    Qwen2(qwen2::Config),
+}
+
+impl ArchConfig {
+    fn build(&self, vb: VarBuilder) -> candle_core::Result<Model> {
+        match self {
+            ArchConfig::Llama(config) => Ok(Model::Llama {
+                model: Llama::load(vb, config)?,
+                config: config.clone(),
+            }),
+            ArchConfig::Mistral(config) => Ok(Model::Mistral(mistral::Model::new(config, vb)?)),
+            ArchConfig::Qwen2(config) => {
+                Ok(Model::Qwen2(qwen2::ModelForCausalLM::new(config, vb)?))
+            }
+        }
+    }
+}
+
+/// The loaded weights. Llama keeps its KV cache outside the model; Mistral and Qwen2 keep it
+/// inside, so each generation decodes with its own copy (the weight tensors are shared).
+pub enum Model {
+    Llama { model: Llama, config: llama::Config },
+    Mistral(mistral::Model),
+    Qwen2(qwen2::ModelForCausalLM),
+}
+
+/// KV cache of one generation, created by `Model::start`.
+#[derive(Clone)]
+enum DecodeState {
+    Llama(Cache),
+    Mistral(Box<mistral::Model>),
+    Qwen2(Box<qwen2::ModelForCausalLM>),
+}
+
+impl Model {
+    fn start(&self, dtype: DType, device: &Device) -> candle_core::Result<DecodeState> {
+        Ok(match self {
+            Model::Llama { config, .. } => {
+                DecodeState::Llama(Cache::new(true, dtype, config, device)?)
+            }
+            Model::Mistral(model) => {
+                let mut model = model.clone();
+                model.clear_kv_cache();
+                DecodeState::Mistral(Box::new(model))
+            }
+            Model::Qwen2(model) => {
+                let mut model = model.clone();
+                model.clear_kv_cache();
+                DecodeState::Qwen2(Box::new(model))
+            }
+        })
+    }
+
+    /// Logits (F32, `[batch, vocab]`) for the token after `input`, whose first token is at
+    /// position `index_pos`.
+    fn forward(
+        &self,
+        state: &mut DecodeState,
+        input: &Tensor,
+        index_pos: usize,
+    ) -> candle_core::Result<Tensor> {
+        match (self, state) {
+            (Model::Llama { model, .. }, DecodeState::Llama(cache)) => {
+                // candle's Llama masks several new tokens as if the cache were empty, so
+                // tokens continuing a filled cache go in one at a time.
+                let seq_len = input.dim(1)?;
+                if index_pos == 0 || seq_len == 1 {
+                    return model.forward(input, index_pos, cache);
+                }
+                let mut logits = None;
+                for i in 0..seq_len {
+                    let token = input.narrow(1, i, 1)?;
+                    logits = Some(model.forward(&token, index_pos + i, cache)?);
+                }
+                logits.ok_or_else(|| candle_core::Error::Msg("Empty input".into()))
+            }
+            (Model::Mistral(_), DecodeState::Mistral(model)) => {
+                model.forward(input, index_pos)?.squeeze(1)?.to_dtype(DType::F32)
+            }
+            (Model::Qwen2(_), DecodeState::Qwen2(model)) => {
+                model.forward(input, index_pos)?.squeeze(1)?.to_dtype(DType::F32)
+            }
+            _ => Err(candle_core::Error::Msg("KV cache is for another architecture".into())),
+        }
+    }
+}
+
 pub struct LlmEngine {
-    pub model: Llama,
-    pub tokenizer: Tokenizer,
+    pub model_dir: std::path::PathBuf,
+    pub model: Model,
+    pub tokenizer: Arc<Tokenizer>,
     pub device: Device,
-    pub config: candle_transformers::models::llama::Config,
+    pub dtype: DType,
+    pub config: ModelConfig,
+    /// `architectures[0]` (or `model_type`) from config.json.
+    pub architecture: Option<String>,
+    /// The most recent prompt prefix, usually the system block with its events.
+    prefix_cache: Mutex<Option<PrefixCache>>,
+}
+
+/// Metadata about a loaded model, for the UI to confirm what was loaded.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ModelInfo {
+    pub architecture: Option<String>,
+    pub vocab_size: usize,
+    pub context_length: usize,
+    pub num_layers: usize,
+    pub hidden_size: usize,
+    pub num_attention_heads: usize,
+}
+
+impl ModelInfo {
+    fn new(config: &ModelConfig, architecture: Option<String>) -> Self {
+        Self {
+            architecture,
+            vocab_size: config.vocab_size,
+            context_length: config.max_position_embeddings,
+            num_layers: config.num_hidden_layers,
+            hidden_size: config.hidden_size,
+            num_attention_heads: config.num_attention_heads,
+        }
+    }
+}
+
+/// Parse a dtype name as accepted by the generate commands: "f16", "bf16" or "f32".
+pub fn parse_dtype(name: &str) -> Result<DType, LlmError> {
+    match name.trim().to_lowercase().as_str() {
+        "f16" => Ok(DType::F16),
+        "bf16" => Ok(DType::BF16),
+        "f32" => Ok(DType::F32),
+        other => Err(LlmError::InvalidInput(format!(
+            "Unsupported dtype: {} (expected f16, bf16 or f32)",
+            other
+        ))),
+    }
+}
+
+/// Open a device by name: "cpu", "cuda" or "cuda:N", "metal" or "metal:N" (N is the GPU
+/// index, 0 by default). CUDA and Metal only open in builds with candle's matching feature,
+/// on a machine that has the device; otherwise the error is `Unsupported`.
+pub fn parse_device(name: &str) -> Result<Device, LlmError> {
+    let name = name.trim().to_lowercase();
+    let (kind, ordinal) = match name.split_once(':') {
+        Some((kind, ordinal)) => {
+            let ordinal = ordinal.parse().map_err(|_| {
+                LlmError::InvalidInput(format!("Invalid device index: {}", name))
+            })?;
+            (kind, ordinal)
+        }
+        None => (name.as_str(), 0),
+    };
+    let device = match kind {
+        "cpu" => return Ok(Device::Cpu),
+        "cuda" => Device::new_cuda(ordinal),
+        "metal" => Device::new_metal(ordinal),
+        _ => {
+            return Err(LlmError::InvalidInput(format!(
+                "Unknown device: {} (expected cpu, cuda or metal)",
+                name
+            )))
+        }
+    };
+    device.map_err(|e| LlmError::Unsupported(format!("Device {} is unavailable: {}", name, e)))
+}
+
+/// `device` as "cpu", "cuda:N" or "metal:N".
+pub fn device_name(device: &Device) -> String {
+    match device.location() {
+        DeviceLocation::Cpu => "cpu".to_string(),
+        DeviceLocation::Cuda { gpu_id } => format!("cuda:{}", gpu_id),
+        DeviceLocation::Metal { gpu_id } => format!("metal:{}", gpu_id),
+    }
+}
+
+/// Whether `name` (as for `parse_device`) refers to `device`.
+pub fn device_matches(name: &str, device: &Device) -> bool {
+    canonical_device_name(name) == device_name(device)
+}
+
+/// `name` in `device_name`'s form: lowercase, with GPU index 0 when none is given.
+fn canonical_device_name(name: &str) -> String {
+    let name = name.trim().to_lowercase();
+    if name == "cpu" || name.contains(':') {
+        name
+    } else {
+        format!("{}:0", name)
+    }
+}
+
+/// candle's CPU kernels are fastest in F32; half precision only pays off on a GPU.
+fn default_dtype(device: &Device) -> DType {
+    if device.is_cpu() {
+        DType::F32
+    } else {
+        DType::F16
+    }
+}
+
+const SAFETENSORS_INDEX: &str = "model.safetensors.index.json";
+
+#[derive(serde::Deserialize)]
+struct SafetensorsIndex {
+    weight_map: std::collections::HashMap<String, String>,
+}
+
+/// Shard number from names like "model-00002-of-00003.safetensors".
+fn shard_number(file_name: &str) -> Option<u32> {
+    let stem = file_name.strip_suffix(".safetensors")?;
+    let (head, _) = stem.rsplit_once("-of-")?;
+    let (_, number) = head.rsplit_once('-')?;
+    number.parse().ok()
+}
+
+/// Shards referenced by model.safetensors.index.json, deduplicated and in shard order.
+fn indexed_safetensors_paths(
+    model_dir: &Path,
+    index_path: &Path,
+) -> Result<Vec<std::path::PathBuf>, LlmError> {
+    let index_bytes = std::fs::read(index_path)
+        .map_err(|e| LlmError::ModelFiles(format!("Failed to read {}: {}", SAFETENSORS_INDEX, e)))?;
+    let index: SafetensorsIndex = serde_json::from_slice(&index_bytes)
+        .map_err(|e| LlmError::ModelFiles(format!("Invalid {}: {}", SAFETENSORS_INDEX, e)))?;
+
+    let mut shards: Vec<String> = index.weight_map.into_values().collect();
+    shards.sort_by(|a, b| shard_number(a).cmp(&shard_number(b)).then_with(|| a.cmp(b)));
+    shards.dedup();
+
+    shards
+        .into_iter()
+        .map(|shard| {
+            let path = model_dir.join(&shard);
+            if path.is_file() {
+                Ok(path)
+            } else {
+                Err(LlmError::ModelFiles(format!(
+                    "Shard {} referenced by {} not found in model dir",
+                    shard, SAFETENSORS_INDEX
+                )))
+            }
+        })
+        .collect()
 }
 
-fn safetensors_paths(model_dir: &Path) -> Result<Vec<std::path::PathBuf>, LlmError> {
+/// Weight files to load: the shards listed in the index when present, otherwise every
+/// .safetensors file in the model dir sorted by name.
+pub(crate) fn safetensors_paths(model_dir: &Path) -> Result<Vec<std::path::PathBuf>, LlmError> {
+    let index_path = model_dir.join(SAFETENSORS_INDEX);
+    if index_path.exists() {
+        return indexed_safetensors_paths(model_dir, &index_path);
+    }
+
     let mut paths: Vec<_> = std::fs::read_dir(model_dir)
-        .map_err(|e| LlmError(format!("Failed to read model dir: {}", e)))?
+        .map_err(|e| LlmError::ModelFiles(format!("Failed to read model dir: {}", e)))?
         .filter_map(|e| e.ok())
         .map(|e| e.path())
         .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("safetensors"))
@@ -39,227 +904,1166 @@ fn safetensors_paths(model_dir: &Path) -> Result<Vec<std::path::PathBuf>, LlmErr
     Ok(paths)
 }
 
-fn load_config(model_dir: &Path) -> Result<candle_transformers::models::llama::Config, LlmError> {
+const LM_HEAD_WEIGHT: &str = "lm_head.weight";
+const EMBED_TOKENS_WEIGHT: &str = "model.embed_tokens.weight";
+
+/// Serve `lm_head.weight` from the token embeddings when the checkpoint has no LM head of
+/// its own. Tied checkpoints often leave it out, but candle's Llama only ties when
+/// config.json says so and Mistral always loads the tensor. Fails naming the missing tensor
+/// when there are no embeddings to tie to either.
+fn tie_embeddings<'a>(
+    vb: VarBuilder<'a>,
+    config: &ModelConfig,
+) -> Result<VarBuilder<'a>, LlmError> {
+    if vb.contains_tensor(LM_HEAD_WEIGHT) {
+        return Ok(vb);
+    }
+    if !vb.contains_tensor(EMBED_TOKENS_WEIGHT) {
+        return Err(LlmError::ModelFiles(format!(
+            "Missing tensor {} (and no {} to tie it to)",
+            LM_HEAD_WEIGHT, EMBED_TOKENS_WEIGHT
+        )));
+    }
+    if !config.tie_word_embeddings {
+        log::warn!(
+            "No {} in the weights although tie_word_embeddings is off; using {}",
+            LM_HEAD_WEIGHT,
+            EMBED_TOKENS_WEIGHT
+        );
+    }
+    Ok(vb.rename_f(|name| match name {
+        LM_HEAD_WEIGHT => EMBED_TOKENS_WEIGHT.to_string(),
+        _ => name.to_string(),
+    }))
+}
+
+/// The parsed config.json, with its `architecture_name` for `ModelInfo`.
+fn load_config(
+    model_dir: &Path,
+) -> Result<(ArchConfig, ModelConfig, Option<String>), LlmError> {
     let config_path = model_dir.join("config.json");
     let config_bytes = std::fs::read(&config_path)
-        .map_err(|e| LlmError(format!("Failed to read config.json: {}", e)))?;
-    let llama_config: LlamaConfig = serde_json::from_slice(&config_bytes)
-        .map_err(|e| LlmError(format!("Invalid config.json: {}", e)))?;
-    let config = llama_config.into_config(false);
-    Ok(config)
+        .map_err(|e| LlmError::ModelFiles(format!("Failed to read config.json: {}", e)))?;
+    let invalid =
+        |e: serde_json::Error| LlmError::ModelFiles(format!("Invalid config.json: {}", e));
+    let value: serde_json::Value = serde_json::from_slice(&config_bytes).map_err(invalid)?;
+    let common: ModelConfig = serde_json::from_value(value.clone()).map_err(invalid)?;
+    let architecture = architecture_name(&value).map(String::from);
+    let config = match Architecture::detect(&value)? {
+        Architecture::Llama => {
+            let config: LlamaConfig = serde_json::from_value(value).map_err(invalid)?;
+            ArchConfig::Llama(config.into_config(false))
+        }
+        Architecture::Mistral => {
+            ArchConfig::Mistral(serde_json::from_value(value).map_err(invalid)?)
+        }
+        Architecture::Qwen2 => ArchConfig::Qwen2(serde_json::from_value(value).map_err(invalid)?),
+    };
+    Ok((config, common, architecture))
+}
+
+/// Load `tokenizer.json`, falling back to a SentencePiece `tokenizer.model`.
+fn load_tokenizer(model_dir: &Path) -> Result<Tokenizer, LlmError> {
+    let json_path = model_dir.join("tokenizer.json");
+    if json_path.is_file() {
+        return Tokenizer::from_file(json_path)
+            .map_err(|e| LlmError::ModelFiles(format!("Failed to load tokenizer: {}", e)));
+    }
+    let sp_path = model_dir.join("tokenizer.model");
+    if sp_path.is_file() {
+        log::info!("No tokenizer.json; building tokenizer from {}", sp_path.display());
+        return crate::sentencepiece::load_tokenizer(&sp_path);
+    }
+    Err(LlmError::ModelFiles(
+        "Neither tokenizer.json nor tokenizer.model found in model dir".into(),
+    ))
 }
 
-pub fn load(model_dir: &Path) -> Result<LlmEngine, LlmError> {
-    let device = Device::Cpu;
-    let dtype = DType::F16;
+/// How many tokenizers, and tokenizer files, a `TokenizerCache` keeps.
+const TOKENIZER_CACHE_SIZE: usize = 4;
+
+/// Tokenizers by a hash of the file they were loaded from (see `load_tokenizer`), so models
+/// sharing a tokenizer load it once and tokenizing doesn't need a loaded model. A file is
+/// only hashed when its path is new or its modification time or length changed, so a hit
+/// costs a `stat`; an edited file hashes differently and is loaded afresh. Keeps the
+/// `TOKENIZER_CACHE_SIZE` most recently used.
+#[derive(Default)]
+pub struct TokenizerCache {
+    inner: Mutex<TokenizerEntries>,
+}
+
+/// Both lists least recently used first.
+#[derive(Default)]
+struct TokenizerEntries {
+    files: Vec<TokenizerFile>,
+    tokenizers: Vec<(u64, Arc<Tokenizer>)>,
+}
 
-    let config = load_config(model_dir)?;
-    let tokenizer_path = model_dir.join("tokenizer.json");
-    let tokenizer = Tokenizer::from_file(tokenizer_path)
-        .map_err(|e| LlmError(format!("Failed to load tokenizer: {}", e)))?;
+/// A tokenizer file as last seen, and the `fnv1a` of its contents then.
+struct TokenizerFile {
+    path: std::path::PathBuf,
+    modified: Option<std::time::SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+/// Move the entry at `i` to the end (most recently used) and return it.
+fn touch<T>(entries: &mut Vec<T>, i: usize) -> &T {
+    let entry = entries.remove(i);
+    entries.push(entry);
+    &entries[entries.len() - 1]
+}
+
+/// Append `entry`, dropping the least recently used when full.
+fn insert_bounded<T>(entries: &mut Vec<T>, entry: T) {
+    if entries.len() >= TOKENIZER_CACHE_SIZE {
+        entries.remove(0);
+    }
+    entries.push(entry);
+}
+
+impl TokenizerCache {
+    /// The tokenizer of the model in `model_dir`, loading it unless one from an identical
+    /// file is cached.
+    pub fn get(&self, model_dir: &Path) -> Result<Arc<Tokenizer>, LlmError> {
+        let path = tokenizer_file(model_dir)?;
+        let read_error = |e: std::io::Error| {
+            LlmError::ModelFiles(format!("Failed to read {}: {}", path.display(), e))
+        };
+        let metadata = std::fs::metadata(&path).map_err(read_error)?;
+        let (modified, len) = (metadata.modified().ok(), metadata.len());
+
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let known = inner.files.iter().position(|f| {
+            f.path == path && f.modified.is_some() && f.modified == modified && f.len == len
+        });
+        let hash = match known {
+            Some(i) => touch(&mut inner.files, i).hash,
+            None => {
+                let hash = fnv1a(std::fs::read(&path).map_err(read_error)?);
+                inner.files.retain(|f| f.path != path);
+                let file = TokenizerFile {
+                    path: path.clone(),
+                    modified,
+                    len,
+                    hash,
+                };
+                insert_bounded(&mut inner.files, file);
+                hash
+            }
+        };
+        if let Some(i) = inner.tokenizers.iter().position(|(h, _)| *h == hash) {
+            return Ok(touch(&mut inner.tokenizers, i).1.clone());
+        }
+        drop(inner);
+        let tokenizer = Arc::new(load_tokenizer(model_dir)?);
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if !inner.tokenizers.iter().any(|(h, _)| *h == hash) {
+            insert_bounded(&mut inner.tokenizers, (hash, tokenizer.clone()));
+        }
+        Ok(tokenizer)
+    }
+}
+
+/// The file `load_tokenizer` would read from `model_dir`.
+fn tokenizer_file(model_dir: &Path) -> Result<std::path::PathBuf, LlmError> {
+    ["tokenizer.json", "tokenizer.model"]
+        .iter()
+        .map(|name| model_dir.join(name))
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            LlmError::ModelFiles(
+                "Neither tokenizer.json nor tokenizer.model found in model dir".into(),
+            )
+        })
+}
+
+/// How `tokenizer` splits `text`, without BOS or other special tokens.
+pub fn tokenize(tokenizer: &Tokenizer, text: &str) -> Result<Vec<TokenPiece>, LlmError> {
+    let encoding = tokenizer
+        .encode(text, false)
+        .map_err(|e| LlmError::Tokenizer(format!("Encode error: {}", e)))?;
+    Ok(encoding
+        .get_ids()
+        .iter()
+        .zip(encoding.get_tokens())
+        .map(|(&id, piece)| TokenPiece {
+            id,
+            piece: piece.clone(),
+        })
+        .collect())
+}
+
+/// Text for `ids` with `tokenizer`, keeping special tokens (see `LlmEngine::detokenize`).
+pub fn detokenize(tokenizer: &Tokenizer, ids: &[u32]) -> Result<String, LlmError> {
+    let vocab_size = tokenizer.get_vocab_size(true);
+    if let Some(&id) = ids.iter().find(|&&id| id as usize >= vocab_size) {
+        return Err(LlmError::InvalidInput(format!(
+            "Token id {} is outside the vocabulary (size {})",
+            id, vocab_size
+        )));
+    }
+    tokenizer
+        .decode(ids, false)
+        .map_err(|e| LlmError::Tokenizer(format!("Decode error: {}", e)))
+}
+
+/// What `check_model` found in a model directory.
+#[derive(Debug, Default, serde::Serialize)]
+pub struct ModelCheck {
+    /// True when config, tokenizer and weights all look usable.
+    pub ok: bool,
+    pub config_found: bool,
+    pub config_error: Option<String>,
+    pub tokenizer_found: bool,
+    pub tokenizer_error: Option<String>,
+    pub weight_files: Vec<String>,
+    pub weights_error: Option<String>,
+    /// `architectures[0]` (or `model_type`) from config.json, e.g. "LlamaForCausalLM".
+    pub architecture: Option<String>,
+}
+
+/// config.json's `architectures[0]` (e.g. "LlamaForCausalLM"), or its `model_type` when
+/// that is missing.
+fn architecture_name(config: &serde_json::Value) -> Option<&str> {
+    config
+        .get("architectures")
+        .and_then(|a| a.get(0))
+        .or_else(|| config.get("model_type"))
+        .and_then(|a| a.as_str())
+}
+
+fn detect_architecture(config_bytes: &[u8]) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(config_bytes).ok()?;
+    architecture_name(&value).map(String::from)
+}
+
+/// `ModelInfo` from config.json alone, so the UI can size inputs before the model is loaded.
+pub fn model_info(model_dir: &Path) -> Result<ModelInfo, LlmError> {
+    let (_, config, architecture) = load_config(model_dir)?;
+    Ok(ModelInfo::new(&config, architecture))
+}
+
+/// Validate a model directory without loading the weights: config.json and tokenizer.json
+/// (or tokenizer.model) must parse and at least one weights file must be present (and every shard the index
+/// references, if there is one).
+pub fn check_model(model_dir: &Path) -> ModelCheck {
+    let mut check = ModelCheck::default();
+
+    let config_path = model_dir.join("config.json");
+    check.config_found = config_path.is_file();
+    if check.config_found {
+        match std::fs::read(&config_path) {
+            Ok(bytes) => check.architecture = detect_architecture(&bytes),
+            Err(e) => check.config_error = Some(format!("Failed to read config.json: {}", e)),
+        }
+        if check.config_error.is_none() {
+            check.config_error = load_config(model_dir).err().map(|e| e.to_string());
+        }
+    } else {
+        check.config_error = Some("config.json not found".into());
+    }
+
+    check.tokenizer_found =
+        model_dir.join("tokenizer.json").is_file() || model_dir.join("tokenizer.model").is_file();
+    check.tokenizer_error = load_tokenizer(model_dir).err().map(|e| e.to_string());
+
+    match safetensors_paths(model_dir) {
+        Ok(paths) if paths.is_empty() => {
+            check.weights_error = Some("No .safetensors files found in model dir".into());
+        }
+        Ok(paths) => {
+            check.weight_files = paths
+                .iter()
+                .filter_map(|p| p.file_name().and_then(|n| n.to_str()).map(String::from))
+                .collect();
+        }
+        Err(e) => check.weights_error = Some(e.to_string()),
+    }
+
+    check.ok = check.config_error.is_none()
+        && check.tokenizer_error.is_none()
+        && check.weights_error.is_none();
+    check
+}
+
+pub fn load(model_dir: &Path, dtype: Option<&str>) -> Result<LlmEngine, LlmError> {
+    let tokenizers = TokenizerCache::default();
+    load_cancellable(model_dir, dtype, Device::Cpu, &tokenizers, &AtomicBool::new(false))
+}
+
+/// `load_cancellable` on the first device in `preference` (names as for `parse_device`)
+/// that opens and holds the model: one that can't be opened, or fails to load or runs out
+/// of memory, is skipped with a warning. An empty list means the CPU. Errors no other
+/// device would fix (model files, input, cancellation) end the search.
+pub fn load_preferring(
+    model_dir: &Path,
+    dtype: Option<&str>,
+    preference: &[String],
+    tokenizers: &TokenizerCache,
+    cancel: &AtomicBool,
+) -> Result<LlmEngine, LlmError> {
+    if preference.is_empty() {
+        return load_cancellable(model_dir, dtype, Device::Cpu, tokenizers, cancel);
+    }
+    let mut last_error = None;
+    for name in preference {
+        let result = parse_device(name)
+            .and_then(|device| load_cancellable(model_dir, dtype, device, tokenizers, cancel));
+        match result {
+            Ok(engine) => return Ok(engine),
+            Err(e) if e.is_device_specific() => {
+                log::warn!("Device {} skipped: {}", name, e);
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_error.unwrap_or(LlmError::Unsupported("No device to load the model on".into())))
+}
+
+/// Rough size of the model's weights in `dtype`, counted from config.json: embeddings and
+/// LM head (once when they're tied), then per layer the attention projections (with grouped
+/// KV heads), the MLP and the norms.
+fn estimated_weight_bytes(config: &ModelConfig, dtype: DType) -> u64 {
+    let hidden = config.hidden_size as u64;
+    let heads = config.num_attention_heads.max(1) as u64;
+    let kv_heads = config.num_key_value_heads.map_or(heads, |n| n as u64);
+    let kv_dim = hidden / heads * kv_heads;
+    let attention = 2 * hidden * hidden + 2 * hidden * kv_dim;
+    let mlp = 3 * hidden * config.intermediate_size as u64;
+    let layer = attention + mlp + 2 * hidden;
+    let embeddings = if config.tie_word_embeddings { 1 } else { 2 };
+    let params =
+        config.num_hidden_layers as u64 * layer + embeddings * config.vocab_size as u64 * hidden;
+    params * dtype.size_in_bytes() as u64
+}
+
+/// Fail with `InsufficientMemory` when the weights plus a margin for the KV cache and
+/// activations won't fit in available RAM, so a load that would abort the process is
+/// refused up front. Only CPU loads are checked; without a memory reading the load goes
+/// ahead.
+fn check_memory(config: &ModelConfig, dtype: DType, device: &Device) -> Result<(), LlmError> {
+    if !device.is_cpu() {
+        return Ok(());
+    }
+    let weights = estimated_weight_bytes(config, dtype);
+    let required = weights + weights / 10;
+    let mut system = sysinfo::System::new();
+    system.refresh_memory();
+    let available = system.available_memory();
+    log::info!(
+        "Estimated {:.1} GiB for the model, {:.1} GiB available",
+        required as f64 / GIB,
+        available as f64 / GIB
+    );
+    if available > 0 && required > available {
+        return Err(LlmError::InsufficientMemory {
+            required,
+            available,
+        });
+    }
+    Ok(())
+}
+
+fn check_cancelled(cancel: &AtomicBool) -> Result<(), LlmError> {
+    if cancel.load(Ordering::Relaxed) {
+        return Err(LlmError::Cancelled);
+    }
+    Ok(())
+}
+
+/// `load`, giving up with "Model load cancelled" once `cancel` is set. The flag is checked
+/// between stages: after reading config.json, after the tokenizer, after finding the weight
+/// files and after memory-mapping them. Building the model (`ArchConfig::build`, which
+/// converts every tensor to `dtype` and is most of the load time) runs inside candle and
+/// can't be interrupted; a cancel during it takes effect when it finishes, discarding the
+/// result. The tokenizer comes from `tokenizers` when an identical one is cached.
+pub fn load_cancellable(
+    model_dir: &Path,
+    dtype: Option<&str>,
+    device: Device,
+    tokenizers: &TokenizerCache,
+    cancel: &AtomicBool,
+) -> Result<LlmEngine, LlmError> {
+    let dtype = match dtype {
+        Some(name) => parse_dtype(name)?,
+        None => default_dtype(&device),
+    };
+    log::info!("Using dtype {:?} on {:?}", dtype, device);
+
+    let (arch_config, config, architecture) = load_config(model_dir)?;
+    check_memory(&config, dtype, &device)?;
+    check_cancelled(cancel)?;
+    let tokenizer = tokenizers.get(model_dir)?;
+    check_cancelled(cancel)?;
 
     let paths = safetensors_paths(model_dir)?;
     if paths.is_empty() {
-        return Err(LlmError("No .safetensors files found in model dir".into()));
+        return Err(LlmError::ModelFiles("No .safetensors files found in model dir".into()));
     }
+    check_cancelled(cancel)?;
 
     let vb = unsafe { VarBuilder::from_mmaped_safetensors(&paths, dtype, &device) }
-        .map_err(|e| LlmError(format!("Failed to load weights: {}", e)))?;
+        .map_err(|e| LlmError::load("Failed to load weights", e))?;
+    let vb = tie_embeddings(vb, &config)?;
+    check_cancelled(cancel)?;
 
-    let model = Llama::load(vb, &config)
-        .map_err(|e| LlmError(format!("Failed to load model: {}", e)))?;
+    let model = arch_config
+        .build(vb)
+        .map_err(|e| LlmError::load("Failed to load model", e))?;
+    check_cancelled(cancel)?;
 
     Ok(LlmEngine {
+        model_dir: model_dir.to_path_buf(),
         model,
         tokenizer,
         device,
+        dtype,
         config,
+        architecture,
+        prefix_cache: Mutex::new(None),
     })
 }
 
 impl LlmEngine {
-    pub fn generate(
+    pub fn info(&self) -> ModelInfo {
+        ModelInfo::new(&self.config, self.architecture.clone())
+    }
+
+    fn bos_token_id(&self) -> Option<u32> {
+        self.config
+            .bos_token_id
+            .or_else(|| self.tokenizer.token_to_id(BOS_TOKEN))
+    }
+
+    /// `encode_parts` with this model's tokenizer, BOS and EOS.
+    fn encode(
         &self,
-        prompt: &str,
-        max_tokens: usize,
-        temperature: f64,
-        seed: u64,
-    ) -> Result<String, LlmError> {
-        let mut tokens = self
-            .tokenizer
-            .encode(prompt, true)
-            .map_err(|e| LlmError(format!("Encode error: {}", e)))?
-            .get_ids()
-            .to_vec();
+        parts: &[PromptPart],
+        add_special_tokens: bool,
+    ) -> Result<(Vec<u32>, usize), LlmError> {
+        let eos = self.eos_token_ids().first().copied();
+        encode_parts(&self.tokenizer, self.bos_token_id(), eos, parts, add_special_tokens)
+    }
 
-        let prompt_len = tokens.len();
+    /// How the tokenizer splits `text`, without BOS or other special tokens.
+    pub fn tokenize(&self, text: &str) -> Result<Vec<TokenPiece>, LlmError> {
+        tokenize(&self.tokenizer, text)
+    }
 
-        let dtype = DType::F16;
-        let mut cache = Cache::new(true, dtype, &self.config, &self.device)
-            .map_err(|e| LlmError(format!("Cache creation failed: {}", e)))?;
+    /// Text for `ids`, keeping special tokens so they show up when inspecting a prompt.
+    pub fn detokenize(&self, ids: &[u32]) -> Result<String, LlmError> {
+        if let Some(&id) = ids.iter().find(|&&id| id as usize >= self.config.vocab_size) {
+            return Err(LlmError::InvalidInput(format!(
+                "Token id {} is outside the vocabulary (size {})",
+                id, self.config.vocab_size
+            )));
+        }
+        self.tokenizer
+            .decode(ids, false)
+            .map_err(|e| LlmError::Tokenizer(format!("Decode error: {}", e)))
+    }
 
-        let sampling = if temperature <= 0.0 {
-            Sampling::ArgMax
-        } else {
-            Sampling::All { temperature }
-        };
-        let mut logits_processor = LogitsProcessor::from_sampling(seed, sampling);
+    fn decode(&self, ids: &[u32]) -> Result<String, LlmError> {
+        self.tokenizer
+            .decode(ids, true)
+            .map_err(|e| LlmError::Tokenizer(format!("Decode error: {}", e)))
+    }
 
-        let eos_token_id = self.config.eos_token_id.clone().or_else(|| {
-            self.tokenizer
-                .token_to_id(EOS_TOKEN)
-                .map(LlamaEosToks::Single)
-        });
+    /// EOS ids from config.json, else every `FALLBACK_EOS_TOKENS` entry in the vocabulary.
+    fn eos_token_ids(&self) -> Vec<u32> {
+        match self.config.eos_token_id.clone() {
+            Some(LlamaEosToks::Single(id)) => vec![id],
+            Some(LlamaEosToks::Multiple(ids)) => ids,
+            None => FALLBACK_EOS_TOKENS
+                .iter()
+                .filter_map(|token| self.tokenizer.token_to_id(token))
+                .collect(),
+        }
+    }
+
+    /// Ids that end generation: the model's EOS ids plus `options.stop_tokens`.
+    fn stop_token_ids(&self, options: &GenerateOptions) -> Result<Vec<u32>, LlmError> {
+        let mut ids = self.eos_token_ids();
+        for token in &options.stop_tokens {
+            let id = self.tokenizer.token_to_id(token).ok_or_else(|| {
+                LlmError::InvalidInput(format!("Stop token {:?} is not in the vocabulary", token))
+            })?;
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
 
-        let mut index_pos = 0usize;
+    /// `piece_text` for every id in a `vocab_size`-long logits vector.
+    fn token_texts(&self, vocab_size: usize) -> Vec<Option<String>> {
+        (0..vocab_size as u32)
+            .map(|id| self.tokenizer.id_to_token(id).and_then(|p| piece_text(&p)))
+            .collect()
+    }
 
-        for _ in 0..max_tokens {
-            let (context_size, context_index) = if cache.use_kv_cache && tokens.len() > prompt_len {
-                (1, index_pos)
+    /// Set the logit of every token the grammar can't accept next to -inf. EOS is only
+    /// allowed once the document is complete, and then nothing else is.
+    fn mask_logits(
+        &self,
+        logits: &Tensor,
+        state: &JsonState,
+        texts: &[Option<String>],
+        eos_ids: &[u32],
+    ) -> Result<Tensor, LlmError> {
+        let mut values = logits
+            .to_vec1::<f32>()
+            .map_err(|e| LlmError::inference("Logits read failed", e))?;
+        let mut any_allowed = false;
+        for (id, value) in values.iter_mut().enumerate() {
+            let allowed = if eos_ids.contains(&(id as u32)) {
+                state.is_complete()
+            } else if state.is_complete() {
+                false
             } else {
-                (tokens.len(), 0)
+                texts
+                    .get(id)
+                    .and_then(|t| t.as_deref())
+                    .is_some_and(|t| !t.is_empty() && state.after(t).is_some())
             };
+            if allowed {
+                any_allowed = true;
+            } else {
+                *value = f32::NEG_INFINITY;
+            }
+        }
+        if !any_allowed {
+            return Err(LlmError::Inference("Grammar allows no next token".into()));
+        }
+        Tensor::new(values, &self.device)
+            .map_err(|e| LlmError::inference("Tensor creation failed", e))
+    }
+
+    /// Shared decode loop: runs up to `max_tokens` steps after `tokens` (the encoded prompt),
+    /// calling `on_step` with all generated ids so far after each sampled token; it returns
+    /// false to stop early (`FinishReason::Halted`).
+    /// Returns the generated ids (including any EOS), why generation stopped, and with
+    /// `options.logprobs` each generated id's log-probability (see `TokenLogprob`).
+    ///
+    /// With a `session`, its cache is reused when `tokens` extends what it holds, and the
+    /// cache is stored back into it afterwards.
+    ///
+    /// The first `prefix_len` tokens can be run on their own and their cache kept on the
+    /// engine, so a later prompt with the same prefix (and no usable session) starts from a
+    /// copy of it. That is only done once the previous request had the same prefix: on
+    /// Llama the tokens after a filled cache are fed one at a time, so splitting off a
+    /// prefix that is never reused (the system block changes with the retrieved events)
+    /// would make the rest of the prompt slower. Results with and without a cached prefix
+    /// can differ in rounding, so this only happens with `options.cache_prefix`.
+    fn decode_loop<F>(
+        &self,
+        mut tokens: Vec<u32>,
+        prefix_len: usize,
+        options: &GenerateOptions,
+        mut session: Option<&mut Session>,
+        mut on_step: F,
+    ) -> Result<(Vec<u32>, FinishReason, Vec<f32>), LlmError>
+    where
+        F: FnMut(&[u32]) -> Result<bool, LlmError>,
+    {
+        let prompt_len = tokens.len();
+        let start = std::time::Instant::now();
 
-            let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
-            let input = Tensor::new(ctxt, &self.device)
-                .map_err(|e| LlmError(format!("Tensor creation failed: {}", e)))?
-                .unsqueeze(0)
-                .map_err(|e| LlmError(format!("Unsqueeze failed: {}", e)))?;
+        let reusable = session.as_mut().and_then(|s| s.cached.take()).filter(|c| {
+            c.model_dir == self.model_dir
+                && c.dtype == self.dtype
+                && c.tokens.len() < prompt_len
+                && tokens.starts_with(&c.tokens)
+        });
+        let (mut cache, mut index_pos) = match reusable {
+            Some(cached) => {
+                log::info!("Reusing {} cached session tokens", cached.tokens.len());
+                (cached.cache, cached.tokens.len())
+            }
+            None => match options
+                .cache_prefix
+                .then(|| self.cached_prefix(&tokens[..prefix_len]))
+                .flatten()
+            {
+                Some(cache) => {
+                    log::info!("Reusing {} cached prefix tokens", prefix_len);
+                    (cache, prefix_len)
+                }
+                None => {
+                    let cache = self
+                        .model
+                        .start(self.dtype, &self.device)
+                        .map_err(|e| LlmError::inference("Cache creation failed", e))?;
+                    (cache, 0)
+                }
+            },
+        };
+        let split = options.cache_prefix && index_pos < prefix_len && prefix_len < prompt_len;
+        if split && self.prefix_seen(&tokens[..prefix_len]) {
+            self.forward(&mut cache, &tokens[index_pos..prefix_len], index_pos)?;
+            index_pos = prefix_len;
+            self.store_prefix(&tokens[..prefix_len], Some(&cache));
+        } else if split {
+            self.store_prefix(&tokens[..prefix_len], None);
+        }
 
-            let logits = self
-                .model
-                .forward(&input, context_index, &mut cache)
-                .map_err(|e| LlmError(format!("Forward failed: {}", e)))?
-                .squeeze(0)
-                .map_err(|e| LlmError(format!("Squeeze failed: {}", e)))?;
+        let mut logits_processor = LogitsProcessor::from_sampling(options.seed, options.sampling());
+
+        let eos_ids = self.stop_token_ids(options)?;
+        let mut grammar_state = options.grammar.map(Grammar::start);
+        let token_texts = match grammar_state {
+            Some(_) => self.token_texts(self.config.vocab_size),
+            None => Vec::new(),
+        };
+
+        let mut finish_reason = FinishReason::Length;
+        let mut logprobs = Vec::new();
+        // Decodes the reply alongside generation to count its lines for `max_lines`.
+        let mut line_stream = options.max_lines.map(|_| TokenOutputStream::new(&self.tokenizer));
+        let mut lines = LineCounter::default();
+
+        for _ in 0..options.max_tokens {
+            // Everything not yet in the cache: the (rest of the) prompt first, then one token.
+            let ctxt = &tokens[index_pos..];
+            let logits = self.forward(&mut cache, ctxt, index_pos)?;
 
             let logits = if (DEFAULT_REPEAT_PENALTY - 1.0).abs() < 1e-6 {
                 logits
             } else {
-                let start_at = tokens.len().saturating_sub(DEFAULT_REPEAT_LAST_N);
+                let mut start_at = tokens.len().saturating_sub(DEFAULT_REPEAT_LAST_N);
+                if options.repeat_penalty_completion_only {
+                    start_at = start_at.max(prompt_len);
+                }
                 apply_repeat_penalty(&logits, DEFAULT_REPEAT_PENALTY, &tokens[start_at..])
-                    .map_err(|e| LlmError(format!("Repeat penalty failed: {}", e)))?
+                    .map_err(|e| LlmError::inference("Repeat penalty failed", e))?
+            };
+
+            let logits = match &grammar_state {
+                Some(state) => self.mask_logits(&logits, state, &token_texts, &eos_ids)?,
+                None => logits,
+            };
+
+            let generated = tokens.len() - prompt_len;
+            let grammar_done = grammar_state.as_ref().is_some_and(JsonState::is_complete);
+            let logits = if generated < options.min_tokens && !grammar_done {
+                suppress(&logits, &eos_ids)?
+            } else {
+                logits
             };
 
             let next_token = logits_processor
                 .sample(&logits)
-                .map_err(|e| LlmError(format!("Sample failed: {}", e)))?;
+                .map_err(|e| LlmError::inference("Sample failed", e))?;
+            if options.logprobs {
+                logprobs.push(logprob(&logits, next_token as usize)?);
+            }
 
             index_pos += ctxt.len();
             tokens.push(next_token);
 
-            match &eos_token_id {
-                Some(LlamaEosToks::Single(id)) if next_token == *id => break,
-                Some(LlamaEosToks::Multiple(ids)) if ids.contains(&next_token) => break,
-                _ => {}
+            let keep_going = on_step(&tokens[prompt_len..])?;
+
+            if eos_ids.contains(&next_token) {
+                finish_reason = FinishReason::Stop;
+                break;
+            }
+            if let (Some(stream), Some(max_lines)) = (line_stream.as_mut(), options.max_lines) {
+                if let Some(chunk) = stream.next_token(next_token)? {
+                    lines.push(&chunk);
+                }
+                if lines.lines >= max_lines {
+                    finish_reason = FinishReason::Stop;
+                    break;
+                }
+            }
+            if !keep_going {
+                finish_reason = FinishReason::Halted;
+                break;
+            }
+            if options.timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                log::warn!("Generation timed out after {} tokens", tokens.len() - prompt_len);
+                finish_reason = FinishReason::Timeout;
+                break;
+            }
+            if let Some(state) = grammar_state.as_mut() {
+                let text = token_texts[next_token as usize].as_deref().unwrap_or_default();
+                *state = state
+                    .after(text)
+                    .ok_or_else(|| {
+                        LlmError::Inference("Sampled a token outside the grammar".into())
+                    })?;
             }
         }
 
-        let generated_ids: Vec<u32> = tokens[prompt_len..].to_vec();
-        let text = self
-            .tokenizer
-            .decode(&generated_ids, true)
-            .map_err(|e| LlmError(format!("Decode error: {}", e)))?;
+        if let Some(session) = session {
+            session.cached = Some(SessionCache {
+                cache,
+                tokens: tokens[..index_pos].to_vec(),
+                model_dir: self.model_dir.clone(),
+                dtype: self.dtype,
+            });
+        }
+        Ok((tokens.split_off(prompt_len), finish_reason, logprobs))
+    }
 
-        Ok(text)
+    /// Logits for the token after `ctxt`, which continues the `index_pos` tokens already
+    /// in `cache`.
+    fn forward(
+        &self,
+        cache: &mut DecodeState,
+        ctxt: &[u32],
+        index_pos: usize,
+    ) -> Result<Tensor, LlmError> {
+        let input = Tensor::new(ctxt, &self.device)
+            .map_err(|e| LlmError::inference("Tensor creation failed", e))?
+            .unsqueeze(0)
+            .map_err(|e| LlmError::inference("Unsqueeze failed", e))?;
+        self.model
+            .forward(cache, &input, index_pos)
+            .map_err(|e| LlmError::inference("Forward failed", e))?
+            .squeeze(0)
+            .map_err(|e| LlmError::inference("Squeeze failed", e))
     }
 
-    pub fn generate_stream<E>(
+    /// A copy of the prefix cache if it holds exactly `prefix`.
+    fn cached_prefix(&self, prefix: &[u32]) -> Option<DecodeState> {
+        if prefix.is_empty() {
+            return None;
+        }
+        let cached = self.prefix_cache.lock().ok()?;
+        cached
+            .as_ref()
+            .filter(|c| c.len == prefix.len() && c.key == token_hash(prefix))
+            .and_then(|c| c.cache.clone())
+    }
+
+    /// Whether the last prefix stored, cached or not, is `prefix`.
+    fn prefix_seen(&self, prefix: &[u32]) -> bool {
+        let Ok(cached) = self.prefix_cache.lock() else {
+            return false;
+        };
+        cached
+            .as_ref()
+            .is_some_and(|c| c.len == prefix.len() && c.key == token_hash(prefix))
+    }
+
+    /// Replace the prefix cache with `prefix`, and its cache once it has been run.
+    fn store_prefix(&self, prefix: &[u32], cache: Option<&DecodeState>) {
+        if let Ok(mut cached) = self.prefix_cache.lock() {
+            *cached = Some(PrefixCache {
+                key: token_hash(prefix),
+                len: prefix.len(),
+                cache: cache.cloned(),
+            });
+        }
+    }
+
+    /// Generated token ids for `prompt`, without decoding them to text. The sampler is
+    /// seeded with `options.seed` each call, so repeated calls with the same inputs return
+    /// identical sequences.
+    pub fn generate_tokens(
         &self,
-        prompt: &str,
-        max_tokens: usize,
-        temperature: f64,
-        seed: u64,
+        prompt: &[PromptPart],
+        options: &GenerateOptions,
+    ) -> Result<(Vec<u32>, FinishReason), LlmError> {
+        let (tokens, prefix_len) = self.encode(prompt, options.add_special_tokens)?;
+        let (ids, finish_reason, _) =
+            self.decode_loop(tokens, prefix_len, options, None, |_| Ok(true))?;
+        Ok((ids, finish_reason))
+    }
+
+    pub fn generate(
+        &self,
+        prompt: &[PromptPart],
+        options: &GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        let (tokens, prefix_len) = self.encode(prompt, options.add_special_tokens)?;
+        self.generate_ids(tokens, prefix_len, options)
+    }
+
+    /// `generate` for a prompt the caller already encoded; the ids are used exactly as
+    /// given, so include the BOS token if the model expects one.
+    pub fn generate_from_tokens(
+        &self,
+        tokens: Vec<u32>,
+        options: &GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        if tokens.is_empty() {
+            return Err(LlmError::InvalidInput("No prompt tokens given".into()));
+        }
+        if let Some(&id) = tokens.iter().find(|&&id| id as usize >= self.config.vocab_size) {
+            return Err(LlmError::InvalidInput(format!(
+                "Token id {} is outside the vocabulary (size {})",
+                id, self.config.vocab_size
+            )));
+        }
+        self.generate_ids(tokens, 0, options)
+    }
+
+    fn generate_ids(
+        &self,
+        tokens: Vec<u32>,
+        prefix_len: usize,
+        options: &GenerateOptions,
+    ) -> Result<Generation, LlmError> {
+        let (token_ids, finish_reason, logprobs) =
+            self.decode_loop(tokens, prefix_len, options, None, |_| Ok(true))?;
+        let logprobs = if options.logprobs {
+            let logprobs = token_ids
+                .iter()
+                .zip(logprobs)
+                .map(|(&token, logprob)| {
+                    Ok(TokenLogprob {
+                        token,
+                        text: self.detokenize(&[token])?,
+                        logprob,
+                    })
+                })
+                .collect::<Result<_, LlmError>>()?;
+            Some(logprobs)
+        } else {
+            None
+        };
+        Ok(Generation {
+            text: self.decode(&token_ids)?,
+            token_ids,
+            finish_reason,
+            logprobs,
+        })
+    }
+
+    /// Stream the reply through `emit`, called with each text chunk and the id of the token
+    /// that completed it (a multi-token character is emitted once, with its last token).
+    /// `emit` returns false to stop generating; the reply so far stands and the finish
+    /// reason is `FinishReason::Halted`.
+    /// Pass a `session` to reuse its KV cache across turns.
+    pub fn generate_stream<E, P>(
+        &self,
+        prompt: &[PromptPart],
+        options: &GenerateOptions,
+        session: Option<&mut Session>,
         mut emit: E,
-    ) -> Result<(), LlmError>
+        mut progress: P,
+    ) -> Result<FinishReason, LlmError>
     where
-        E: FnMut(&str),
+        E: FnMut(u32, &str) -> bool,
+        P: FnMut(Progress),
     {
-        let mut tokens = self
-            .tokenizer
-            .encode(prompt, true)
-            .map_err(|e| LlmError(format!("Encode error: {}", e)))?
-            .get_ids()
-            .to_vec();
+        let (tokens, prefix_len) = self.encode(prompt, options.add_special_tokens)?;
+        let mut stream = TokenOutputStream::new(&self.tokenizer);
+        let start = std::time::Instant::now();
 
-        let prompt_len = tokens.len();
+        let (generated_ids, finish_reason, _) =
+            self.decode_loop(tokens, prefix_len, options, session, |generated_ids| {
+                let mut keep_going = true;
+                if let Some(&id) = generated_ids.last() {
+                    if let Some(chunk) = stream.next_token(id)? {
+                        keep_going = emit(id, &chunk);
+                    }
+                }
+                let tokens_generated = generated_ids.len();
+                if tokens_generated % PROGRESS_INTERVAL == 0 {
+                    let elapsed = start.elapsed();
+                    progress(Progress {
+                        tokens_generated,
+                        elapsed_ms: elapsed.as_millis() as u64,
+                        tokens_per_second: tokens_generated as f64
+                            / elapsed.as_secs_f64().max(1e-9),
+                    });
+                }
+                Ok(keep_going)
+            })?;
+        if let (Some(rest), Some(&id)) = (stream.finish()?, generated_ids.last()) {
+            emit(id, &rest);
+        }
 
-        let dtype = DType::F16;
-        let mut cache = Cache::new(true, dtype, &self.config, &self.device)
-            .map_err(|e| LlmError(format!("Cache creation failed: {}", e)))?;
+        Ok(finish_reason)
+    }
 
-        let sampling = if temperature <= 0.0 {
-            Sampling::ArgMax
-        } else {
-            Sampling::All { temperature }
+    /// Time a greedy generation of up to `max_tokens` tokens for `prompt`, used as given
+    /// (no chat template). The prefix and session caches are bypassed so every run does the
+    /// same work. Setting `cancel` stops generating early; the figures then cover the tokens
+    /// generated so far.
+    pub fn benchmark(
+        &self,
+        prompt: &str,
+        max_tokens: usize,
+        cancel: &AtomicBool,
+    ) -> Result<Benchmark, LlmError> {
+        let parts = [PromptPart::Text(prompt.to_string())];
+        let (tokens, _) = self.encode(&parts, true)?;
+        let prompt_tokens = tokens.len();
+        let options = GenerateOptions {
+            max_tokens: max_tokens.max(1),
+            ..Default::default()
         };
-        let mut logits_processor = LogitsProcessor::from_sampling(seed, sampling);
+        let start = std::time::Instant::now();
+        let mut prompt_elapsed = None;
+        let (generated, _, _) = self.decode_loop(tokens, 0, &options, None, |_| {
+            prompt_elapsed.get_or_insert_with(|| start.elapsed());
+            Ok(!cancel.load(Ordering::Relaxed))
+        })?;
+        let elapsed = start.elapsed();
+        let prompt_elapsed = prompt_elapsed.unwrap_or(elapsed);
+        let generation_elapsed = elapsed - prompt_elapsed;
+        let per_second = |n: usize, d: std::time::Duration| n as f64 / d.as_secs_f64().max(1e-9);
+        Ok(Benchmark {
+            prompt_tokens,
+            generated_tokens: generated.len(),
+            prompt_ms: prompt_elapsed.as_millis() as u64,
+            generation_ms: generation_elapsed.as_millis() as u64,
+            prompt_tokens_per_second: per_second(prompt_tokens, prompt_elapsed),
+            tokens_per_second: per_second(generated.len().saturating_sub(1), generation_elapsed),
+            prefix_cache: None,
+        })
+    }
 
-        let eos_token_id = self.config.eos_token_id.clone().or_else(|| {
-            self.tokenizer
-                .token_to_id(EOS_TOKEN)
-                .map(LlamaEosToks::Single)
-        });
+    /// Time a greedy generation of up to `max_tokens` tokens for `prompt` after a shared
+    /// `prefix` (a system turn ending in EOS): without prefix caching, then with it, both
+    /// the request that stores the prefix's cache and one that starts from it. The engine's
+    /// prefix cache is cleared first and holds `prefix` afterwards. Setting `cancel` cuts
+    /// each remaining run short, so the timings are then only a lower bound.
+    pub fn benchmark_prefix_cache(
+        &self,
+        prefix: &str,
+        prompt: &str,
+        max_tokens: usize,
+        cancel: &AtomicBool,
+    ) -> Result<PrefixCacheBenchmark, LlmError> {
+        let parts = [
+            PromptPart::Text(prefix.to_string()),
+            PromptPart::Eos,
+            PromptPart::Text(prompt.to_string()),
+        ];
+        let (tokens, prefix_len) = self.encode(&parts, true)?;
+        if let Ok(mut cached) = self.prefix_cache.lock() {
+            *cached = None;
+        }
+        let run = |cache_prefix: bool| {
+            let options = GenerateOptions {
+                max_tokens: max_tokens.max(1),
+                cache_prefix,
+                ..Default::default()
+            };
+            let start = std::time::Instant::now();
+            let (generated, _, _) =
+                self.decode_loop(tokens.clone(), prefix_len, &options, None, |_| {
+                    Ok(!cancel.load(Ordering::Relaxed))
+                })?;
+            Ok::<_, LlmError>((generated, start.elapsed().as_millis() as u64))
+        };
+        let (uncached, uncached_ms) = run(false)?;
+        // A prefix is only stored once the previous request had it (see `decode_loop`).
+        run(true)?;
+        let (_, storing_ms) = run(true)?;
+        let (cached, cached_ms) = run(true)?;
+        Ok(PrefixCacheBenchmark {
+            prefix_tokens: prefix_len,
+            uncached_ms,
+            storing_ms,
+            cached_ms,
+            identical: cached == uncached,
+        })
+    }
+}
 
-        let mut index_pos = 0usize;
-        let mut last_emitted_len = 0usize;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizers::decoders::byte_fallback::ByteFallback;
+    use tokenizers::decoders::fuse::Fuse;
+    use tokenizers::decoders::sequence::Sequence;
+    use tokenizers::models::bpe::BPE;
 
-        for _ in 0..max_tokens {
-            let (context_size, context_index) = if cache.use_kv_cache && tokens.len() > prompt_len {
-                (1, index_pos)
-            } else {
-                (tokens.len(), 0)
-            };
+    /// Byte-fallback tokenizer: "a", "b" plus the byte tokens for 😀 (F0 9F 98 80) and
+    /// é (C3 A9).
+    fn byte_tokenizer() -> Tokenizer {
+        let pieces = ["a", "b", "<0xF0>", "<0x9F>", "<0x98>", "<0x80>", "<0xC3>", "<0xA9>"];
+        let vocab = pieces
+            .iter()
+            .enumerate()
+            .map(|(id, p)| (p.to_string(), id as u32))
+            .collect();
+        let bpe = BPE::builder()
+            .vocab_and_merges(vocab, Vec::new())
+            .byte_fallback(true)
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(bpe);
+        let decoders = vec![ByteFallback::new().into(), Fuse::new().into()];
+        tokenizer.with_decoder(Sequence::new(decoders));
+        tokenizer
+    }
 
-            let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
-            let input = Tensor::new(ctxt, &self.device)
-                .map_err(|e| LlmError(format!("Tensor creation failed: {}", e)))?
-                .unsqueeze(0)
-                .map_err(|e| LlmError(format!("Unsqueeze failed: {}", e)))?;
+    fn stream_all(tokenizer: &Tokenizer, ids: &[u32]) -> Vec<Option<String>> {
+        let mut stream = TokenOutputStream::new(tokenizer);
+        ids.iter().map(|&id| stream.next_token(id).unwrap()).collect()
+    }
 
-            let logits = self
-                .model
-                .forward(&input, context_index, &mut cache)
-                .map_err(|e| LlmError(format!("Forward failed: {}", e)))?
-                .squeeze(0)
-                .map_err(|e| LlmError(format!("Squeeze failed: {}", e)))?;
+    #[test]
+    fn emoji_is_emitted_once_all_bytes_arrive() {
+        let tokenizer = byte_tokenizer();
+        let chunks = stream_all(&tokenizer, &[0, 2, 3, 4, 5, 1]);
+        assert_eq!(
+            chunks,
+            vec![
+                Some("a".to_string()),
+                None,
+                None,
+                None,
+                Some("😀".to_string()),
+                Some("b".to_string()),
+            ]
+        );
+    }
 
-            let logits = if (DEFAULT_REPEAT_PENALTY - 1.0).abs() < 1e-6 {
-                logits
-            } else {
-                let start_at = tokens.len().saturating_sub(DEFAULT_REPEAT_LAST_N);
-                apply_repeat_penalty(&logits, DEFAULT_REPEAT_PENALTY, &tokens[start_at..])
-                    .map_err(|e| LlmError(format!("Repeat penalty failed: {}", e)))?
-            };
+    #[test]
+    fn two_byte_character_is_not_split() {
+        let tokenizer = byte_tokenizer();
+        let chunks = stream_all(&tokenizer, &[6, 7]);
+        assert_eq!(chunks, vec![None, Some("é".to_string())]);
+    }
 
-            let next_token = logits_processor
-                .sample(&logits)
-                .map_err(|e| LlmError(format!("Sample failed: {}", e)))?;
+    #[test]
+    fn special_tokens_add_one_bos() {
+        let mut tokenizer = byte_tokenizer();
+        tokenizer.add_special_tokens(&[tokenizers::AddedToken::from("<s>", true)]);
+        let bos = tokenizer.token_to_id("<s>");
+        let encode = |text: &str, add_special_tokens| {
+            let parts = [PromptPart::Text(text.to_string())];
+            encode_parts(&tokenizer, bos, None, &parts, add_special_tokens).unwrap().0
+        };
+        let bos = bos.unwrap();
+        assert_eq!(encode("ab", true), [bos, 0, 1]);
+        assert_eq!(encode("ab", false), [0, 1]);
+        // A raw prompt that spells out BOS gets it once either way.
+        assert_eq!(encode("<s>ab", true), [bos, 0, 1]);
+        assert_eq!(encode("<s>ab", false), [bos, 0, 1]);
+    }
 
-            index_pos += ctxt.len();
-            tokens.push(next_token);
+    #[test]
+    fn finish_flushes_an_incomplete_character() {
+        let tokenizer = byte_tokenizer();
+        let mut stream = TokenOutputStream::new(&tokenizer);
+        assert_eq!(stream.next_token(0).unwrap(), Some("a".to_string()));
+        assert_eq!(stream.next_token(2).unwrap(), None);
+        assert_eq!(stream.finish().unwrap(), Some("\u{FFFD}".to_string()));
+        assert_eq!(stream.finish().unwrap(), None);
+    }
 
-            let generated_ids: Vec<u32> = tokens[prompt_len..].to_vec();
-            let full_text = self
-                .tokenizer
-                .decode(&generated_ids, true)
-                .map_err(|e| LlmError(format!("Decode error: {}", e)))?;
-            let current_len = full_text.len();
-            if current_len > last_emitted_len {
-                let chunk = &full_text[last_emitted_len..];
-                if !chunk.is_empty() {
-                    emit(chunk);
-                }
-                last_emitted_len = current_len;
-            }
+    #[test]
+    fn architecture_prefers_architectures_over_model_type() {
+        let detect = |config: serde_json::Value| Architecture::detect(&config).ok();
+        let config = serde_json::json!({
+            "architectures": ["Qwen2ForCausalLM"],
+            "model_type": "llama",
+        });
+        assert_eq!(architecture_name(&config), Some("Qwen2ForCausalLM"));
+        assert_eq!(detect(config), Some(Architecture::Qwen2));
+        let config = serde_json::json!({ "model_type": "mistral" });
+        assert_eq!(architecture_name(&config), Some("mistral"));
+        assert_eq!(detect(config), Some(Architecture::Mistral));
+        assert_eq!(detect(serde_json::json!({})), Some(Architecture::Llama));
+        assert_eq!(detect(serde_json::json!({ "model_type": "gpt2" })), None);
+    }
 
-            match &eos_token_id {
-                Some(LlamaEosToks::Single(id)) if next_token == *id => break,
-                Some(LlamaEosToks::Multiple(ids)) if ids.contains(&next_token) => break,
-                _ => {}
-            }
+    /// A model dir holding only `tokenizer`'s tokenizer.json.
+    fn tokenizer_dir(name: &str, tokenizer: &Tokenizer) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("tokenizer-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        tokenizer.save(dir.join("tokenizer.json"), false).unwrap();
+        dir
+    }
+
+    #[test]
+    fn tokenizer_cache_shares_identical_files() {
+        let first = tokenizer_dir("first", &byte_tokenizer());
+        let second = tokenizer_dir("second", &byte_tokenizer());
+        let cache = TokenizerCache::default();
+        let a = cache.get(&first).unwrap();
+        let b = cache.get(&second).unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(detokenize(&a, &[0, 1]).unwrap(), "ab");
+        assert!(matches!(detokenize(&a, &[99]), Err(LlmError::InvalidInput(_))));
+
+        let mut other = byte_tokenizer();
+        other.add_special_tokens(&[tokenizers::AddedToken::from("<s>", true)]);
+        other.save(second.join("tokenizer.json"), false).unwrap();
+        let edited = cache.get(&second).unwrap();
+        let unchanged = cache.get(&first).unwrap();
+        std::fs::remove_dir_all(&first).unwrap();
+        std::fs::remove_dir_all(&second).unwrap();
+        assert!(!Arc::ptr_eq(&a, &edited), "an edited file is loaded afresh");
+        assert!(Arc::ptr_eq(&a, &unchanged));
+    }
+
+    #[test]
+    fn cache_entries_keep_the_most_recently_used() {
+        let mut entries = Vec::new();
+        for i in 0..TOKENIZER_CACHE_SIZE {
+            insert_bounded(&mut entries, i);
         }
+        assert_eq!(*touch(&mut entries, 0), 0);
+        insert_bounded(&mut entries, TOKENIZER_CACHE_SIZE);
+        assert_eq!(entries.len(), TOKENIZER_CACHE_SIZE);
+        assert!(!entries.contains(&1), "least recently used is dropped");
+        assert_eq!(entries[..2], [2, 3]);
+        assert_eq!(entries[entries.len() - 2..], [0, TOKENIZER_CACHE_SIZE]);
+    }
+
+    #[test]
+    fn devices_parse_by_name() {
+        assert!(parse_device(" CPU ").unwrap().is_cpu());
+        let invalid = |name: &str| matches!(parse_device(name), Err(LlmError::InvalidInput(_)));
+        assert!(invalid("cuda:first"));
+        assert!(invalid("cuda:-1"));
+        assert!(invalid("tpu"));
+        if !candle_core::utils::cuda_is_available() {
+            assert!(matches!(parse_device("cuda:0"), Err(LlmError::Unsupported(_))));
+        }
+    }
+
+    #[test]
+    fn device_names_match_with_a_default_index() {
+        assert_eq!(canonical_device_name("cuda"), "cuda:0");
+        assert_eq!(canonical_device_name(" CUDA:0"), "cuda:0");
+        assert_eq!(canonical_device_name("metal:1"), "metal:1");
+        assert_eq!(canonical_device_name("CPU"), "cpu");
+        assert!(device_matches("CPU", &Device::Cpu));
+        assert!(!device_matches("cuda", &Device::Cpu));
+        assert!(!device_matches("cuda:0", &Device::Cpu));
+        assert!(!device_matches("cpu:1", &Device::Cpu));
+    }
 
-        Ok(())
+    #[test]
+    fn lines_count_from_the_first_visible_text() {
+        let mut counter = LineCounter::default();
+        for chunk in ["\n", " \n\t", ""] {
+            counter.push(chunk);
+        }
+        assert_eq!(counter.lines, 0);
+        counter.push("\nHi");
+        assert_eq!(counter.lines, 0);
+        counter.push(" there.\n\n");
+        assert_eq!(counter.lines, 2);
+        counter.push("\n");
+        assert_eq!(counter.lines, 3);
+    }
+
+    #[test]
+    fn logprobs_are_a_distribution_and_masked_ids_are_impossible() {
+        let logits = Tensor::new(&[1.0f32, 2.0, 3.0, 1000.0], &Device::Cpu).unwrap();
+        let total = |logits: &Tensor| -> f32 {
+            (0..4).map(|i| logprob(logits, i).unwrap().exp()).sum()
+        };
+        assert!((total(&logits) - 1.0).abs() < 1e-5);
+        assert!(logprob(&logits, 3).unwrap() <= 0.0);
+
+        let masked = suppress(&logits, &[3, 99]).unwrap();
+        assert_eq!(logprob(&masked, 3).unwrap(), f32::NEG_INFINITY);
+        assert!((total(&masked) - 1.0).abs() < 1e-5);
+        assert!(logprob(&masked, 2).unwrap() > logprob(&masked, 0).unwrap());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,70 @@
+//! Log filter that can be changed at runtime (`set_log_level`): a default level plus
+//! per-module overrides, checked by a filter on the log plugin.
+
+use log::LevelFilter;
+use std::sync::RwLock;
+
+struct Filter {
+    default: LevelFilter,
+    /// Module path prefixes (e.g. "app_lib::rag") and their levels.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+static FILTER: RwLock<Filter> = RwLock::new(Filter {
+    default: LevelFilter::Info,
+    modules: Vec::new(),
+});
+
+/// Level names accepted by `set_log_level`: off, error, warn, info, debug, trace.
+pub fn parse_level(name: &str) -> Result<LevelFilter, String> {
+    name.trim().parse().map_err(|_| {
+        format!(
+            "Unknown log level: {} (expected off, error, warn, info, debug or trace)",
+            name
+        )
+    })
+}
+
+/// For the log plugin's `filter`: the most specific module override for the record's
+/// target wins, else the default level.
+pub fn enabled(metadata: &log::Metadata) -> bool {
+    let Ok(filter) = FILTER.read() else {
+        return true;
+    };
+    let target = metadata.target();
+    let level = filter
+        .modules
+        .iter()
+        .filter(|(module, _)| {
+            target == module || target.starts_with(&format!("{}::", module))
+        })
+        .max_by_key(|(module, _)| module.len())
+        .map_or(filter.default, |(_, level)| *level);
+    metadata.level() <= level
+}
+
+/// Set the default level, or with `module` the level for that module only. A bare module
+/// name such as "rag" means this crate's module. Also raises or lowers `log`'s global max
+/// level to the most verbose level now in use, so disabled records stay cheap.
+pub fn set_level(level: LevelFilter, module: Option<&str>) -> Result<(), String> {
+    let mut filter = FILTER.write().map_err(|e| e.to_string())?;
+    match module.map(str::trim).filter(|m| !m.is_empty()) {
+        Some(module) => {
+            let module = if module.contains("::") {
+                module.to_string()
+            } else {
+                format!("{}::{}", env!("CARGO_CRATE_NAME"), module)
+            };
+            filter.modules.retain(|(m, _)| *m != module);
+            filter.modules.push((module, level));
+        }
+        None => filter.default = level,
+    }
+    let max = filter
+        .modules
+        .iter()
+        .map(|(_, level)| *level)
+        .fold(filter.default, Ord::max);
+    log::set_max_level(max);
+    Ok(())
+}
@@ -1,15 +1,102 @@
 //! Ollama API client for offloading inference (e.g. to AMD GPU via ROCm on Windows).
 
 use serde::Deserialize;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 
+#[derive(Debug)]
+pub enum OllamaError {
+    /// The request couldn't be sent (server down, bad URL, ...).
+    Connection(reqwest::Error),
+    /// Non-success status, with the response body.
+    HttpStatus(u16, String),
+    /// The streamed body couldn't be read.
+    Read(std::io::Error),
+    /// An `error` chunk from the server, e.g. "model not found".
+    Model(String),
+    /// The base URL can't be used, with the reason.
+    InvalidUrl(String),
+}
+
+impl std::fmt::Display for OllamaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OllamaError::Connection(e) => write!(f, "Ollama request failed: {}", e),
+            OllamaError::HttpStatus(status, body) => write!(f, "Ollama error {}: {}", status, body),
+            OllamaError::Read(e) => write!(f, "Ollama response read failed: {}", e),
+            OllamaError::Model(message) => write!(f, "Ollama error: {}", message),
+            OllamaError::InvalidUrl(reason) => write!(f, "Invalid Ollama URL: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for OllamaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OllamaError::Connection(e) => Some(e),
+            OllamaError::Read(e) => Some(e),
+            OllamaError::HttpStatus(..) | OllamaError::Model(_) | OllamaError::InvalidUrl(_) => {
+                None
+            }
+        }
+    }
+}
+
+/// `base_url` without trailing slashes, with `http://` added when it names no scheme (as in
+/// "localhost:11434"). Only http and https URLs with a host are accepted.
+pub fn normalize_base_url(base_url: &str) -> Result<String, OllamaError> {
+    let trimmed = base_url.trim();
+    if trimmed.is_empty() {
+        return Err(OllamaError::InvalidUrl("empty URL".into()));
+    }
+    let with_scheme = if trimmed.contains("://") {
+        trimmed.to_string()
+    } else {
+        format!("http://{}", trimmed)
+    };
+    let url = reqwest::Url::parse(&with_scheme)
+        .map_err(|e| OllamaError::InvalidUrl(format!("{} ({})", base_url, e)))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        let reason = format!("{} (expected an http or https URL)", base_url);
+        return Err(OllamaError::InvalidUrl(reason));
+    }
+    if url.host_str().map_or(true, str::is_empty) {
+        return Err(OllamaError::InvalidUrl(format!("{} (no host)", base_url)));
+    }
+    Ok(with_scheme.trim_end_matches('/').to_string())
+}
+
 #[derive(serde::Serialize)]
 struct GenerateRequest {
     model: String,
     prompt: String,
     stream: bool,
+    /// Skip the model's template on the server; the prompt is already complete.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    raw: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     options: Option<GenerateOptions>,
+    /// How long the server keeps the model loaded after this request: a duration such as
+    /// "30m", or a number of seconds where a negative one means forever.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_keep_alive"
+    )]
+    keep_alive: Option<String>,
+}
+
+/// Ollama parses a string `keep_alive` as a duration with a unit, so a bare number ("-1",
+/// "300") is sent as a JSON number of seconds instead.
+fn serialize_keep_alive<S>(keep_alive: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let value = keep_alive.as_deref().map(str::trim).unwrap_or_default();
+    match value.parse::<i64>() {
+        Ok(seconds) => serializer.serialize_i64(seconds),
+        Err(_) => serializer.serialize_str(value),
+    }
 }
 
 #[derive(serde::Serialize)]
@@ -24,10 +111,53 @@ struct GenerateOptions {
 struct GenerateChunk {
     response: Option<String>,
     done: Option<bool>,
+    error: Option<String>,
+    done_reason: Option<String>,
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+    /// Nanoseconds spent generating `eval_count` tokens.
+    eval_duration: Option<u64>,
+}
+
+/// Token counts and speed from the final (`done`) chunk, for `chat-done`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Metrics {
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+    pub tokens_per_second: Option<f64>,
+    /// Ollama's `done_reason`, e.g. "stop" or "length".
+    pub finish_reason: Option<String>,
+}
+
+impl Metrics {
+    fn from_chunk(chunk: &GenerateChunk) -> Self {
+        let tokens_per_second = match (chunk.eval_count, chunk.eval_duration) {
+            (Some(count), Some(nanos)) if nanos > 0 => Some(count as f64 * 1e9 / nanos as f64),
+            _ => None,
+        };
+        Metrics {
+            prompt_tokens: chunk.prompt_eval_count,
+            completion_tokens: chunk.eval_count,
+            tokens_per_second,
+            finish_reason: chunk.done_reason.clone(),
+        }
+    }
 }
 
 /// Call Ollama /api/generate with streaming; send each "response" chunk via `tx` as Ok(chunk).
+/// An `error` chunk (e.g. "model not found") is sent as Err and ends the stream. Returns the
+/// final chunk's `Metrics`, or None if the stream ended without one.
 /// Runs synchronously (blocking) so it can be called from a sync Tauri command.
+/// `base_url` is normalized first (see `normalize_base_url`).
+/// With `raw`, Ollama sends the prompt to the model without applying its template.
+/// `keep_alive` overrides how long the server keeps the model loaded afterwards (see
+/// `GenerateRequest::keep_alive`); None leaves the server's default.
+///
+/// The body is read as it arrives and split into lines with a `LineBuffer`, so a chunk cut
+/// across two reads is parsed once whole. `cancel` is checked before each read and each
+/// line; once it is set the response is dropped, closing the connection so the server stops
+/// generating, and the function returns Ok(None).
+#[allow(clippy::too_many_arguments)]
 pub fn stream_generate(
     client: &reqwest::blocking::Client,
     base_url: &str,
@@ -35,56 +165,180 @@ pub fn stream_generate(
     prompt: &str,
     num_predict: Option<u32>,
     temperature: Option<f64>,
-    tx: Sender<Result<String, String>>,
-) -> Result<(), String> {
-    let url = format!("{}/api/generate", base_url.trim_end_matches('/'));
+    raw: bool,
+    keep_alive: Option<&str>,
+    cancel: &AtomicBool,
+    tx: Sender<Result<String, OllamaError>>,
+) -> Result<Option<Metrics>, OllamaError> {
+    let url = format!("{}/api/generate", normalize_base_url(base_url)?);
     let body = GenerateRequest {
         model: model.to_string(),
         prompt: prompt.to_string(),
         stream: true,
+        raw,
         options: Some(GenerateOptions {
             num_predict,
             temperature,
         }),
+        keep_alive: keep_alive.map(String::from),
     };
 
     let response = client
         .post(&url)
         .json(&body)
         .send()
-        .map_err(|e| format!("Ollama request failed: {}", e))?;
+        .map_err(OllamaError::Connection)?;
 
     if !response.status().is_success() {
-        let status = response.status();
+        let status = response.status().as_u16();
         let text = response.text().unwrap_or_default();
-        return Err(format!("Ollama error {}: {}", status, text));
+        return Err(OllamaError::HttpStatus(status, text));
     }
 
-    let bytes = response
-        .bytes()
-        .map_err(|e| format!("Ollama response read failed: {}", e))?;
-
-    for line in bytes.split(|&b| b == b'\n') {
-        if line.is_empty() {
-            continue;
+    let mut response = response;
+    let mut lines = LineBuffer::default();
+    let mut read_buf = [0u8; 4096];
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            log::info!("Ollama stream cancelled");
+            return Ok(None);
         }
-        let line = match std::str::from_utf8(line) {
-            Ok(s) => s,
-            Err(_) => continue,
-        };
-        let chunk: GenerateChunk = match serde_json::from_str(line) {
-            Ok(c) => c,
-            Err(_) => continue,
+        let n = response.read(&mut read_buf).map_err(OllamaError::Read)?;
+        let complete = if n == 0 {
+            lines.finish().into_iter().collect()
+        } else {
+            lines.push(&read_buf[..n])
         };
-        if let Some(ref s) = chunk.response {
-            if !s.is_empty() {
-                let _ = tx.send(Ok(s.clone()));
+        for line in complete {
+            if cancel.load(Ordering::Relaxed) {
+                log::info!("Ollama stream cancelled");
+                return Ok(None);
             }
+            let Some(chunk) = parse_chunk(&line) else {
+                continue;
+            };
+            if let Some(error) = chunk.error {
+                let _ = tx.send(Err(OllamaError::Model(error)));
+                return Ok(None);
+            }
+            if let Some(ref s) = chunk.response {
+                if !s.is_empty() {
+                    let _ = tx.send(Ok(s.clone()));
+                }
+            }
+            if chunk.done == Some(true) {
+                return Ok(Some(Metrics::from_chunk(&chunk)));
+            }
+        }
+        if n == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// One NDJSON line as a chunk; blank or malformed lines are None and skipped.
+fn parse_chunk(line: &[u8]) -> Option<GenerateChunk> {
+    let line = std::str::from_utf8(line).ok()?;
+    serde_json::from_str(line).ok()
+}
+
+/// Bytes of a newline-delimited stream whose reads may end anywhere, even inside a line or
+/// a multi-byte character. Only complete lines come out; the partial last line waits for
+/// the next read.
+#[derive(Default)]
+struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Append `bytes` and return the lines they complete, without their newlines.
+    fn push(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(bytes);
+        let Some(last_newline) = self.pending.iter().rposition(|&b| b == b'\n') else {
+            return Vec::new();
+        };
+        let rest = self.pending.split_off(last_newline + 1);
+        let complete = std::mem::replace(&mut self.pending, rest);
+        complete[..last_newline]
+            .split(|&b| b == b'\n')
+            .map(<[u8]>::to_vec)
+            .collect()
+    }
+
+    /// The last line, if the stream ended without a newline after it.
+    fn finish(&mut self) -> Option<Vec<u8>> {
+        Some(std::mem::take(&mut self.pending)).filter(|line| !line.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Responses of the chunks in `body`, fed to a `LineBuffer` in pieces of the given
+    /// lengths (the rest in one last piece).
+    fn responses(body: &[u8], piece_lens: &[usize]) -> Vec<String> {
+        let mut lines = LineBuffer::default();
+        let mut complete = Vec::new();
+        let mut rest = body;
+        for &len in piece_lens {
+            let (piece, tail) = rest.split_at(len.min(rest.len()));
+            complete.extend(lines.push(piece));
+            rest = tail;
         }
-        if chunk.done == Some(true) {
-            break;
+        complete.extend(lines.push(rest));
+        complete.extend(lines.finish());
+        complete
+            .iter()
+            .filter_map(|line| parse_chunk(line)?.response)
+            .collect()
+    }
+
+    #[test]
+    fn chunks_split_across_reads_parse_whole() {
+        let body = "{\"response\":\"Caf\u{e9}\"}\n{\"response\":\" \u{1f600}\"}\n\
+                    {\"response\":\"!\",\"done\":true}";
+        let body = body.as_bytes();
+        let expected = vec!["Caf\u{e9}", " \u{1f600}", "!"];
+        assert_eq!(responses(body, &[]), expected);
+        for split in 0..=body.len() {
+            assert_eq!(responses(body, &[split]), expected, "split at byte {}", split);
         }
+        assert_eq!(responses(body, &vec![1; body.len()]), expected);
+        assert_eq!(responses(body, &[3, 7, 2, 11, 5]), expected);
     }
 
-    Ok(())
+    /// Whether `base_url` is rejected as an invalid URL.
+    fn invalid(base_url: &str) -> bool {
+        matches!(normalize_base_url(base_url), Err(OllamaError::InvalidUrl(_)))
+    }
+
+    #[test]
+    fn base_url_without_scheme_gets_http() {
+        let url = normalize_base_url("localhost:11434").unwrap();
+        assert_eq!(url, "http://localhost:11434");
+        let url = normalize_base_url("  192.168.1.20:11434 ").unwrap();
+        assert_eq!(url, "http://192.168.1.20:11434");
+    }
+
+    #[test]
+    fn base_url_keeps_scheme_and_drops_trailing_slash() {
+        let url = normalize_base_url("http://localhost:11434/").unwrap();
+        assert_eq!(url, "http://localhost:11434");
+        let url = normalize_base_url("https://ollama.example.com//").unwrap();
+        assert_eq!(url, "https://ollama.example.com");
+        let url = normalize_base_url("https://example.com/ollama/").unwrap();
+        assert_eq!(url, "https://example.com/ollama");
+    }
+
+    #[test]
+    fn unusable_base_url_is_rejected() {
+        assert!(invalid(""));
+        assert!(invalid("   "));
+        assert!(invalid("http://"));
+        assert!(invalid("localhost:port"));
+        assert!(invalid("http://local host:11434"));
+        assert!(invalid("ftp://localhost:11434"));
+        assert!(invalid("file:///tmp/ollama"));
+    }
 }
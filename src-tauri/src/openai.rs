@@ -0,0 +1,120 @@
+//! OpenAI-compatible chat completions client (llama.cpp server, LocalAI, vLLM, ...).
+
+use serde::Deserialize;
+use std::io::BufRead;
+use std::sync::mpsc::Sender;
+
+#[derive(serde::Serialize)]
+pub struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, content: String) -> Self {
+        Self {
+            role: role.to_string(),
+            content,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct ChatChunk {
+    #[serde(default)]
+    choices: Vec<ChunkChoice>,
+}
+
+#[derive(Deserialize)]
+struct ChunkChoice {
+    delta: Option<ChunkDelta>,
+}
+
+#[derive(Deserialize)]
+struct ChunkDelta {
+    content: Option<String>,
+}
+
+/// Accepts either the server root ("http://localhost:8080") or its "/v1" prefix.
+fn completions_url(base_url: &str) -> String {
+    let base = base_url.trim_end_matches('/');
+    if base.ends_with("/v1") {
+        format!("{}/chat/completions", base)
+    } else {
+        format!("{}/v1/chat/completions", base)
+    }
+}
+
+/// Call /v1/chat/completions with streaming; send each `choices[0].delta.content` via `tx`
+/// as Ok(chunk). The response is server-sent events: `data: {...}` lines ending with
+/// `data: [DONE]`. Runs synchronously (blocking) so it can be called from a sync Tauri command.
+pub fn stream_chat(
+    client: &reqwest::blocking::Client,
+    base_url: &str,
+    model: &str,
+    messages: Vec<ChatMessage>,
+    max_tokens: Option<u32>,
+    temperature: Option<f64>,
+    tx: Sender<Result<String, String>>,
+) -> Result<(), String> {
+    let url = completions_url(base_url);
+    let body = ChatRequest {
+        model: model.to_string(),
+        messages,
+        stream: true,
+        max_tokens,
+        temperature,
+    };
+
+    let response = client
+        .post(&url)
+        .json(&body)
+        .send()
+        .map_err(|e| format!("OpenAI-compatible request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().unwrap_or_default();
+        return Err(format!("OpenAI-compatible server error {}: {}", status, text));
+    }
+
+    let reader = std::io::BufReader::new(response);
+    for line in reader.lines() {
+        let line = line.map_err(|e| format!("OpenAI-compatible response read failed: {}", e))?;
+        let data = match line.trim().strip_prefix("data:") {
+            Some(d) => d.trim(),
+            None => continue,
+        };
+        if data == "[DONE]" {
+            break;
+        }
+        let chunk: ChatChunk = match serde_json::from_str(data) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        let content = chunk
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|c| c.delta)
+            .and_then(|d| d.content);
+        if let Some(s) = content {
+            if !s.is_empty() {
+                let _ = tx.send(Ok(s));
+            }
+        }
+    }
+
+    Ok(())
+}
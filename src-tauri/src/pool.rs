@@ -0,0 +1,118 @@
+//! A small fixed-size pool of engines so independent conversations (e.g. chat tabs) can
+//! generate in parallel instead of serializing on one mutex.
+//!
+//! Each slot holds its own fully loaded model, so memory grows with every slot in use;
+//! slots are only loaded on demand, and an idle engine of the requested model is reused
+//! before empty slots.
+
+use crate::llm::{LlmEngine, ModelInfo};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+/// Upper bound on concurrently loaded engines.
+pub const DEFAULT_POOL_SIZE: usize = 2;
+
+pub struct EnginePool {
+    slots: Vec<Mutex<Option<LlmEngine>>>,
+    next: AtomicUsize,
+}
+
+impl EnginePool {
+    pub fn new(size: usize) -> Self {
+        Self {
+            slots: (0..size.max(1)).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Lock an idle slot, preferring one whose engine was loaded from `model_dir` (any
+    /// loaded engine without one), then an empty slot, so a different model is loaded
+    /// alongside rather than over a loaded one. When every slot is busy, wait for the slots
+    /// in turn (round-robin) so waiters spread out.
+    pub fn acquire(
+        &self,
+        model_dir: Option<&Path>,
+    ) -> Result<MutexGuard<'_, Option<LlmEngine>>, String> {
+        let loaded_from_dir = |engine: &Option<LlmEngine>| match (engine, model_dir) {
+            (Some(engine), Some(dir)) => engine.model_dir == dir,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        for slot in &self.slots {
+            if let Ok(guard) = slot.try_lock() {
+                if loaded_from_dir(&guard) {
+                    return Ok(guard);
+                }
+            }
+        }
+        for slot in &self.slots {
+            if let Ok(guard) = slot.try_lock() {
+                if guard.is_none() {
+                    return Ok(guard);
+                }
+            }
+        }
+        for slot in &self.slots {
+            if let Ok(guard) = slot.try_lock() {
+                return Ok(guard);
+            }
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        self.slots[i].lock().map_err(|e| e.to_string())
+    }
+
+    /// Drop every loaded engine; returns how many were loaded. Slots in use are retried
+    /// until `wait` has passed (their work should have been cancelled first), then left
+    /// loaded rather than blocking on a generation that doesn't stop.
+    pub fn unload_all(&self, wait: Duration) -> usize {
+        let deadline = Instant::now() + wait;
+        let mut busy: Vec<&Mutex<Option<LlmEngine>>> = self.slots.iter().collect();
+        let mut unloaded = 0;
+        loop {
+            busy.retain(|slot| {
+                let mut guard = match slot.try_lock() {
+                    Ok(guard) => guard,
+                    Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                    Err(TryLockError::WouldBlock) => return true,
+                };
+                unloaded += usize::from(guard.take().is_some());
+                false
+            });
+            if busy.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        if !busy.is_empty() {
+            log::warn!("{} engine slot(s) still busy; left loaded", busy.len());
+        }
+        unloaded
+    }
+
+    /// Info of an idle engine loaded from `model_dir`, without waiting on busy slots.
+    pub fn loaded_info(&self, model_dir: &Path) -> Option<ModelInfo> {
+        self.slots.iter().find_map(|slot| {
+            let guard = slot.try_lock().ok()?;
+            let engine = guard.as_ref().filter(|e| e.model_dir == model_dir)?;
+            Some(engine.info())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unload_all_skips_a_busy_slot_after_waiting() {
+        let pool = EnginePool::new(2);
+        let _busy = pool.slots[0].lock().unwrap();
+        let start = Instant::now();
+        assert_eq!(pool.unload_all(Duration::from_millis(50)), 0);
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(50), "gave up after {:?}", waited);
+        assert!(waited < Duration::from_secs(5), "blocked for {:?}", waited);
+    }
+}
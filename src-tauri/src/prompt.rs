@@ -0,0 +1,177 @@
+//! Chat templates: how a system block, earlier turns and the user's message are laid out
+//! for each model family, ending where the assistant's reply should begin.
+
+use crate::llm::{PromptPart, EOS_TOKEN};
+
+/// One earlier exchange in the conversation.
+#[derive(Debug, Clone, Copy)]
+pub struct Turn<'a> {
+    pub user: &'a str,
+    pub assistant: &'a str,
+}
+
+pub trait ChatTemplate {
+    /// The prompt as parts, so the local engine can insert the model's own EOS id where a
+    /// turn ends (`PromptPart::Eos`).
+    fn parts(&self, system: Option<&str>, history: &[Turn], user: &str) -> Vec<PromptPart>;
+
+    /// The prompt as text, for backends that take a prompt string.
+    fn render(&self, system: Option<&str>, history: &[Turn], user: &str) -> String {
+        render_parts(&self.parts(system, history, user))
+    }
+}
+
+/// Prompt parts as text, with EOS spelled out as `llm::EOS_TOKEN`.
+pub fn render_parts(parts: &[PromptPart]) -> String {
+    parts
+        .iter()
+        .map(|part| match part {
+            PromptPart::Text(text) => text.as_str(),
+            PromptPart::Eos => EOS_TOKEN,
+        })
+        .collect()
+}
+
+/// The template a `chat_template` name selects; "zephyr" is TinyLlama's format and
+/// "mistral" Llama 2's.
+pub fn template(name: &str) -> Option<&'static dyn ChatTemplate> {
+    match name.to_ascii_lowercase().as_str() {
+        "tinyllama" | "zephyr" => Some(&TinyLlama),
+        "llama2" | "mistral" => Some(&Llama2),
+        "chatml" => Some(&ChatMl),
+        _ => None,
+    }
+}
+
+/// `<|system|>`, `<|user|>` and `<|assistant|>` headers, each turn ended by EOS.
+pub struct TinyLlama;
+
+impl ChatTemplate for TinyLlama {
+    fn parts(&self, system: Option<&str>, history: &[Turn], user: &str) -> Vec<PromptPart> {
+        use PromptPart::{Eos, Text};
+        let mut parts = Vec::new();
+        // Headers after the first start on a new line.
+        let header = |parts: &mut Vec<PromptPart>, role: &str, text: &str| {
+            let newline = if parts.is_empty() { "" } else { "\n" };
+            parts.extend([Text(format!("{}<|{}|>\n{}", newline, role, text)), Eos]);
+        };
+        if let Some(system) = system {
+            header(&mut parts, "system", system);
+        }
+        for turn in history {
+            header(&mut parts, "user", turn.user);
+            header(&mut parts, "assistant", turn.assistant);
+        }
+        header(&mut parts, "user", user);
+        parts.push(Text("\n<|assistant|>\n".to_string()));
+        parts
+    }
+}
+
+/// `[INST] ... [/INST]` pairs, with the system block in `<<SYS>>` inside the first one.
+/// Each completed exchange ends with EOS and the next starts with "<s>".
+pub struct Llama2;
+
+impl ChatTemplate for Llama2 {
+    fn parts(&self, system: Option<&str>, history: &[Turn], user: &str) -> Vec<PromptPart> {
+        use PromptPart::{Eos, Text};
+        let mut system = system.map(|s| format!("<<SYS>>\n{}\n<</SYS>>\n\n", s));
+        let mut parts = Vec::new();
+        for turn in history {
+            let start = if parts.is_empty() { "" } else { "<s>" };
+            let system = system.take().unwrap_or_default();
+            parts.push(Text(format!(
+                "{}[INST] {}{} [/INST] {} ",
+                start, system, turn.user, turn.assistant
+            )));
+            parts.push(Eos);
+        }
+        let start = if parts.is_empty() { "" } else { "<s>" };
+        let system = system.unwrap_or_default();
+        parts.push(Text(format!("{}[INST] {}{} [/INST]", start, system, user)));
+        parts
+    }
+}
+
+/// `<|im_start|>role ... <|im_end|>` blocks. The turn end is spelled out rather than an
+/// `Eos` part, since ChatML models often name a different EOS in config.json.
+pub struct ChatMl;
+
+impl ChatTemplate for ChatMl {
+    fn parts(&self, system: Option<&str>, history: &[Turn], user: &str) -> Vec<PromptPart> {
+        let block = |role: &str, text: &str| format!("<|im_start|>{}\n{}<|im_end|>\n", role, text);
+        let mut text: String = system.map(|s| block("system", s)).unwrap_or_default();
+        for turn in history {
+            text.push_str(&block("user", turn.user));
+            text.push_str(&block("assistant", turn.assistant));
+        }
+        text.push_str(&block("user", user));
+        text.push_str("<|im_start|>assistant\n");
+        vec![PromptPart::Text(text)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HISTORY: [Turn; 1] = [Turn {
+        user: "When is the dentist?",
+        assistant: "Tuesday at 9.",
+    }];
+
+    #[test]
+    fn tinyllama_renders_headers_with_eos() {
+        assert_eq!(
+            TinyLlama.render(None, &[], "Hi"),
+            "<|user|>\nHi</s>\n<|assistant|>\n"
+        );
+        assert_eq!(
+            TinyLlama.render(Some("Be brief."), &HISTORY, "And after?"),
+            "<|system|>\nBe brief.</s>\n\
+             <|user|>\nWhen is the dentist?</s>\n\
+             <|assistant|>\nTuesday at 9.</s>\n\
+             <|user|>\nAnd after?</s>\n\
+             <|assistant|>\n"
+        );
+    }
+
+    #[test]
+    fn llama2_puts_system_in_first_inst() {
+        assert_eq!(Llama2.render(None, &[], "Hi"), "[INST] Hi [/INST]");
+        assert_eq!(
+            Llama2.render(Some("Be brief."), &[], "Hi"),
+            "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST]"
+        );
+        assert_eq!(
+            Llama2.render(Some("Be brief."), &HISTORY, "And after?"),
+            "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nWhen is the dentist? [/INST] Tuesday at 9. </s>\
+             <s>[INST] And after? [/INST]"
+        );
+    }
+
+    #[test]
+    fn chatml_wraps_each_turn() {
+        assert_eq!(
+            ChatMl.render(None, &[], "Hi"),
+            "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            ChatMl.render(Some("Be brief."), &HISTORY, "And after?"),
+            "<|im_start|>system\nBe brief.<|im_end|>\n\
+             <|im_start|>user\nWhen is the dentist?<|im_end|>\n\
+             <|im_start|>assistant\nTuesday at 9.<|im_end|>\n\
+             <|im_start|>user\nAnd after?<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn template_names_are_case_insensitive() {
+        let render = |name: &str| template(name).map(|t| t.render(None, &[], "Hi"));
+        assert_eq!(render("Zephyr"), Some(TinyLlama.render(None, &[], "Hi")));
+        assert_eq!(render("MISTRAL"), Some(Llama2.render(None, &[], "Hi")));
+        assert_eq!(render("chatml"), Some(ChatMl.render(None, &[], "Hi")));
+        assert_eq!(render("alpaca"), None);
+    }
+}
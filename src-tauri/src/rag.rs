@@ -1,59 +1,1859 @@
-use std::path::Path;
+use crate::dates;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-#[derive(Debug, serde::Deserialize)]
+/// One entry in the events file. The optional fields may be omitted, so older files with
+/// only title/date/description still load.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Event {
     pub title: String,
     pub date: String,
     pub description: String,
+    pub location: Option<String>,
+    pub start_time: Option<String>,
+    pub end_time: Option<String>,
+    /// Categories such as "work" or "health". A JSON array, or in CSV one cell with the
+    /// tags separated by commas or semicolons.
+    #[serde(default, deserialize_with = "deserialize_tags")]
+    pub tags: Option<Vec<String>>,
+    /// Importance from 1 (low) to 5 (high), scaling the event's relevance score (see
+    /// `Event::priority_factor`); unset is `DEFAULT_PRIORITY`.
+    pub priority: Option<u8>,
 }
 
-pub fn load_events(events_path: &Path) -> Result<Vec<Event>, String> {
-    let bytes =
-        std::fs::read(events_path).map_err(|e| format!("Failed to read events file: {}", e))?;
-    let events: Vec<Event> =
-        serde_json::from_slice(&bytes).map_err(|e| format!("Invalid events JSON: {}", e))?;
-    Ok(events)
+/// The neutral priority: scores are left as they are.
+pub const DEFAULT_PRIORITY: u8 = 3;
+
+/// How much each priority step above or below `DEFAULT_PRIORITY` adds to or takes from the
+/// score, as a fraction of it.
+const PRIORITY_STEP: f32 = 0.25;
+
+impl Event {
+    /// Multiplier for the event's relevance score: 1.5 at priority 5, 1 at the default of
+    /// 3, 0.5 at 1. Priorities outside 1..=5 count as the nearest end.
+    pub fn priority_factor(&self) -> f32 {
+        let priority = self.priority.unwrap_or(DEFAULT_PRIORITY).clamp(1, 5);
+        1.0 + PRIORITY_STEP * (f32::from(priority) - f32::from(DEFAULT_PRIORITY))
+    }
+
+    /// Whether any of the event's tags is one of `tags`, ignoring case.
+    pub fn has_any_tag(&self, tags: &[String]) -> bool {
+        self.tags.iter().flatten().any(|tag| {
+            tags.iter().any(|wanted| tag.trim().eq_ignore_ascii_case(wanted.trim()))
+        })
+    }
 }
 
-fn event_searchable_text(event: &Event) -> String {
-    format!("{} {}", event.title, event.description).to_lowercase()
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum TagsField {
+    List(Vec<String>),
+    Text(String),
 }
 
-pub fn search_events<'a>(events: &'a [Event], query: &str, limit: usize) -> Vec<&'a Event> {
-    let query_lower = query.to_lowercase();
-    let query_words: Vec<&str> = query_lower
-        .split_whitespace()
-        .filter(|s| s.len() > 1)
+fn deserialize_tags<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let tags = match <Option<TagsField> as serde::Deserialize>::deserialize(deserializer)? {
+        Some(TagsField::List(tags)) => tags,
+        Some(TagsField::Text(text)) => text.split([',', ';']).map(String::from).collect(),
+        None => return Ok(None),
+    };
+    let tags: Vec<String> = tags
+        .into_iter()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
         .collect();
+    Ok(Some(tags).filter(|tags| !tags.is_empty()))
+}
+
+/// An `Event` as a CSV row, with its tags joined into one cell.
+#[derive(serde::Serialize)]
+struct CsvEvent<'a> {
+    title: &'a str,
+    date: &'a str,
+    description: &'a str,
+    location: Option<&'a str>,
+    start_time: Option<&'a str>,
+    end_time: Option<&'a str>,
+    tags: Option<String>,
+    priority: Option<u8>,
+}
+
+impl<'a> From<&'a Event> for CsvEvent<'a> {
+    fn from(event: &'a Event) -> Self {
+        CsvEvent {
+            title: &event.title,
+            date: &event.date,
+            description: &event.description,
+            location: event.location.as_deref(),
+            start_time: event.start_time.as_deref(),
+            end_time: event.end_time.as_deref(),
+            tags: event.tags.as_ref().map(|tags| tags.join(";")),
+            priority: event.priority,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RagError {
+    FileNotFound(PathBuf),
+    Read(std::io::Error),
+    ParseError(serde_json::Error),
+    /// A CSV events file failed to parse; `row` is the line the bad record starts on (the
+    /// header is line 1), which differs from its record number after multi-line cells.
+    CsvError { row: usize, source: csv::Error },
+    /// An event to add was rejected, e.g. for a missing title or unreadable date.
+    InvalidEvent(String),
+    Write(std::io::Error),
+    /// An events URL couldn't be fetched: connection failure, timeout or error status.
+    Fetch { url: String, reason: String },
+}
+
+impl std::fmt::Display for RagError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RagError::FileNotFound(path) => write!(f, "Events file not found: {}", path.display()),
+            RagError::Read(e) => write!(f, "Failed to read events file: {}", e),
+            RagError::ParseError(e) => write!(f, "Invalid events JSON: {}", e),
+            RagError::CsvError { row, source } => {
+                write!(f, "Invalid events CSV at row {}: {}", row, source)
+            }
+            RagError::InvalidEvent(reason) => write!(f, "Invalid event: {}", reason),
+            RagError::Write(e) => write!(f, "Failed to write events file: {}", e),
+            RagError::Fetch { url, reason } => {
+                write!(f, "Failed to fetch events from {}: {}", url, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RagError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RagError::FileNotFound(_) | RagError::InvalidEvent(_) | RagError::Fetch { .. } => {
+                None
+            }
+            RagError::Read(e) | RagError::Write(e) => Some(e),
+            RagError::ParseError(e) => Some(e),
+            RagError::CsvError { source, .. } => Some(source),
+        }
+    }
+}
+
+/// Events read from a file, plus how many JSON entries were skipped as malformed and how
+/// many duplicates were dropped (see `dedup_events`).
+#[derive(Debug)]
+pub struct LoadedEvents {
+    pub events: Vec<Event>,
+    pub skipped: usize,
+    pub duplicates: usize,
+}
+
+/// Load events from a JSON array, or from a CSV file with a header row naming the `Event`
+/// fields when the extension is `.csv`. In JSON, entries that aren't valid events are
+/// logged and skipped rather than failing the whole file; CSV errors still fail with the
+/// line number.
+///
+/// A leading UTF-8 byte order mark (as some Windows editors save) is ignored, and CSV line
+/// endings are normalized to `\n`, so CRLF files don't leave `\r` in multi-line cells.
+/// Repeated entries are collapsed with `dedup_events`.
+///
+/// `events_path` may also be an http(s) URL (see `events_url`), fetched with `fetch_events`.
+pub fn load_events(events_path: &Path) -> Result<LoadedEvents, RagError> {
+    let bytes = read_events(events_path)?;
+    let bytes = bytes.strip_prefix(UTF8_BOM).unwrap_or(&bytes);
+    let mut loaded = if is_csv(events_path) {
+        let events = parse_csv_events(&normalize_line_endings(bytes))?;
+        LoadedEvents {
+            events,
+            skipped: 0,
+            duplicates: 0,
+        }
+    } else {
+        parse_json_events(bytes)?
+    };
+    loaded.duplicates = dedup_events(&mut loaded.events);
+    Ok(loaded)
+}
+
+/// The raw contents of the events file or URL.
+fn read_events(events_path: &Path) -> Result<Vec<u8>, RagError> {
+    match events_url(events_path) {
+        Some(url) => fetch_events(url),
+        None => std::fs::read(events_path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => RagError::FileNotFound(events_path.to_path_buf()),
+            _ => RagError::Read(e),
+        }),
+    }
+}
+
+/// Whether `events_path` names a CSV file; for a URL, by its path without the query or
+/// fragment, so ".../events.csv?token=x" is CSV too.
+fn is_csv(events_path: &Path) -> bool {
+    let path = match events_url(events_path) {
+        Some(url) => Path::new(url.split(['?', '#']).next().unwrap_or(url)),
+        None => events_path,
+    };
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv"))
+}
+
+/// A broken entry found by `validate_events`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct EntryError {
+    /// Position of the entry in the file, from 0 (for CSV, the data row after the header).
+    pub index: usize,
+    pub message: String,
+}
+
+/// Result of `validate_events`: `count` entries load, and `errors` lists the rest along
+/// with loadable entries that have no title or an unreadable date.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Validation {
+    pub valid: bool,
+    pub count: usize,
+    pub errors: Vec<EntryError>,
+}
+
+/// Check every entry of the events file the way `load_events` parses it, collecting what
+/// is wrong with each instead of skipping it. Only a file that can't be read at all, or
+/// JSON that isn't an array, is an error.
+pub fn validate_events(events_path: &Path) -> Result<Validation, RagError> {
+    let bytes = read_events(events_path)?;
+    let bytes = bytes.strip_prefix(UTF8_BOM).unwrap_or(&bytes);
+    let entries: Vec<Result<Event, String>> = if is_csv(events_path) {
+        csv_records(&normalize_line_endings(bytes))
+            .map(|record| record.map_err(|e| e.to_string()))
+            .collect()
+    } else {
+        let entries: Vec<serde_json::Value> =
+            serde_json::from_slice(bytes).map_err(RagError::ParseError)?;
+        entries
+            .into_iter()
+            .map(|entry| serde_json::from_value(entry).map_err(|e| e.to_string()))
+            .collect()
+    };
+    let mut count = 0;
+    let mut errors = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let problem = match entry {
+            Ok(event) => {
+                count += 1;
+                event_problem(&event)
+            }
+            Err(message) => Some(message),
+        };
+        if let Some(message) = problem {
+            errors.push(EntryError { index, message });
+        }
+    }
+    Ok(Validation {
+        valid: errors.is_empty(),
+        count,
+        errors,
+    })
+}
+
+/// Why `event` can't be added or answered about by date: no title, or a date
+/// `dates::parse_date` doesn't understand.
+fn event_problem(event: &Event) -> Option<String> {
+    if event.title.trim().is_empty() {
+        return Some("title is empty".into());
+    }
+    if dates::parse_date(&event.date).is_none() {
+        return Some(format!("unrecognized date: {}", event.date));
+    }
+    None
+}
+
+/// Drop events whose title (ignoring case and surrounding whitespace), date and start time
+/// match an earlier one; dates and times compare by the moment they name, so "3/1/2026" and
+/// "2026-03-01", or "2 PM" and "14:00", are the same. A recurring title at two times of the
+/// same day is two events. The first entry is kept, with any optional fields it lacks
+/// taken from the duplicates. Returns how many were dropped.
+pub fn dedup_events(events: &mut Vec<Event>) -> usize {
+    let key = |e: &Event| {
+        let date = dates::parse_date(&e.date)
+            .map_or_else(|| e.date.trim().to_string(), |d| d.to_string());
+        let start = e.start_time.as_deref().map(|time| {
+            dates::parse_time(time).map_or_else(|| time.trim().to_string(), |t| t.to_string())
+        });
+        (e.title.trim().to_lowercase(), date, start)
+    };
+    let before = events.len();
+    let mut kept: Vec<Event> = Vec::with_capacity(before);
+    let mut index: HashMap<(String, String, Option<String>), usize> = HashMap::new();
+    for event in events.drain(..) {
+        match index.get(&key(&event)) {
+            Some(&i) => {
+                let first = &mut kept[i];
+                first.location = first.location.take().or(event.location);
+                first.end_time = first.end_time.take().or(event.end_time);
+                first.tags = first.tags.take().or(event.tags);
+                first.priority = first.priority.or(event.priority);
+            }
+            None => {
+                index.insert(key(&event), kept.len());
+                kept.push(event);
+            }
+        }
+    }
+    *events = kept;
+    before - events.len()
+}
+
+/// Two events on the same day whose times overlap.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Conflict {
+    pub first: Event,
+    pub second: Event,
+}
+
+/// Pairs of events on the same date whose `start_time`..`end_time` ranges overlap, in file
+/// order. An event without an end time occupies just its start minute; events without a
+/// readable date or start time (all-day entries) never conflict.
+pub fn detect_conflicts(events: &[Event]) -> Vec<Conflict> {
+    let slot = |e: &Event| {
+        let date = dates::parse_date(&e.date)?;
+        let start = dates::parse_time(e.start_time.as_deref()?)?;
+        let end = e.end_time.as_deref().and_then(dates::parse_time);
+        let end = end
+            .filter(|end| *end > start)
+            .unwrap_or(start + chrono::Duration::minutes(1));
+        Some((date, start, end))
+    };
+    let slots: Vec<_> = events.iter().map(slot).collect();
+    let mut conflicts = Vec::new();
+    for (i, a) in slots.iter().enumerate() {
+        let Some((date, start, end)) = a else {
+            continue;
+        };
+        for (j, b) in slots.iter().enumerate().skip(i + 1) {
+            let Some((other_date, other_start, other_end)) = b else {
+                continue;
+            };
+            if date == other_date && start < other_end && other_start < end {
+                conflicts.push(Conflict {
+                    first: events[i].clone(),
+                    second: events[j].clone(),
+                });
+            }
+        }
+    }
+    conflicts
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// How long a fetched events URL is reused before it is downloaded again.
+const URL_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Timeout for downloading an events URL.
+const URL_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `events_path` as a URL when it is one ("http://" or "https://"); anything else is a
+/// file path.
+pub fn events_url(events_path: &Path) -> Option<&str> {
+    events_path.to_str().filter(|path| {
+        let lower = path.to_ascii_lowercase();
+        lower.starts_with("http://") || lower.starts_with("https://")
+    })
+}
+
+/// Fetched events URLs: when each was downloaded, and its body.
+type UrlCache = Mutex<HashMap<String, (Instant, Vec<u8>)>>;
+
+/// The body of `url`, fetched with a blocking GET and cached for `URL_CACHE_TTL`, since
+/// every request loads the events anew.
+fn fetch_events(url: &str) -> Result<Vec<u8>, RagError> {
+    static CACHE: OnceLock<UrlCache> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+    if let Ok(cache) = cache.lock() {
+        if let Some((fetched, bytes)) = cache.get(url) {
+            if fetched.elapsed() < URL_CACHE_TTL {
+                return Ok(bytes.clone());
+            }
+        }
+    }
+
+    let fetch_error = |reason: String| RagError::Fetch {
+        url: url.to_string(),
+        reason,
+    };
+    let response = reqwest::blocking::Client::builder()
+        .timeout(URL_FETCH_TIMEOUT)
+        .build()
+        .and_then(|client| client.get(url).send())
+        .map_err(|e| {
+            if e.is_timeout() {
+                fetch_error(format!("no response within {}s", URL_FETCH_TIMEOUT.as_secs()))
+            } else {
+                fetch_error(e.to_string())
+            }
+        })?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(fetch_error(format!("server returned {}", status)));
+    }
+    let bytes = response.bytes().map_err(|e| fetch_error(e.to_string()))?.to_vec();
+    log::info!("Fetched {} bytes of events from {}", bytes.len(), url);
+    if let Ok(mut cache) = cache.lock() {
+        cache.insert(url.to_string(), (Instant::now(), bytes.clone()));
+    }
+    Ok(bytes)
+}
+
+/// `\r\n` and lone `\r` become `\n`.
+fn normalize_line_endings(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter().peekable();
+    while let Some(&b) = iter.next() {
+        if b == b'\r' {
+            iter.next_if_eq(&&b'\n');
+            out.push(b'\n');
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+fn parse_json_events(bytes: &[u8]) -> Result<LoadedEvents, RagError> {
+    let entries: Vec<serde_json::Value> =
+        serde_json::from_slice(bytes).map_err(RagError::ParseError)?;
+    let mut loaded = LoadedEvents {
+        events: Vec::with_capacity(entries.len()),
+        skipped: 0,
+        duplicates: 0,
+    };
+    for (i, entry) in entries.into_iter().enumerate() {
+        match serde_json::from_value(entry) {
+            Ok(event) => loaded.events.push(event),
+            Err(e) => {
+                log::warn!("Skipping malformed event #{}: {}", i + 1, e);
+                loaded.skipped += 1;
+            }
+        }
+    }
+    Ok(loaded)
+}
+
+/// Empty optional cells (e.g. no location) load as None.
+fn parse_csv_events(bytes: &[u8]) -> Result<Vec<Event>, RagError> {
+    csv_records(bytes).collect()
+}
+
+/// Each CSV data row as an event, or the error for that row.
+fn csv_records(bytes: &[u8]) -> impl Iterator<Item = Result<Event, RagError>> + '_ {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::Headers)
+        .from_reader(bytes)
+        .into_deserialize()
+        .enumerate()
+        .map(|(i, record)| {
+            record.map_err(|source| {
+                let row = source.position().map_or(i + 2, |position| position.line() as usize);
+                RagError::CsvError { row, source }
+            })
+        })
+}
+
+/// Append `event` to the events file (JSON or CSV, as for `load_events`), creating the file
+/// if it doesn't exist, and return how many events it now holds. The event needs a title
+/// and a date `dates::parse_date` understands.
+///
+/// JSON entries are kept as they are, malformed ones included; a CSV file is rewritten
+/// with just the `Event` columns. The new contents go to a temporary file that then
+/// replaces the original, so a crash mid-write leaves the old file intact. Retrieval reads
+/// the file on every request, so the event is used from the next one.
+pub fn append_event(events_path: &Path, event: Event) -> Result<usize, RagError> {
+    if let Some(problem) = event_problem(&event) {
+        return Err(RagError::InvalidEvent(problem));
+    }
+    if let Some(url) = events_url(events_path) {
+        return Err(RagError::InvalidEvent(format!(
+            "events from {} are read-only; add them at the source",
+            url
+        )));
+    }
+    let bytes = match std::fs::read(events_path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(RagError::Read(e)),
+    };
+    let bytes = bytes.strip_prefix(UTF8_BOM).unwrap_or(&bytes);
+
+    let (contents, count) = if is_csv(events_path) {
+        let mut events = parse_csv_events(&normalize_line_endings(bytes))?;
+        events.push(event);
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for event in &events {
+            writer
+                .serialize(CsvEvent::from(event))
+                .map_err(|e| RagError::Write(e.into()))?;
+        }
+        let contents = writer
+            .into_inner()
+            .map_err(|e| RagError::Write(e.into_error()))?;
+        (contents, events.len())
+    } else {
+        let mut entries: Vec<serde_json::Value> = if bytes.is_empty() {
+            Vec::new()
+        } else {
+            serde_json::from_slice(bytes).map_err(RagError::ParseError)?
+        };
+        entries.push(serde_json::to_value(event).map_err(RagError::ParseError)?);
+        let contents = serde_json::to_vec_pretty(&entries).map_err(RagError::ParseError)?;
+        (contents, entries.len())
+    };
+    write_atomically(events_path, &contents).map_err(RagError::Write)?;
+    log::info!("Added an event to {} ({} total)", events_path.display(), count);
+    Ok(count)
+}
+
+/// Write to a sibling temporary file, flush it to disk and rename it over `path`.
+fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp_path, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp_path);
+    })
+}
+
+/// Scoring tunables, loaded from `rag_config.json` at startup or set with `configure_rag`.
+/// Keys missing from the file keep their defaults.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RagConfig {
+    /// Weight of a query word found in the title.
+    pub title_weight: f32,
+    /// Weight of a query word found in the description or location.
+    pub description_weight: f32,
+    /// Maximum edit distance at which an event word still matches a query word of at least
+    /// `FUZZY_MIN_LEN` characters; 0 keeps matching exact.
+    pub fuzziness: u8,
+    /// Rank with BM25 (term frequency, word rarity across events, event length) instead of
+    /// summing the weights of matched query words.
+    pub bm25: bool,
+    /// BM25 term-frequency saturation.
+    pub bm25_k1: f32,
+    /// BM25 length normalization: 0 ignores event length, 1 fully normalizes by it.
+    pub bm25_b: f32,
+    /// Bonus for matching events near the current date: `recency_weight / (1 + days)`,
+    /// `days` being the distance from `current_date` either way. 0 disables it, as does a
+    /// request without `current_date`.
+    pub recency_weight: f32,
+}
+
+impl Default for RagConfig {
+    fn default() -> Self {
+        Self {
+            title_weight: 1.0,
+            description_weight: 1.0,
+            fuzziness: 0,
+            bm25: false,
+            bm25_k1: 1.2,
+            bm25_b: 0.75,
+            recency_weight: 0.0,
+        }
+    }
+}
+
+const MAX_FIELD_WEIGHT: f32 = 10.0;
+const MAX_FUZZINESS: u8 = 2;
+const MAX_BM25_K1: f32 = 3.0;
+const MAX_RECENCY_WEIGHT: f32 = 10.0;
+
+impl RagConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let in_range = |name: &str, value: f32, max: f32| {
+            if value.is_finite() && (0.0..=max).contains(&value) {
+                Ok(())
+            } else {
+                Err(format!("{} must be between 0 and {} (got {})", name, max, value))
+            }
+        };
+        in_range("title_weight", self.title_weight, MAX_FIELD_WEIGHT)?;
+        in_range("description_weight", self.description_weight, MAX_FIELD_WEIGHT)?;
+        in_range("bm25_k1", self.bm25_k1, MAX_BM25_K1)?;
+        in_range("bm25_b", self.bm25_b, 1.0)?;
+        in_range("recency_weight", self.recency_weight, MAX_RECENCY_WEIGHT)?;
+        if self.fuzziness > MAX_FUZZINESS {
+            return Err(format!(
+                "fuzziness must be at most {} (got {})",
+                MAX_FUZZINESS, self.fuzziness
+            ));
+        }
+        Ok(())
+    }
+
+    /// Read and validate a config file. A missing file gives the defaults.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        let config: Self = serde_json::from_slice(&bytes)
+            .map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Knobs for keyword retrieval.
+#[derive(Debug, Clone, Copy)]
+pub struct SearchOptions {
+    /// Reduce query and event words to their stems so "running" matches "run".
+    pub stem: bool,
+    /// Split descriptions longer than this many characters (~200 works well) into chunks that
+    /// are scored separately, injecting only the best-matching chunks instead of whole
+    /// descriptions.
+    pub chunk_chars: Option<usize>,
+    /// Character budget for the formatted events block; see `format_hits_for_prompt`.
+    pub max_context_chars: Option<usize>,
+    /// Which fields each injected event shows.
+    pub verbosity: Verbosity,
+    /// The request's current date, for `RagConfig::recency_weight`; set by retrieval from
+    /// its `current_date`.
+    pub today: Option<chrono::NaiveDate>,
+    pub config: RagConfig,
+}
+
+/// How much of each event goes into the prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Verbosity {
+    /// "- Title (date time, location) description"
+    #[default]
+    Full,
+    /// "- Title (date time, location)", without the description.
+    Titles,
+    /// "- Title (date)"
+    Compact,
+}
+
+impl Verbosity {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "full" => Ok(Verbosity::Full),
+            "titles" => Ok(Verbosity::Titles),
+            "compact" => Ok(Verbosity::Compact),
+            other => Err(format!(
+                "Unknown context verbosity: {} (expected \"full\", \"titles\" or \"compact\")",
+                other
+            )),
+        }
+    }
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            stem: true,
+            chunk_chars: None,
+            max_context_chars: None,
+            verbosity: Verbosity::Full,
+            today: None,
+            config: RagConfig::default(),
+        }
+    }
+}
+
+/// A retrieval result: an event, optionally narrowed to the part of its description that
+/// matched (see `SearchOptions::chunk_chars`).
+#[derive(Debug, Clone, Copy)]
+pub struct Hit<'a> {
+    pub event: &'a Event,
+    pub excerpt: Option<&'a str>,
+}
+
+impl<'a> From<&'a Event> for Hit<'a> {
+    fn from(event: &'a Event) -> Self {
+        Hit {
+            event,
+            excerpt: None,
+        }
+    }
+}
+
+const STOPWORDS: &[&str] = &[
+    "a", "about", "above", "after", "again", "all", "am", "an", "and", "any", "are", "as", "at",
+    "be", "been", "before", "being", "below", "between", "both", "but", "by", "can", "could",
+    "did", "do", "does", "doing", "down", "during", "each", "few", "for", "from", "further",
+    "had", "has", "have", "having", "he", "her", "here", "hers", "him", "his", "how", "i", "if",
+    "in", "into", "is", "it", "its", "just", "me", "more", "most", "my", "no", "nor", "not",
+    "now", "of", "off", "on", "once", "only", "or", "other", "our", "ours", "out", "over", "own",
+    "same", "she", "should", "so", "some", "such", "than", "that", "the", "their", "them",
+    "then", "there", "these", "they", "this", "those", "through", "to", "too", "under", "until",
+    "up", "very", "was", "we", "were", "what", "when", "where", "which", "while", "who", "whom",
+    "why", "will", "with", "would", "you", "your", "yours",
+];
+
+fn is_vowel(c: u8) -> bool {
+    matches!(c, b'a' | b'e' | b'i' | b'o' | b'u')
+}
+
+/// Light suffix-stripping stemmer (plural "-s/-es/-ies", "-ing", "-ed", "-ly"), closer to
+/// Porter's step 1 than the full algorithm: enough to conflate "meetings"/"meeting"/"meet"
+/// and "running"/"run" without mangling short words.
+fn stem(word: &str) -> String {
+    let w = word.as_bytes();
+    if w.len() <= 3 || !w.iter().all(u8::is_ascii_lowercase) {
+        return word.to_string();
+    }
+    if let Some(base) = word.strip_suffix("ies") {
+        return format!("{}y", base);
+    }
+    if word.ends_with("sses") {
+        return word[..word.len() - 2].to_string();
+    }
+    if word.ends_with('s') && !word.ends_with("ss") && !word.ends_with("us") && !word.ends_with("is") {
+        return stem(&word[..word.len() - 1]);
+    }
+    for suffix in ["ing", "ed", "ly"] {
+        if let Some(base) = word.strip_suffix(suffix) {
+            let b = base.as_bytes();
+            if b.len() < 3 || !b.iter().copied().any(is_vowel) {
+                continue;
+            }
+            // "running" -> "runn" -> "run", but keep "fall", "miss", "buzz".
+            let n = b.len();
+            if b[n - 1] == b[n - 2] && !is_vowel(b[n - 1]) && !matches!(b[n - 1], b'l' | b's' | b'z') {
+                return base[..n - 1].to_string();
+            }
+            return base.to_string();
+        }
+    }
+    word.to_string()
+}
+
+/// One word as query and document words are compared: NFKC-normalized (so full-width and
+/// ligature forms match their plain spelling), lowercased, and without leading or trailing
+/// punctuation, so "Appointment," matches "appointment". Inner punctuation stays, keeping
+/// "2024-06-15" and "o'clock" whole.
+fn normalize_token(word: &str) -> String {
+    let mut normalized = tokenizers::NormalizedString::from(word);
+    normalized.nfkc();
+    normalized
+        .get()
+        .to_lowercase()
+        .trim_matches(|c: char| !c.is_alphanumeric())
+        .to_string()
+}
+
+/// Normalized words (see `normalize_token`) with stopwords removed, stemmed when
+/// `options.stem` is set.
+fn normalize_words(text: &str, options: &SearchOptions) -> Vec<String> {
+    text.split_whitespace()
+        .map(normalize_token)
+        .filter(|w| !w.is_empty() && !STOPWORDS.contains(&w.as_str()))
+        .map(|w| if options.stem { stem(&w) } else { w })
+        .collect()
+}
+
+/// An event's normalized words, split by how `RagConfig` weights them.
+struct Fields {
+    title: Vec<String>,
+    /// Description (or one chunk of it), location, tags and date (see
+    /// `dates::search_terms`).
+    body: Vec<String>,
+}
+
+impl Fields {
+    fn new(
+        title: &str,
+        description: &str,
+        location: Option<&str>,
+        tags: &[String],
+        date: &str,
+        options: &SearchOptions,
+    ) -> Self {
+        let body = format!(
+            "{} {} {} {}",
+            description,
+            location.unwrap_or_default(),
+            tags.join(" "),
+            dates::search_terms(date)
+        );
+        Fields {
+            title: normalize_words(title, options),
+            body: normalize_words(&body, options),
+        }
+    }
+
+    fn of(event: &Event, options: &SearchOptions) -> Self {
+        let (location, tags) = (event.location.as_deref(), event.tags.as_deref());
+        let tags = tags.unwrap_or_default();
+        Self::new(&event.title, &event.description, location, tags, &event.date, options)
+    }
+
+    fn len(&self) -> usize {
+        self.title.len() + self.body.len()
+    }
+}
+
+/// Query words shorter than this never match fuzzily; short words are too easy to hit.
+const FUZZY_MIN_LEN: usize = 4;
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1; b.len() + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != cb);
+            row[j + 1] = substitution.min(prev[j + 1] + 1).min(row[j] + 1);
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+/// Whether event word `word` matches query word `query`: it contains it ("meet" in
+/// "meeting"), or is within `fuzziness` edits of it.
+fn word_matches(word: &str, query: &str, fuzziness: u8) -> bool {
+    word.contains(query)
+        || (fuzziness > 0
+            && query.chars().count() >= FUZZY_MIN_LEN
+            && edit_distance(word, query) <= usize::from(fuzziness))
+}
+
+fn term_frequency(words: &[String], query: &str, fuzziness: u8) -> usize {
+    words.iter().filter(|w| word_matches(w, query, fuzziness)).count()
+}
+
+/// Scores events against one query under a `RagConfig`. For BM25 it holds the corpus
+/// statistics (word rarity, average event length) of the events it was built from.
+struct Scorer<'q> {
+    query_words: &'q [String],
+    config: RagConfig,
+    idf: Vec<f32>,
+    avg_len: f32,
+}
+
+impl<'q> Scorer<'q> {
+    fn new(events: &[Event], query_words: &'q [String], options: &SearchOptions) -> Self {
+        let config = options.config;
+        let mut scorer = Scorer {
+            query_words,
+            config,
+            idf: Vec::new(),
+            avg_len: 0.0,
+        };
+        if config.bm25 && !events.is_empty() {
+            let fields: Vec<Fields> = events.iter().map(|e| Fields::of(e, options)).collect();
+            let n = fields.len() as f32;
+            scorer.avg_len = fields.iter().map(Fields::len).sum::<usize>() as f32 / n;
+            scorer.idf = query_words
+                .iter()
+                .map(|q| {
+                    let df = fields.iter().filter(|f| scorer.tf(f, q) > 0.0).count() as f32;
+                    (1.0 + (n - df + 0.5) / (df + 0.5)).ln()
+                })
+                .collect();
+        }
+        scorer
+    }
+
+    /// Field-weighted number of occurrences of `query` in `fields`.
+    fn tf(&self, fields: &Fields, query: &str) -> f32 {
+        let fuzziness = self.config.fuzziness;
+        self.config.title_weight * term_frequency(&fields.title, query, fuzziness) as f32
+            + self.config.description_weight * term_frequency(&fields.body, query, fuzziness) as f32
+    }
+
+    fn score(&self, fields: &Fields) -> f32 {
+        if !self.config.bm25 {
+            return weighted_score(fields, self.query_words, &self.config);
+        }
+        let (k1, b) = (self.config.bm25_k1, self.config.bm25_b);
+        let len_norm = 1.0 - b + b * fields.len() as f32 / self.avg_len.max(1.0);
+        self.query_words
+            .iter()
+            .zip(&self.idf)
+            .map(|(q, idf)| {
+                let tf = self.tf(fields, q);
+                idf * tf * (k1 + 1.0) / (tf + k1 * len_norm)
+            })
+            .sum()
+    }
+
+    fn score_event(&self, event: &Event, options: &SearchOptions) -> f32 {
+        if !self.config.bm25 {
+            return score_event(event, self.query_words, options);
+        }
+        self.score(&Fields::of(event, options))
+    }
+}
+
+/// Each query word counts once, at the weight of the best field containing it.
+fn weighted_score(fields: &Fields, query_words: &[String], config: &RagConfig) -> f32 {
+    let fuzziness = config.fuzziness;
+    query_words
+        .iter()
+        .map(|q| {
+            let in_title = term_frequency(&fields.title, q, fuzziness) > 0;
+            let in_body = term_frequency(&fields.body, q, fuzziness) > 0;
+            match (in_title, in_body) {
+                (true, true) => config.title_weight.max(config.description_weight),
+                (true, false) => config.title_weight,
+                (false, true) => config.description_weight,
+                (false, false) => 0.0,
+            }
+        })
+        .sum()
+}
+
+/// The score `search_events` ranks `event` by when BM25 is off, for `query_words` from
+/// `query_words`: the title weight for each word in the title, the description weight for
+/// each word only in the description, location or date, 0 when nothing matches. Title
+/// matches and the recency bonus are applied on top by the search itself. BM25 scores
+/// depend on the other events too and need a `Scorer`.
+pub fn score_event(event: &Event, query_words: &[String], options: &SearchOptions) -> f32 {
+    weighted_score(&Fields::of(event, options), query_words, &options.config)
+}
+
+/// The query's normalized words (see `normalize_words`), without one-letter words.
+pub fn query_words(query: &str, options: &SearchOptions) -> Vec<String> {
+    normalize_words(query, options)
+        .into_iter()
+        .filter(|s| s.len() > 1)
+        .collect()
+}
+
+/// Split `text` into pieces of roughly `size` characters, breaking at whitespace.
+fn chunk_text(text: &str, size: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let rest = &text[start..];
+        if rest.chars().count() <= size {
+            chunks.push(rest.trim());
+            break;
+        }
+        let limit = rest.char_indices().nth(size).map(|(i, _)| i).unwrap_or(rest.len());
+        let end = rest[..limit]
+            .rfind(char::is_whitespace)
+            .filter(|&i| i > 0)
+            .unwrap_or(limit);
+        chunks.push(rest[..end].trim());
+        start += end;
+        start += text[start..].len() - text[start..].trim_start().len();
+    }
+    chunks.retain(|c| !c.is_empty());
+    chunks
+}
+
+/// Like `search_events`, but long descriptions are split into `chunk_chars` chunks and every
+/// chunk is scored on its own (together with the event's title and location) and adjusted
+/// as in `search_events_scored` (see `ranking_score`). The best chunks overall are returned,
+/// so one event may contribute several; chunks of events whose title matches the query
+/// (see `title_matches`) come first.
+pub fn search_event_chunks<'a>(
+    events: &'a [Event],
+    query: &str,
+    limit: usize,
+    offset: usize,
+    chunk_chars: usize,
+    options: &SearchOptions,
+) -> Vec<Hit<'a>> {
+    let query_words = query_words(query, options);
     if query_words.is_empty() {
-        return events.iter().take(limit).collect();
+        return events.iter().skip(offset).take(limit).map(Hit::from).collect();
+    }
+    let scorer = Scorer::new(events, &query_words, options);
+    let mut scored: Vec<(bool, f32, Hit)> = Vec::new();
+    for event in events {
+        let title = title_matches(event, query);
+        let chunks = chunk_text(&event.description, chunk_chars);
+        if chunks.len() <= 1 {
+            let score = ranking_score(scorer.score_event(event, options), event, options);
+            scored.push((title, score, Hit::from(event)));
+            continue;
+        }
+        for chunk in chunks {
+            let (location, tags) = (event.location.as_deref(), event.tags.as_deref());
+            let tags = tags.unwrap_or_default();
+            let fields = Fields::new(&event.title, chunk, location, tags, &event.date, options);
+            let hit = Hit {
+                event,
+                excerpt: Some(chunk),
+            };
+            let score = ranking_score(scorer.score(&fields), event, options);
+            scored.push((title, score, hit));
+        }
     }
-    let mut scored: Vec<(usize, &Event)> = events
+    scored.retain(|(title, score, _)| *title || *score > 0.0);
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.total_cmp(&a.1)));
+    scored.into_iter().skip(offset).take(limit).map(|(_, _, hit)| hit).collect()
+}
+
+/// The shorter side of a `title_matches` match needs at least this many letters, so a
+/// query like "a" or "to" doesn't pull titles up.
+const TITLE_MATCH_MIN_LEN: usize = 3;
+
+/// Whether the event's title appears in the query or the query in the title as whole
+/// words, ignoring case and punctuation, as in "tell me about my Dentist Appointment".
+/// "Gym" doesn't match a query about "gymnastics".
+fn title_matches(event: &Event, query: &str) -> bool {
+    let title = lowercase_words(&event.title);
+    let query = lowercase_words(query);
+    let long_enough = |words: &[String]| {
+        words.iter().map(|w| w.chars().count()).sum::<usize>() >= TITLE_MATCH_MIN_LEN
+    };
+    (long_enough(&title) && contains_words(&query, &title))
+        || (long_enough(&query) && contains_words(&title, &query))
+}
+
+fn lowercase_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Whether `needle` occurs in `haystack` as a run of consecutive words.
+fn contains_words(haystack: &[String], needle: &[String]) -> bool {
+    !needle.is_empty() && haystack.windows(needle.len()).any(|words| words == needle)
+}
+
+/// Events scored against the query under `options.config`, best first. `offset` skips
+/// that many results, so callers can page through matches `limit` at a time.
+/// Events whose title matches the query (see `title_matches`) come before all others,
+/// whatever their score.
+pub fn search_events<'a>(
+    events: &'a [Event],
+    query: &str,
+    limit: usize,
+    offset: usize,
+    options: &SearchOptions,
+) -> Vec<&'a Event> {
+    search_events_scored(events, query, limit, offset, options)
+        .into_iter()
+        .map(|(_, e)| e)
+        .collect()
+}
+
+/// `search_events` with each event's relevance: its score divided by the best score among
+/// the returned events, so 1.0 is the strongest match in the set. Scores are scaled by
+/// `Event::priority_factor`, so of two equally good matches the higher priority ranks
+/// first. A query with no search words matches nothing in particular, and every event
+/// gets 0.
+pub fn search_events_scored<'a>(
+    events: &'a [Event],
+    query: &str,
+    limit: usize,
+    offset: usize,
+    options: &SearchOptions,
+) -> Vec<(f32, &'a Event)> {
+    let query_words = query_words(query, options);
+    if query_words.is_empty() {
+        return events.iter().skip(offset).take(limit).map(|e| (0.0, e)).collect();
+    }
+    let scorer = Scorer::new(events, &query_words, options);
+    let mut scored: Vec<(bool, f32, &Event)> = events
         .iter()
         .map(|e| {
-            let text = event_searchable_text(e);
-            let matches = query_words.iter().filter(|w| text.contains(*w)).count();
-            (matches, e)
+            let score = ranking_score(scorer.score_event(e, options), e, options);
+            (title_matches(e, query), score, e)
         })
-        .filter(|(n, _)| *n > 0)
+        .filter(|(title, score, _)| *title || *score > 0.0)
         .collect();
-    scored.sort_by(|a, b| b.0.cmp(&a.0));
-    scored.into_iter().take(limit).map(|(_, e)| e).collect()
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.total_cmp(&a.1)));
+    let mut page: Vec<(f32, &Event)> = scored
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|(_, score, e)| (score, e))
+        .collect();
+    normalize_scores(&mut page);
+    page
+}
+
+/// A keyword `score` with the event's `recency_bonus` added and scaled by its
+/// `Event::priority_factor`. No keyword match stays 0, so neither makes an unrelated event
+/// relevant.
+fn ranking_score(score: f32, event: &Event, options: &SearchOptions) -> f32 {
+    if score > 0.0 {
+        (score + recency_bonus(event, options)) * event.priority_factor()
+    } else {
+        0.0
+    }
+}
+
+/// `RagConfig::recency_weight` scaled down by the event's distance in days from
+/// `options.today`; 0 without a current date or a parseable event date.
+fn recency_bonus(event: &Event, options: &SearchOptions) -> f32 {
+    let weight = options.config.recency_weight;
+    let (Some(today), true) = (options.today, weight > 0.0) else {
+        return 0.0;
+    };
+    match dates::parse_date(&event.date) {
+        Some(date) => weight / (1.0 + (date - today).num_days().abs() as f32),
+        None => 0.0,
+    }
 }
 
-pub fn format_events_for_prompt(events: &[&Event]) -> String {
-    if events.is_empty() {
-        return String::from("(No relevant events found.)");
+/// Divide each score by the largest, leaving all zeros alone.
+fn normalize_scores<T>(scored: &mut [(f32, T)]) {
+    let max = scored.iter().map(|(score, _)| *score).fold(0.0, f32::max);
+    if max > 0.0 {
+        for (score, _) in scored.iter_mut() {
+            *score /= max;
+        }
     }
-    events
+}
+
+/// Events dated within `range`, best keyword matches first and then by date. Unlike
+/// `search_events`, events with no keyword overlap are kept: the date phrase already
+/// makes them relevant ("what do I have this weekend"), so they get the recency bonus
+/// too. Events whose title matches the query (see `title_matches`) come first.
+pub fn search_events_in_range<'a>(
+    events: &'a [Event],
+    query: &str,
+    range: dates::DateRange,
+    limit: usize,
+    offset: usize,
+    options: &SearchOptions,
+) -> Vec<&'a Event> {
+    let query_words = query_words(query, options);
+    let scorer = Scorer::new(events, &query_words, options);
+    let mut in_range: Vec<(bool, f32, chrono::NaiveDate, &Event)> = events
+        .iter()
+        .filter_map(|e| {
+            let date = dates::parse_date(&e.date).filter(|date| range.contains(*date))?;
+            let score = scorer.score_event(e, options) + recency_bonus(e, options);
+            Some((title_matches(e, query), score * e.priority_factor(), date, e))
+        })
+        .collect();
+    in_range.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.total_cmp(&a.1)).then(a.2.cmp(&b.2)));
+    in_range.into_iter().skip(offset).take(limit).map(|(_, _, _, e)| e).collect()
+}
+
+/// The next `n` events on or after `today` (after skipping `offset`), soonest first. Events
+/// with unparseable dates are skipped.
+pub fn upcoming_events(
+    events: &[Event],
+    today: chrono::NaiveDate,
+    n: usize,
+    offset: usize,
+) -> Vec<&Event> {
+    let mut upcoming: Vec<(chrono::NaiveDate, &Event)> = events
         .iter()
-        .map(|e| format!("- {} ({}) {}", e.title, e.date, e.description))
-        .collect::<Vec<_>>()
-        .join("\n")
+        .filter_map(|e| Some((dates::parse_date(&e.date)?, e)))
+        .filter(|(date, _)| *date >= today)
+        .collect();
+    upcoming.sort_by_key(|(date, _)| *date);
+    upcoming.into_iter().skip(offset).take(n).map(|(_, e)| e).collect()
+}
+
+/// Words that mark a question about the schedule in general rather than a specific event.
+const SCHEDULE_WORDS: &[&str] = &[
+    "agenda", "calendar", "coming", "event", "events", "next", "plan", "plans", "schedule",
+    "soon", "upcoming",
+];
+
+/// For a generic schedule question ("what's on my schedule?", "any upcoming events?"),
+/// the rest of the query once schedule words are removed; None if it isn't one.
+fn strip_schedule_words(query: &str) -> Option<String> {
+    let lower = query.to_lowercase();
+    let words: Vec<&str> = lower
+        .split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .filter(|w| !w.is_empty())
+        .collect();
+    if !words.iter().any(|w| SCHEDULE_WORDS.contains(w)) {
+        return None;
+    }
+    Some(
+        words
+            .into_iter()
+            .filter(|w| !SCHEDULE_WORDS.contains(w))
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+/// "3/2/2026 10:00-11:00, Downtown Clinic": the date plus whichever of the time range and
+/// location are present.
+fn event_when_where(event: &Event) -> String {
+    let when = match (&event.start_time, &event.end_time) {
+        (Some(start), Some(end)) => format!("{} {}-{}", event.date, start, end),
+        (Some(start), None) => format!("{} {}", event.date, start),
+        (None, Some(end)) => format!("{} until {}", event.date, end),
+        (None, None) => event.date.clone(),
+    };
+    match &event.location {
+        Some(location) => format!("{}, {}", when, location),
+        None => when,
+    }
+}
+
+const OMITTED_NOTE: &str = "(additional events omitted)";
+
+/// One event as a prompt line: title, when and where, then the description or the matched
+/// excerpt, cut down according to `verbosity`.
+fn format_hit(hit: &Hit, verbosity: Verbosity) -> String {
+    let e = hit.event;
+    match verbosity {
+        Verbosity::Full => {}
+        Verbosity::Titles => return format!("- {} ({})", e.title, event_when_where(e)),
+        Verbosity::Compact => return format!("- {} ({})", e.title, e.date),
+    }
+    let description = match hit.excerpt {
+        Some(excerpt) => {
+            let at_start = e.description.trim_start().starts_with(excerpt);
+            let at_end = e.description.trim_end().ends_with(excerpt);
+            format!(
+                "{}{}{}",
+                if at_start { "" } else { "..." },
+                excerpt,
+                if at_end { "" } else { "..." }
+            )
+        }
+        None => e.description.clone(),
+    };
+    format!("- {} ({}) {}", e.title, event_when_where(e), description)
+}
+
+/// One line per hit. Excerpts keep the event's title/date prefix and are marked with "..."
+/// where the description was cut.
+///
+/// With `max_chars`, a block that would be longer keeps only as many of the leading (best
+/// ranked) lines as fit together with a closing "(additional events omitted)" line. The
+/// note is left out when the budget is too small for it, so the block never exceeds
+/// `max_chars`.
+pub fn format_hits_for_prompt(
+    hits: &[Hit],
+    max_chars: Option<usize>,
+    verbosity: Verbosity,
+) -> String {
+    let lines: Vec<String> = hits.iter().map(|hit| format_hit(hit, verbosity)).collect();
+    let block = lines.join("\n");
+    let budget = match max_chars {
+        Some(budget) if block.chars().count() > budget => budget,
+        _ => return block,
+    };
+
+    let note = Some(OMITTED_NOTE).filter(|note| note.chars().count() <= budget);
+    let mut used = note.map_or(0, |note| note.chars().count());
+    let mut kept: Vec<&str> = Vec::new();
+    for line in &lines {
+        let len = line.chars().count() + 1;
+        if used + len > budget {
+            break;
+        }
+        used += len;
+        kept.push(line);
+    }
+    log::info!(
+        "Events block over {} chars; keeping {} of {} events",
+        budget,
+        kept.len(),
+        lines.len()
+    );
+    kept.extend(note);
+    kept.join("\n")
+}
+
+fn is_generic_schedule_query(events: &[Event], query: &str, options: &SearchOptions) -> bool {
+    match strip_schedule_words(query) {
+        Some(topic) => {
+            let words = query_words(&topic, options);
+            words.is_empty() || search_events(events, &topic, 1, 0, options).is_empty()
+        }
+        None => false,
+    }
+}
+
+/// Events from `events_path`, logging any malformed entries that were skipped and any
+/// duplicates dropped.
+fn load_logged(events_path: &Path) -> Result<Vec<Event>, RagError> {
+    let LoadedEvents {
+        events,
+        skipped,
+        duplicates,
+    } = load_events(events_path)?;
+    if skipped > 0 {
+        log::warn!("Skipped {} malformed event(s) in {}", skipped, events_path.display());
+    }
+    if duplicates > 0 {
+        log::info!("Dropped {} duplicate event(s) in {}", duplicates, events_path.display());
+    }
+    Ok(events)
+}
+
+/// `detect_conflicts` over the events in `events_path`.
+pub fn find_conflicts(events_path: &Path) -> Result<Vec<Conflict>, RagError> {
+    Ok(detect_conflicts(&load_logged(events_path)?))
+}
+
+/// Figures about an events file for diagnosing poor retrieval: few or undated events,
+/// empty descriptions, a small vocabulary, or nothing after today.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CorpusStats {
+    pub events: usize,
+    pub empty_descriptions: usize,
+    /// Mean description length in characters.
+    pub average_description_chars: f64,
+    /// Distinct searchable words (normalized and stemmed as for retrieval, stopwords left
+    /// out) across titles, descriptions, locations and tags.
+    pub vocabulary_size: usize,
+    /// Earliest and latest readable dates, as YYYY-MM-DD.
+    pub first_date: Option<String>,
+    pub last_date: Option<String>,
+    /// Events whose date `dates::parse_date` can't read; date questions never find them.
+    pub undated: usize,
+    /// Events on or after `today`, when it was given.
+    pub upcoming: Option<usize>,
+}
+
+/// `CorpusStats` for `events`, counting `upcoming` from `today`.
+pub fn corpus_stats(events: &[Event], today: Option<chrono::NaiveDate>) -> CorpusStats {
+    let options = SearchOptions::default();
+    let mut vocabulary = std::collections::HashSet::new();
+    let mut dates = Vec::with_capacity(events.len());
+    let mut description_chars = 0;
+    let mut empty_descriptions = 0;
+    for event in events {
+        let chars = event.description.trim().chars().count();
+        description_chars += chars;
+        if chars == 0 {
+            empty_descriptions += 1;
+        }
+        let tags = event.tags.as_deref().unwrap_or_default().join(" ");
+        let location = event.location.as_deref().unwrap_or_default();
+        for text in [event.title.as_str(), &event.description, location, &tags] {
+            vocabulary.extend(normalize_words(text, &options));
+        }
+        dates.extend(dates::parse_date(&event.date));
+    }
+    CorpusStats {
+        events: events.len(),
+        empty_descriptions,
+        average_description_chars: description_chars as f64 / events.len().max(1) as f64,
+        vocabulary_size: vocabulary.len(),
+        first_date: dates.iter().min().map(|d| d.to_string()),
+        last_date: dates.iter().max().map(|d| d.to_string()),
+        undated: events.len() - dates.len(),
+        upcoming: today.map(|today| dates.iter().filter(|&&d| d >= today).count()),
+    }
+}
+
+/// `corpus_stats` over the events in `events_path`.
+pub fn rag_stats(
+    events_path: &Path,
+    today: Option<chrono::NaiveDate>,
+) -> Result<CorpusStats, RagError> {
+    Ok(corpus_stats(&load_logged(events_path)?, today))
+}
+
+/// With `tags`, only the events carrying at least one of them (see `Event::has_any_tag`),
+/// so scoring and every retrieval mode see just those.
+fn filter_by_tags(events: Vec<Event>, tags: Option<&[String]>) -> Vec<Event> {
+    match tags.filter(|tags| !tags.is_empty()) {
+        Some(tags) => events.into_iter().filter(|e| e.has_any_tag(tags)).collect(),
+        None => events,
+    }
+}
+
+/// How many events retrieval keeps at each stage.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Best matches kept from scoring, to be re-ranked.
+    pub candidates: usize,
+    /// How many of the re-ranked candidates are returned (and injected).
+    pub inject: usize,
+    /// Matches skipped before the candidates, so callers can page through results.
+    pub offset: usize,
+}
+
+/// Stable sort by distance in days from `today`, so among equally near events the better
+/// match stays first. Events whose date doesn't parse go last.
+fn rerank_by_date(hits: &mut [Hit], today: chrono::NaiveDate) {
+    hits.sort_by_key(|hit| {
+        dates::parse_date(&hit.event.date).map_or(i64::MAX, |date| (date - today).num_days().abs())
+    });
 }
 
-pub fn retrieve_context(events_path: &Path, query: &str, limit: usize) -> Result<String, String> {
-    let events = load_events(events_path)?;
-    let relevant = search_events(&events, query, limit);
-    Ok(format_events_for_prompt(&relevant))
+/// Scores how relevant an event (as its prompt line) is to the query; higher is better.
+/// None when no score could be had, which ranks the event after all scored ones.
+pub type Reranker<'r> = &'r dyn Fn(&str, &str) -> Option<f32>;
+
+/// Stable sort by `rerank`'s score, best first.
+fn rerank_with(hits: &mut Vec<Hit>, query: &str, rerank: Reranker) {
+    let mut scored: Vec<(Option<f32>, Hit)> = hits
+        .drain(..)
+        .map(|hit| (rerank(query, &format_hit(&hit, Verbosity::Full)), hit))
+        .collect();
+    scored.sort_by(|(a, _), (b, _)| match (a, b) {
+        (Some(a), Some(b)) => b.total_cmp(a),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    });
+    hits.extend(scored.into_iter().map(|(_, hit)| hit));
+}
+
+/// When `current_date` is given and the query contains a date phrase ("this weekend",
+/// "in July"), only events in that range are considered; otherwise plain keyword search.
+/// A generic schedule question with no keyword match beyond the schedule words themselves
+/// gets the next upcoming events instead.
+///
+/// The first `limits.candidates` results are then re-ranked by how close they are to
+/// `current_date` (when given) and cut to `limits.inject`. With as many candidates as
+/// injected events, the ranking is left as is. A `rerank` function replaces the date
+/// re-ranking and is applied to the candidates even when there are no more of them than
+/// are injected.
+fn select_hits<'a>(
+    events: &'a [Event],
+    query: &str,
+    current_date: Option<&str>,
+    limits: Limits,
+    options: &SearchOptions,
+    rerank: Option<Reranker>,
+) -> Vec<Hit<'a>> {
+    let today = current_date.and_then(dates::parse_date);
+    let options = &SearchOptions { today, ..*options };
+    let range = today.and_then(|today| dates::parse_date_range(query, today));
+    let (limit, offset) = (limits.candidates.max(limits.inject), limits.offset);
+    let mut hits: Vec<Hit> = match (range, today) {
+        (Some(range), _) => {
+            log::info!("Restricting retrieval to {} ..= {}", range.start, range.end);
+            search_events_in_range(events, query, range, limit, offset, options)
+                .into_iter()
+                .map(Hit::from)
+                .collect()
+        }
+        (None, Some(today)) if is_generic_schedule_query(events, query, options) => {
+            log::info!("Generic schedule query; using upcoming events");
+            upcoming_events(events, today, limit, offset)
+                .into_iter()
+                .map(Hit::from)
+                .collect()
+        }
+        (None, _) => match options.chunk_chars {
+            Some(size) => search_event_chunks(events, query, limit, offset, size, options),
+            None => search_events(events, query, limit, offset, options)
+                .into_iter()
+                .map(Hit::from)
+                .collect(),
+        },
+    };
+    match (rerank, today) {
+        (Some(rerank), _) => {
+            log::info!("Re-ranking {} candidates", hits.len());
+            rerank_with(&mut hits, query, rerank);
+        }
+        (None, Some(today)) if limits.candidates > limits.inject => {
+            rerank_by_date(&mut hits, today);
+        }
+        _ => {}
+    }
+    hits.truncate(limits.inject);
+    hits
+}
+
+/// Retrieved events (see `select_hits`) formatted for the prompt, from the events tagged
+/// with one of `filter_tags` when given. Returns None when no event matched, so the caller
+/// can decide how to tell the model.
+pub fn retrieve_context(
+    events_path: &Path,
+    query: &str,
+    current_date: Option<&str>,
+    filter_tags: Option<&[String]>,
+    limits: Limits,
+    options: &SearchOptions,
+    rerank: Option<Reranker>,
+) -> Result<Option<String>, RagError> {
+    let events = filter_by_tags(load_logged(events_path)?, filter_tags);
+    let hits = select_hits(&events, query, current_date, limits, options, rerank);
+    if hits.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(format_hits_for_prompt(
+        &hits,
+        options.max_context_chars,
+        options.verbosity,
+    )))
+}
+
+/// A retrieved event with how strongly it matched the query, for showing sources.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Source {
+    #[serde(flatten)]
+    pub event: Event,
+    /// Keyword score relative to the best-scoring returned event, from 0 to 1 (see
+    /// `search_events_scored`). Events picked by date alone can score 0.
+    pub relevance: f32,
+}
+
+/// The events `retrieve_context` would inject for `query`, whole rather than formatted.
+pub fn retrieve_events(
+    events_path: &Path,
+    query: &str,
+    current_date: Option<&str>,
+    filter_tags: Option<&[String]>,
+    limits: Limits,
+    options: &SearchOptions,
+) -> Result<Vec<Source>, RagError> {
+    let events = filter_by_tags(load_logged(events_path)?, filter_tags);
+    let options = SearchOptions {
+        chunk_chars: None,
+        ..*options
+    };
+    let hits = select_hits(&events, query, current_date, limits, &options, None);
+    // Re-score the selected events, since date and re-ranking passes don't keep scores.
+    let query_words = query_words(query, &options);
+    let scorer = Scorer::new(&events, &query_words, &options);
+    let mut scored: Vec<(f32, &Event)> = hits
+        .into_iter()
+        .map(|hit| (scorer.score_event(hit.event, &options), hit.event))
+        .collect();
+    normalize_scores(&mut scored);
+    Ok(scored
+        .into_iter()
+        .map(|(relevance, event)| Source {
+            event: event.clone(),
+            relevance,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(title: &str, description: &str) -> Event {
+        Event {
+            title: title.to_string(),
+            date: "2025-03-01".to_string(),
+            description: description.to_string(),
+            location: None,
+            start_time: None,
+            end_time: None,
+            tags: None,
+            priority: None,
+        }
+    }
+
+    #[test]
+    fn titled_query_returns_that_event_first() {
+        let events = vec![
+            event(
+                "Team Lunch",
+                "Tell everyone about the new appointment system over lunch with the team",
+            ),
+            event("Dentist Appointment", "Checkup"),
+            event("Appointment Review", "Tell me about appointment metrics"),
+        ];
+        let query = "tell me about my Dentist Appointment";
+        let options = SearchOptions::default();
+        let hits = search_events(&events, query, 3, 0, &options);
+        assert_eq!(hits[0].title, "Dentist Appointment");
+        let hits = search_event_chunks(&events, query, 3, 0, 30, &options);
+        assert_eq!(hits[0].event.title, "Dentist Appointment");
+    }
+
+    #[test]
+    fn title_matches_whole_words_only() {
+        let gym = event("Gym", "Leg day");
+        assert!(title_matches(&gym, "when is gym?"));
+        assert!(!title_matches(&gym, "gymnastics class"));
+        let dentist = event("Dentist Appointment", "Checkup");
+        assert!(title_matches(&dentist, "DENTIST"));
+        assert!(title_matches(&dentist, "my dentist appointment, please"));
+        assert!(!title_matches(&dentist, "den"));
+        assert!(!title_matches(&event("Go", "Board game"), "let's go"));
+        assert!(!title_matches(&event("Dentist", "Checkup"), "a"));
+    }
+
+    fn titles<'a>(hits: &[&'a Event]) -> Vec<&'a str> {
+        hits.iter().map(|e| e.title.as_str()).collect()
+    }
+
+    #[test]
+    fn empty_query_returns_events_in_file_order() {
+        let events = vec![event("Dentist", "Checkup"), event("Gym", "Leg day")];
+        let options = SearchOptions::default();
+        assert_eq!(titles(&search_events(&events, "", 5, 0, &options)), ["Dentist", "Gym"]);
+        // Only stopwords and punctuation: nothing to search for either.
+        let scored = search_events_scored(&events, "the, a ?", 1, 0, &options);
+        assert_eq!(scored.len(), 1);
+        assert_eq!(scored[0].0, 0.0);
+    }
+
+    #[test]
+    fn unmatched_query_returns_nothing() {
+        let events = vec![event("Dentist", "Checkup"), event("Gym", "Leg day")];
+        let hits = search_events(&events, "piano recital", 5, 0, &SearchOptions::default());
+        assert!(hits.is_empty());
+    }
+
+    #[test]
+    fn equal_scores_keep_file_order() {
+        let events = vec![
+            event("Yoga", "Morning class"),
+            event("Pottery", "Evening class"),
+            event("Choir", "Weekly class"),
+        ];
+        let hits = search_events(&events, "class", 5, 0, &SearchOptions::default());
+        assert_eq!(titles(&hits), ["Yoga", "Pottery", "Choir"]);
+    }
+
+    #[test]
+    fn better_match_ranks_first() {
+        let events = vec![
+            event("Call", "Call the plumber about the leak"),
+            event("Plumber visit", "Fix the kitchen leak"),
+        ];
+        let hits = search_events(&events, "plumber kitchen leak", 5, 0, &SearchOptions::default());
+        assert_eq!(titles(&hits), ["Plumber visit", "Call"]);
+    }
+
+    #[test]
+    fn limit_and_offset_page_through_results() {
+        let events: Vec<Event> = (1..=5)
+            .map(|i| event(&format!("Meeting {}", i), "Project sync"))
+            .collect();
+        let options = SearchOptions::default();
+        assert_eq!(
+            titles(&search_events(&events, "sync", 2, 0, &options)),
+            ["Meeting 1", "Meeting 2"]
+        );
+        assert_eq!(titles(&search_events(&events, "sync", 2, 4, &options)), ["Meeting 5"]);
+        assert!(search_events(&events, "sync", 0, 0, &options).is_empty());
+        assert!(search_events(&events, "sync", 2, 9, &options).is_empty());
+    }
+
+    #[test]
+    fn score_event_weights_fields() {
+        let options = SearchOptions {
+            config: RagConfig {
+                title_weight: 2.0,
+                description_weight: 0.5,
+                ..RagConfig::default()
+            },
+            ..SearchOptions::default()
+        };
+        let e = event("Dentist appointment", "Checkup with the dentist, bring x-rays");
+        let score = |query: &str| score_event(&e, &query_words(query, &options), &options);
+        // "dentist" is in both fields and counts once, at the title weight.
+        assert_eq!(score("dentist"), 2.0);
+        assert_eq!(score("checkup"), 0.5);
+        assert_eq!(score("dentist checkup"), 2.5);
+        assert_eq!(score("march"), 0.5, "the event's month is searchable");
+        assert_eq!(score("haircut"), 0.0);
+        assert_eq!(score(""), 0.0);
+    }
+
+    #[test]
+    fn priority_ranks_equal_chunk_matches() {
+        let description = "Bring the insurance forms and arrive early. ".repeat(8);
+        let mut low = event("Clinic A", &description);
+        low.priority = Some(1);
+        let mut high = event("Clinic B", &description);
+        high.priority = Some(5);
+        let events = vec![low, high];
+        let options = SearchOptions::default();
+        let hits = search_event_chunks(&events, "insurance forms", 1, 0, 100, &options);
+        assert!(hits[0].excerpt.is_some(), "descriptions are chunked");
+        assert_eq!(hits[0].event.title, "Clinic B");
+        let hits = search_events(&events, "insurance forms", 2, 0, &options);
+        assert_eq!(titles(&hits), ["Clinic B", "Clinic A"]);
+    }
+
+    #[test]
+    fn rerank_orders_by_score_with_unscored_last() {
+        let events = [
+            event("Lunch", "With Sam"),
+            event("Dentist", "Checkup"),
+            event("Call", "Mom"),
+            event("Gym", "Leg day"),
+        ];
+        let mut hits: Vec<Hit> = events.iter().map(Hit::from).collect();
+        let rerank = |query: &str, line: &str| {
+            assert_eq!(query, "workout");
+            assert!(line.starts_with("- "), "scored as its prompt line: {:?}", line);
+            match line {
+                _ if line.contains("Gym") => Some(9.0),
+                _ if line.contains("Dentist") => Some(2.0),
+                _ => None,
+            }
+        };
+        rerank_with(&mut hits, "workout", &rerank);
+        let titles: Vec<&str> = hits.iter().map(|hit| hit.event.title.as_str()).collect();
+        assert_eq!(titles, ["Gym", "Dentist", "Lunch", "Call"]);
+    }
+
+    #[test]
+    fn events_block_stays_within_budget() {
+        let events = [
+            event("Dentist", "Checkup at the clinic"),
+            event("Gym", "Leg day"),
+            event("Lunch", "With Sam downtown"),
+        ];
+        let hits: Vec<Hit> = events.iter().map(Hit::from).collect();
+        let format = |max_chars| format_hits_for_prompt(&hits, max_chars, Verbosity::Full);
+        let full = format(None);
+        assert_eq!(full.lines().count(), 3);
+        assert_eq!(format(Some(full.chars().count())), full);
+
+        let first = full.lines().next().unwrap();
+        let budget = first.chars().count() + 1 + OMITTED_NOTE.chars().count();
+        assert_eq!(format(Some(budget)), format!("{}\n{}", first, OMITTED_NOTE));
+
+        let tiny = OMITTED_NOTE.chars().count() - 1;
+        let block = format(Some(tiny));
+        assert!(!block.contains(OMITTED_NOTE));
+        assert!(block.chars().count() <= tiny, "{:?} is over {} chars", block, tiny);
+    }
+
+    fn tagged(title: &str, description: &str, tags: &[&str]) -> Event {
+        let tags = Some(tags.iter().map(|tag| tag.to_string()).collect());
+        Event { tags, ..event(title, description) }
+    }
+
+    #[test]
+    fn tags_load_from_a_list_or_separated_text() {
+        let tags = |json: &str| {
+            let base = r#""title": "Gym", "date": "2025-03-01", "description": "Legs""#;
+            serde_json::from_str::<Event>(&format!("{{{}{}}}", base, json)).unwrap().tags
+        };
+        let expected = Some(vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(tags(r#", "tags": "a; b""#), expected);
+        assert_eq!(tags(r#", "tags": "a, ,b;""#), expected);
+        assert_eq!(tags(r#", "tags": ["a", " ", "b "]"#), expected);
+        assert_eq!(tags(r#", "tags": " ; ""#), None);
+        assert_eq!(tags(r#", "tags": []"#), None);
+        assert_eq!(tags(r#", "tags": null"#), None);
+        assert_eq!(tags(""), None);
+
+        let csv = "title,date,description,tags\nGym,2025-03-01,Legs,\"work; health\"\n";
+        let events = parse_csv_events(csv.as_bytes()).unwrap();
+        assert_eq!(events[0].tags, Some(vec!["work".to_string(), "health".to_string()]));
+    }
+
+    #[test]
+    fn tag_filter_ignores_case_and_tags_are_searchable() {
+        let events = vec![
+            tagged("Dentist", "Checkup appointment", &["Health"]),
+            tagged("Standup", "Team sync", &["work"]),
+            event("Gym", "Leg day"),
+        ];
+        let wanted = ["HEALTH".to_string(), "travel".to_string()];
+        let filtered = filter_by_tags(events.clone(), Some(&wanted));
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].title, "Dentist");
+        assert_eq!(filter_by_tags(events.clone(), Some(&[])).len(), 3);
+        assert_eq!(filter_by_tags(events.clone(), None).len(), 3);
+
+        // "health" is only in the tags, not the title or description.
+        let hits = search_events(&events, "health appointments", 3, 0, &SearchOptions::default());
+        assert_eq!(hits[0].title, "Dentist");
+    }
+
+    fn at(title: &str, date: &str, start: Option<&str>, end: Option<&str>) -> Event {
+        Event {
+            date: date.to_string(),
+            start_time: start.map(String::from),
+            end_time: end.map(String::from),
+            ..event(title, "")
+        }
+    }
+
+    #[test]
+    fn dedup_collapses_the_same_title_date_and_start() {
+        let mut events = vec![
+            at("Dentist", "2025-03-01", Some("2 PM"), None),
+            Event {
+                location: Some("Main St".into()),
+                ..at(" dentist ", "3/1/2025", Some("14:00"), Some("15:00"))
+            },
+            at("Standup", "2025-03-01", Some("09:00"), None),
+            at("Standup", "2025-03-01", Some("17:00"), None),
+            at("Standup", "2025-03-02", Some("09:00"), None),
+        ];
+        assert_eq!(dedup_events(&mut events), 1);
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0].title, "Dentist");
+        assert_eq!(events[0].start_time.as_deref(), Some("2 PM"));
+        assert_eq!(events[0].end_time.as_deref(), Some("15:00"));
+        assert_eq!(events[0].location.as_deref(), Some("Main St"));
+        let mut untimed = vec![event("Gym", "Legs"), event("GYM", "Arms")];
+        assert_eq!(dedup_events(&mut untimed), 1);
+        assert_eq!(untimed[0].description, "Legs");
+    }
+
+    #[test]
+    fn conflicts_are_overlapping_times_on_one_day() {
+        let events = [
+            at("Dentist", "2025-03-01", Some("2 PM"), Some("3 PM")),
+            at("Call", "2025-03-01", Some("14:30"), None),
+            at("Gym", "2025-03-01", Some("15:00"), Some("16:00")),
+            at("Lunch", "2025-03-02", Some("14:00"), Some("15:00")),
+            // An end before the start counts as just the start minute.
+            at("Review", "2025-03-01", Some("15:30"), Some("10:00")),
+            at("Holiday", "2025-03-01", None, None),
+        ];
+        let pairs: Vec<_> = detect_conflicts(&events)
+            .into_iter()
+            .map(|c| (c.first.title, c.second.title))
+            .collect();
+        let pairs: Vec<_> = pairs.iter().map(|(a, b)| (a.as_str(), b.as_str())).collect();
+        assert_eq!(pairs, [("Dentist", "Call"), ("Gym", "Review")]);
+
+        let name = format!("events-conflicts-{}.json", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, serde_json::to_string(&events).unwrap()).unwrap();
+        let found = find_conflicts(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(found.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn urls_are_told_from_paths_and_csv_from_json() {
+        let url = |path: &str| events_url(Path::new(path)).is_some();
+        assert!(url("https://example.com/events.json"));
+        assert!(url("HTTP://example.com/events"));
+        assert!(!url("/home/me/events.json"));
+        assert!(!url("https-events.json"));
+        assert!(!url("ftp://example.com/events.csv"));
+
+        let csv = |path: &str| is_csv(Path::new(path));
+        assert!(csv("/home/me/Events.CSV"));
+        assert!(csv("https://example.com/events.csv"));
+        assert!(csv("https://example.com/events.csv?token=x"));
+        assert!(csv("https://example.com/events.csv#today"));
+        assert!(!csv("https://example.com/events.json?format=.csv"));
+        assert!(!csv("/home/me/events.json"));
+    }
+
+    #[test]
+    fn json_with_bom_loads() {
+        let path = std::env::temp_dir().join(format!("events-bom-{}.json", std::process::id()));
+        let json = r#"[{"title": "Dentist", "date": "2025-03-01", "description": "Checkup"}]"#;
+        std::fs::write(&path, [UTF8_BOM, json.as_bytes()].concat()).unwrap();
+        let loaded = load_events(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.events.len(), 1);
+        assert_eq!(loaded.events[0].title, "Dentist");
+    }
+
+    #[test]
+    fn csv_error_names_the_line_after_multiline_cells() {
+        let csv = "title,date,description\nDentist,2025-03-01,\"Bring\nforms\"\n\
+                   Gym,2025-03-02,Legs,extra\n";
+        match parse_csv_events(csv.as_bytes()) {
+            Err(RagError::CsvError { row, .. }) => assert_eq!(row, 4),
+            other => panic!("expected a CSV error, got {:?}", other.map(|events| events.len())),
+        }
+    }
 }
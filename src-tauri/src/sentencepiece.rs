@@ -0,0 +1,333 @@
+//! Build a `tokenizers::Tokenizer` from a SentencePiece `tokenizer.model`, for Llama
+//! distributions that don't ship `tokenizer.json`.
+//!
+//! The model file is a protobuf `ModelProto`; only the fields needed to rebuild the tokenizer
+//! are read. The result mirrors what Hugging Face's Llama converter produces: "▁"-prefixed
+//! normalization, a BPE (or Unigram) model with byte fallback, a decoder that undoes both,
+//! and a post-processor that prepends the BOS token.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use tokenizers::decoders::byte_fallback::ByteFallback;
+use tokenizers::decoders::fuse::Fuse;
+use tokenizers::decoders::sequence::Sequence as DecoderSequence;
+use tokenizers::decoders::strip::Strip;
+use tokenizers::models::bpe::BPE;
+use tokenizers::models::unigram::Unigram;
+use tokenizers::normalizers::{Prepend, Replace, Sequence as NormalizerSequence};
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::{AddedToken, DecoderWrapper, ModelWrapper, NormalizerWrapper, Tokenizer};
+
+use crate::llm::LlmError;
+
+const SPACE: &str = "▁";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PieceType {
+    Normal,
+    Unknown,
+    Control,
+    UserDefined,
+    Unused,
+    Byte,
+}
+
+impl PieceType {
+    fn from_proto(value: u64) -> Self {
+        match value {
+            2 => PieceType::Unknown,
+            3 => PieceType::Control,
+            4 => PieceType::UserDefined,
+            5 => PieceType::Unused,
+            6 => PieceType::Byte,
+            _ => PieceType::Normal,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Piece {
+    text: String,
+    score: f32,
+    kind: PieceType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ModelType {
+    Unigram,
+    Bpe,
+}
+
+#[derive(Debug)]
+struct ModelProto {
+    pieces: Vec<Piece>,
+    model_type: ModelType,
+    byte_fallback: bool,
+    unk_id: u32,
+    bos_id: i64,
+    add_dummy_prefix: bool,
+}
+
+/// Minimal protobuf wire-format reader.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Fixed32(u32),
+    Bytes(&'a [u8]),
+    Other,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64, LlmError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self
+                .buf
+                .get(self.pos)
+                .ok_or_else(|| LlmError::ModelFiles("Truncated tokenizer.model".into()))?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(LlmError::ModelFiles("Malformed varint in tokenizer.model".into()))
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], LlmError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.buf.len())
+            .ok_or_else(|| LlmError::ModelFiles("Truncated tokenizer.model".into()))?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Next `(field number, value)`, or None at the end of the message.
+    fn field(&mut self) -> Result<Option<(u64, Field<'a>)>, LlmError> {
+        if self.pos >= self.buf.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => Field::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Field::Other
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                Field::Bytes(self.take(len)?)
+            }
+            5 => {
+                let b = self.take(4)?;
+                Field::Fixed32(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            }
+            wire => {
+                let message = format!("Unsupported protobuf wire type {}", wire);
+                return Err(LlmError::ModelFiles(message));
+            }
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+fn parse_piece(buf: &[u8]) -> Result<Piece, LlmError> {
+    let mut piece = Piece {
+        text: String::new(),
+        score: 0.0,
+        kind: PieceType::Normal,
+    };
+    let mut reader = Reader::new(buf);
+    while let Some((number, value)) = reader.field()? {
+        match (number, value) {
+            (1, Field::Bytes(b)) => {
+                piece.text = String::from_utf8(b.to_vec())
+                    .map_err(|_| {
+                        LlmError::ModelFiles("Non-UTF-8 piece in tokenizer.model".into())
+                    })?;
+            }
+            (2, Field::Fixed32(bits)) => piece.score = f32::from_bits(bits),
+            (3, Field::Varint(v)) => piece.kind = PieceType::from_proto(v),
+            _ => {}
+        }
+    }
+    Ok(piece)
+}
+
+fn parse_model(buf: &[u8]) -> Result<ModelProto, LlmError> {
+    let mut model = ModelProto {
+        pieces: Vec::new(),
+        model_type: ModelType::Unigram,
+        byte_fallback: false,
+        unk_id: 0,
+        bos_id: 1,
+        add_dummy_prefix: true,
+    };
+    let mut reader = Reader::new(buf);
+    while let Some((number, value)) = reader.field()? {
+        match (number, value) {
+            (1, Field::Bytes(b)) => model.pieces.push(parse_piece(b)?),
+            (2, Field::Bytes(b)) => {
+                let mut trainer = Reader::new(b);
+                while let Some((number, value)) = trainer.field()? {
+                    match (number, value) {
+                        (3, Field::Varint(v)) => {
+                            model.model_type = match v {
+                                1 => ModelType::Unigram,
+                                2 => ModelType::Bpe,
+                                other => {
+                                    return Err(LlmError::ModelFiles(format!(
+                                        "Unsupported SentencePiece model type {}",
+                                        other
+                                    )))
+                                }
+                            }
+                        }
+                        (35, Field::Varint(v)) => model.byte_fallback = v != 0,
+                        (40, Field::Varint(v)) => model.unk_id = v as u32,
+                        // Stored as int32; -1 (no BOS) arrives sign-extended.
+                        (41, Field::Varint(v)) => model.bos_id = v as i64,
+                        _ => {}
+                    }
+                }
+            }
+            (3, Field::Bytes(b)) => {
+                let mut normalizer = Reader::new(b);
+                while let Some((number, value)) = normalizer.field()? {
+                    if let (3, Field::Varint(v)) = (number, value) {
+                        model.add_dummy_prefix = v != 0;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if model.pieces.is_empty() {
+        return Err(LlmError::ModelFiles("tokenizer.model has no pieces".into()));
+    }
+    Ok(model)
+}
+
+/// BPE merges recovered from the vocab: every way of splitting a piece into two pieces that
+/// are also in the vocab, ordered by the merged piece's rank (SentencePiece BPE vocabularies
+/// are sorted by merge order).
+fn bpe_merges(vocab: &HashMap<String, u32>, pieces: &[Piece]) -> Vec<(String, String)> {
+    let mut merges: Vec<(u32, u32, u32, String, String)> = Vec::new();
+    for (id, piece) in pieces.iter().enumerate() {
+        if piece.kind != PieceType::Normal && piece.kind != PieceType::UserDefined {
+            continue;
+        }
+        for (split, _) in piece.text.char_indices().skip(1) {
+            let (left, right) = piece.text.split_at(split);
+            if let (Some(&l), Some(&r)) = (vocab.get(left), vocab.get(right)) {
+                merges.push((id as u32, l, r, left.to_string(), right.to_string()));
+            }
+        }
+    }
+    merges.sort_by_key(|&(id, l, r, _, _)| (id, l, r));
+    merges.into_iter().map(|(_, _, _, l, r)| (l, r)).collect()
+}
+
+fn build_model(model: &ModelProto) -> Result<ModelWrapper, LlmError> {
+    let unk = model
+        .pieces
+        .get(model.unk_id as usize)
+        .map(|p| p.text.clone())
+        .unwrap_or_else(|| "<unk>".to_string());
+    match model.model_type {
+        ModelType::Bpe => {
+            let vocab: HashMap<String, u32> = model
+                .pieces
+                .iter()
+                .enumerate()
+                .map(|(id, p)| (p.text.clone(), id as u32))
+                .collect();
+            let merges = bpe_merges(&vocab, &model.pieces);
+            let bpe = BPE::builder()
+                .vocab_and_merges(vocab, merges)
+                .unk_token(unk)
+                .fuse_unk(true)
+                .byte_fallback(model.byte_fallback)
+                .build()
+                .map_err(|e| {
+                    LlmError::ModelFiles(format!("Failed to build BPE tokenizer: {}", e))
+                })?;
+            Ok(bpe.into())
+        }
+        ModelType::Unigram => {
+            let vocab = model
+                .pieces
+                .iter()
+                .map(|p| (p.text.clone(), f64::from(p.score)))
+                .collect();
+            let unigram = Unigram::from(vocab, Some(model.unk_id as usize), model.byte_fallback)
+                .map_err(|e| {
+                    LlmError::ModelFiles(format!("Failed to build Unigram tokenizer: {}", e))
+                })?;
+            Ok(unigram.into())
+        }
+    }
+}
+
+/// Load `path` (a SentencePiece `tokenizer.model`) as a Hugging Face tokenizer.
+pub fn load_tokenizer(path: &Path) -> Result<Tokenizer, LlmError> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| LlmError::ModelFiles(format!("Failed to read {}: {}", path.display(), e)))?;
+    let model = parse_model(&bytes)?;
+    let replace = |from: &str, to: &str| {
+        Replace::new(from, to)
+            .map_err(|e| LlmError::ModelFiles(format!("Tokenizer setup failed: {}", e)))
+    };
+
+    let mut tokenizer = Tokenizer::new(build_model(&model)?);
+
+    let mut normalizers: Vec<NormalizerWrapper> = Vec::new();
+    if model.add_dummy_prefix {
+        normalizers.push(Prepend::new(SPACE.to_string()).into());
+    }
+    normalizers.push(replace(" ", SPACE)?.into());
+    tokenizer.with_normalizer(NormalizerSequence::new(normalizers));
+
+    let mut decoders: Vec<DecoderWrapper> = vec![replace(SPACE, " ")?.into()];
+    if model.byte_fallback {
+        decoders.push(ByteFallback::new().into());
+    }
+    decoders.push(Fuse::new().into());
+    if model.add_dummy_prefix {
+        decoders.push(Strip::new(' ', 1, 0).into());
+    }
+    tokenizer.with_decoder(DecoderSequence::new(decoders));
+
+    let special: Vec<AddedToken> = model
+        .pieces
+        .iter()
+        .filter(|p| matches!(p.kind, PieceType::Control | PieceType::Unknown))
+        .map(|p| AddedToken::from(p.text.clone(), true))
+        .collect();
+    tokenizer.add_special_tokens(&special);
+
+    if let Some(bos) = usize::try_from(model.bos_id).ok().and_then(|id| model.pieces.get(id)) {
+        let template = TemplateProcessing::builder()
+            .try_single(format!("{} $A", bos.text))
+            .and_then(|b| b.try_pair(format!("{} $A {} $B", bos.text, bos.text)))
+            .map_err(|e| LlmError::ModelFiles(format!("Tokenizer setup failed: {}", e)))?
+            .special_tokens(vec![(bos.text.clone(), model.bos_id as u32)])
+            .build()
+            .map_err(|e| LlmError::ModelFiles(format!("Tokenizer setup failed: {}", e)))?;
+        tokenizer.with_post_processor(template);
+    }
+
+    Ok(tokenizer)
+}
@@ -8,6 +8,14 @@ type Message = {
   content: string
 }
 
+/** Payload of the backend's `chat-event` stream. */
+type ChatEvent =
+  | { type: 'generating' }
+  | { type: 'token'; text: string; id?: number }
+  | { type: 'progress'; tokens_generated: number; elapsed_ms: number; tokens_per_second: number }
+  | { type: 'done'; metrics: unknown }
+  | { type: 'error'; kind: string; message: string }
+
 function App() {
   const [messages, setMessages] = useState<Message[]>([])
   const [input, setInput] = useState('')
@@ -41,9 +49,11 @@ function App() {
     setStreamingContent('')
     setIsStreaming(true)
 
-    const unlisten = await listen<string>('chat-token', (event: any) => {
-      streamedRef.current += event.payload
-      setStreamingContent((prev) => prev + event.payload)
+    const unlisten = await listen<ChatEvent>('chat-event', (event) => {
+      if (event.payload.type !== 'token') return
+      const text = event.payload.text
+      streamedRef.current += text
+      setStreamingContent((prev) => prev + text)
     })
 
     const currentDate = new Date().toLocaleDateString('en-US', {
@@ -53,6 +63,7 @@ function App() {
       day: 'numeric',
     })
 
+    let error = ''
     invoke('generate_stream', {
       prompt,
       modelDir: MODEL_DIR,
@@ -65,14 +76,13 @@ function App() {
     })
       .catch((err: any) => {
         console.error(err)
-        setStreamingContent((prev) => prev + `\n[Error: ${err}]`)
+        // Commands reject with a `{ kind, message }` object, not an Error.
+        error = `[Error: ${err?.message ?? String(err)}]`
       })
       .finally(() => {
-        const raw = streamedRef.current
-        setMessages((prev) => [
-          ...prev,
-          { role: 'assistant', content: stripFakeUserPrompts(raw) },
-        ])
+        const reply = stripFakeUserPrompts(streamedRef.current)
+        const content = [reply, error].filter(Boolean).join('\n')
+        setMessages((prev) => [...prev, { role: 'assistant', content }])
         setStreamingContent('')
         streamedRef.current = ''
         setIsStreaming(false)